@@ -0,0 +1,112 @@
+// Append-only deploy audit log (one JSON object per line) with size-based rotation
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+const DEFAULT_AUDIT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_AUDIT_KEEP: u32 = 5;
+
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    // Serializes rotate + append so concurrent deploys never interleave or lose lines
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: u32) -> Self {
+        AuditLog {
+            path: path.into(),
+            max_bytes,
+            keep,
+            lock: Mutex::new(()),
+        }
+    }
+
+    // Enabled by AUDIT_LOG; AUDIT_MAX_BYTES and AUDIT_KEEP control rotation
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("AUDIT_LOG").ok().filter(|p| !p.is_empty())?;
+        let max_bytes = env::var("AUDIT_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_AUDIT_MAX_BYTES);
+        let keep = env::var("AUDIT_KEEP")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_AUDIT_KEEP);
+        info!("📝 Writing deploy audit log to {}", path);
+        Some(AuditLog::new(path, max_bytes, keep))
+    }
+
+    // Failures are logged, never propagated: auditing must not fail a deploy
+    pub fn record(&self, entry: &serde_json::Value) {
+        let _guard = self.lock.lock().unwrap();
+        if let Err(e) = self.rotate_if_needed().and_then(|_| self.append(entry)) {
+            warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, entry: &serde_json::Value) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", entry)
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    // audit.log -> audit.log.1 -> ... -> audit.log.<keep>; the oldest falls off the end
+    fn rotate_if_needed(&self) -> std::io::Result<()> {
+        let size = match fs::metadata(&self.path) {
+            Ok(meta) => meta.len(),
+            Err(_) => return Ok(()),
+        };
+        if size < self.max_bytes {
+            return Ok(());
+        }
+
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_when_the_file_exceeds_the_limit() {
+        let dir = env::temp_dir().join(format!("graft-hook-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = AuditLog::new(dir.join("audit.log"), 40, 2);
+
+        for i in 0..6 {
+            log.record(&serde_json::json!({ "deploy": i, "project": "app" }));
+        }
+
+        let current = fs::read_to_string(dir.join("audit.log")).unwrap();
+        assert!(current.contains("\"deploy\":5"));
+        assert!(dir.join("audit.log.1").exists());
+        assert!(dir.join("audit.log.2").exists());
+        assert!(!dir.join("audit.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+// Post-deploy backups of compose files and images, with retention
+use tokio::process::Command;
+use tracing::{error, info};
+
+use crate::deploy::{docker_bin, shell_quote};
+
+pub(crate) async fn create_backup(project_name: &str, project_path: &str, use_sudo: bool) {
+    let timestamp = match Command::new("date").arg("+%Y%m%d%H%M%S").output().await {
+        Ok(out) => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        Err(_) => {
+            error!("Failed to get timestamp for backup");
+            return;
+        }
+    };
+
+    let backup_dir = format!("/opt/graft/backup/{}/{}", project_name, timestamp);
+    let compose_dir = format!("{}/compose", backup_dir);
+    let images_dir = format!("{}/images", backup_dir);
+
+    info!("📦 Starting backup for project: {} into {}", project_name, backup_dir);
+
+    // 1. Create directory structure
+    if let Err(e) = std::fs::create_dir_all(&compose_dir) {
+        error!("Failed to create backup compose dir: {}", e);
+        return;
+    }
+    if let Err(e) = std::fs::create_dir_all(&images_dir) {
+        error!("Failed to create backup images dir: {}", e);
+        return;
+    }
+
+    // 2. Backup compose files and env files
+    let _ = Command::new("sh")
+        .arg("-c")
+        .arg(format!("cp {}/docker-compose.yml {}/ 2>/dev/null", project_path, compose_dir))
+        .status()
+        .await;
+    
+    let _ = Command::new("sh")
+        .arg("-c")
+        .arg(format!("cp {}/*.env {}/ 2>/dev/null", project_path, compose_dir))
+        .status()
+        .await;
+
+    // 3. Backup current images using docker save
+    let images_output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("cd {} && {} compose ps --format '{{{{.Image}}}}'", project_path, docker_bin(use_sudo)))
+        .output()
+        .await;
+
+    if let Ok(out) = images_output {
+        let images = String::from_utf8_lossy(&out.stdout);
+        for img in images.lines() {
+            let img = img.trim();
+            if !img.is_empty() {
+                let safe_name = img.replace(['/', ':'], "_");
+                let save_path = format!("{}/{}.tar", images_dir, safe_name);
+                info!("💾 Saving image {} to {}", img, save_path);
+                
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("{} save -o {} {}", docker_bin(use_sudo), shell_quote(&save_path), shell_quote(img)))
+                    .status()
+                    .await;
+                
+                if let Ok(s) = status {
+                    if s.success() {
+                        // Zip the tar file to save space as requested
+                        let _ = Command::new("gzip").arg(&save_path).status().await;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("✅ Backup completed for {}", project_name);
+}
+
+pub(crate) async fn prune_backups(project_name: &str, limit: u32) {
+    let backup_root = format!("/opt/graft/backup/{}", project_name);
+    let output = Command::new("ls")
+        .arg("-1")
+        .arg(&backup_root)
+        .output()
+        .await;
+
+    if let Ok(out) = output {
+        let mut dirs: Vec<String> = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.to_string())
+            .collect();
+        
+        dirs.sort(); // Sorts by timestamp (ascending)
+
+        if dirs.len() > (limit + 1) as usize {
+            let to_delete = dirs.len() - (limit + 1) as usize;
+            for dir in dirs.iter().take(to_delete) {
+                let dir_to_remove = format!("{}/{}", backup_root, dir);
+                info!("🗑️ Pruning old backup (keeping {} total): {}", limit + 1, dir_to_remove);
+                let _ = Command::new("rm").args(["-rf", &dir_to_remove]).status().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,1870 @@
+// Project config and webhook payload types, plus startup validation
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+};
+use tracing::{info, warn};
+
+use crate::window::{DeployWindow, DeployWindows};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookPayload {
+    pub project: String,
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub r#type: String,
+    pub registry: Option<String>,
+    // e.g. "org/repo"; checked against the project's expected_repository when configured
+    pub repository: Option<String>,
+    pub compose_profiles: Option<Vec<String>>,
+    pub scale: Option<BTreeMap<String, u32>>,
+    // Repo mode only: `false` recreates containers from existing images without --build
+    pub build: Option<bool>,
+    // Repo mode only: deploy this tag instead of the configured branch
+    pub tag: Option<String>,
+    // Down mode only: also remove volumes; needs the request confirmed (see confirms)
+    pub volumes: Option<bool>,
+    // `true` or the project name; confirm_project, if used, must be the project name
+    pub confirm: Option<Confirm>,
+    pub confirm_project: Option<String>,
+    // Compose toggles (see COMPOSE_TOGGLES), each honored only if the project allows it
+    pub options: Option<BTreeMap<String, bool>>,
+    // Ad-hoc deploy of this directory instead of the project's; needs ALLOW_ADHOC_PATHS
+    pub path: Option<String>,
+    // Repo mode: preview deploy of refs/pull/<n>/head beside the main deploy; teardown mode
+    // (where it is required): the preview to remove
+    pub pr_number: Option<u32>,
+    // Image mode only: exported as TAG, for compose files written as `image: repo:${TAG}`
+    pub image_tag: Option<String>,
+    // Repo and pull modes: deploy even if require_fast_forward would refuse the commit
+    pub allow_non_fast_forward: Option<bool>,
+    // Repo and image modes: compose YAML layered over the project's files with one more -f
+    pub compose_override: Option<String>,
+    // Deploy even outside the project's deploy_windows
+    pub override_window: Option<bool>,
+    // Who or what sent this, e.g. "ci-main" or "alice"; takes precedence over X-Deploy-Source
+    pub source: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Confirm {
+    Flag(bool),
+    Project(String),
+}
+
+// Payload `options` keys a project can enable through allowed_options
+pub const COMPOSE_TOGGLES: [&str; 4] = ["remove_orphans", "force_recreate", "no_build", "pull"];
+
+// Only ever lowering priority: negative nice levels and the realtime ionice class (1) need root
+// and would let a build crowd out everything else
+pub const NICE_LEVELS: std::ops::RangeInclusive<i32> = 0..=19;
+pub const IONICE_CLASSES: [u8; 2] = [2, 3];
+
+// Smallest `scale` count and `pr_number` the server accepts; the schema's minimums come from these
+pub const MIN_SCALE: u32 = 1;
+pub const MIN_PR_NUMBER: u32 = 1;
+
+pub fn is_valid_pr_number(pr: u32) -> bool {
+    pr >= MIN_PR_NUMBER
+}
+
+// Every `type` a webhook may ask for
+pub const DEPLOY_TYPES: [&str; 9] = ["repo", "pull", "image", "restart", "wake", "stop", "down", "teardown", "login"];
+
+// Server variables a payload without its own token or user falls back to, after the project's
+// credentials; a project's credential_provider replaces the token variables
+pub const GIT_TOKEN_VAR: &str = "GIT_PAT_TOKEN";
+pub const DOCKER_TOKEN_VAR: &str = "DOCKER_ACCESS_TOKEN";
+pub const USER_VAR: &str = "DOCKER_USER";
+
+// JSON Schema of WebhookPayload, served by GET /schema; keep in step with the struct (a test
+// compares the property names against the fields serde knows)
+pub fn payload_schema() -> serde_json::Value {
+    use serde_json::json;
+    let string = |description: &str| json!({ "type": "string", "description": description });
+    let flag = |description: &str| json!({ "type": "boolean", "description": description });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "WebhookPayload",
+        "description": "Body of POST /webhook and POST /plan",
+        "type": "object",
+        "required": ["project", "type"],
+        "properties": {
+            "project": string("Project name from the config (or a project alias)"),
+            "type": { "type": "string", "enum": DEPLOY_TYPES, "description": "What to deploy" },
+            "token": string(&format!(
+                "Registry or git token; falls back to the project's credentials, then its credential_provider or {} (repo, pull) / {} (image, login)",
+                GIT_TOKEN_VAR, DOCKER_TOKEN_VAR
+            )),
+            "user": string(&format!("User for the token; falls back to the project's credentials, then {}", USER_VAR)),
+            "registry": string("Registry host the token is for"),
+            "repository": string("e.g. \"org/repo\"; checked against the project's expected_repository"),
+            "compose_profiles": { "type": "array", "items": { "type": "string" }, "description": "Compose profiles to enable" },
+            "scale": {
+                "type": "object",
+                "additionalProperties": { "type": "integer", "minimum": MIN_SCALE },
+                "description": "Replica count per service"
+            },
+            "build": flag("Repo mode only: false recreates containers from existing images without --build"),
+            "tag": string("Repo mode only: deploy this tag instead of the configured branch"),
+            "volumes": flag("Down mode only: also remove volumes; needs confirm"),
+            "confirm": {
+                "type": ["boolean", "string"],
+                "description": "true or the project name, confirming a destructive request"
+            },
+            "confirm_project": string("The project name, confirming a destructive request"),
+            "source": string("Who or what triggered the deploy; recorded in history, logs and notifications"),
+            "options": {
+                "type": "object",
+                "propertyNames": { "enum": COMPOSE_TOGGLES },
+                "additionalProperties": { "type": "boolean" },
+                "description": "Compose toggles, each honored only if the project allows it"
+            },
+            "path": string("Ad-hoc deploy of this directory instead of the project's; needs ALLOW_ADHOC_PATHS"),
+            "pr_number": { "type": "integer", "minimum": MIN_PR_NUMBER, "description": "Repo and teardown modes: the pull request preview to deploy or remove (required for teardown)" },
+            "image_tag": string("Image mode only: exported to compose as TAG"),
+            "allow_non_fast_forward": flag("Repo and pull modes: deploy even if require_fast_forward would refuse the commit"),
+            "compose_override": string("Repo and image modes: compose YAML layered over the project's files"),
+            "override_window": flag("Deploy even outside the project's deploy_windows")
+        }
+    })
+}
+
+// Limits for WebhookPayload::oversized_field; tokens get their own, since JWT-style tokens run long
+#[derive(Debug, Clone, Copy)]
+pub struct FieldLimits {
+    pub max_len: usize,
+    pub max_token_len: usize,
+}
+
+impl Default for FieldLimits {
+    fn default() -> Self {
+        FieldLimits { max_len: 256, max_token_len: 4096 }
+    }
+}
+
+impl WebhookPayload {
+    // Whether a destructive request (down --volumes, or a type in confirm_types) was confirmed
+    pub fn confirms(&self) -> bool {
+        match &self.confirm {
+            Some(Confirm::Flag(true)) => true,
+            Some(Confirm::Project(name)) if *name == self.project => true,
+            _ => self.confirm_project.as_deref() == Some(self.project.as_str()),
+        }
+    }
+
+    // CI templates often render an unset variable as "": a blank project or type is the field
+    // missing (Err names it), while blank optional strings become None so the defaults apply,
+    // or are refused too when `reject_optional` (REJECT_BLANK_FIELDS) is set
+    pub fn normalize_blank_fields(&mut self, reject_optional: bool) -> Result<(), &'static str> {
+        if self.project.trim().is_empty() {
+            return Err("project");
+        }
+        if self.r#type.trim().is_empty() {
+            return Err("type");
+        }
+        let optional = [
+            ("token", &mut self.token),
+            ("user", &mut self.user),
+            ("registry", &mut self.registry),
+            ("repository", &mut self.repository),
+            ("tag", &mut self.tag),
+            ("image_tag", &mut self.image_tag),
+            ("confirm_project", &mut self.confirm_project),
+            ("compose_override", &mut self.compose_override),
+            ("source", &mut self.source),
+            ("path", &mut self.path),
+        ];
+        for (name, value) in optional {
+            if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+                if reject_optional {
+                    return Err(name);
+                }
+                *value = None;
+            }
+        }
+        if matches!(&self.confirm, Some(Confirm::Project(name)) if name.trim().is_empty()) {
+            if reject_optional {
+                return Err("confirm");
+            }
+            self.confirm = None;
+        }
+        Ok(())
+    }
+
+    // First field longer than the limits allow, named as in the JSON
+    pub fn oversized_field(&self, limits: FieldLimits) -> Option<&'static str> {
+        let too_long = |value: Option<&str>| value.is_some_and(|v| v.len() > limits.max_len);
+        if self.token.as_deref().is_some_and(|t| t.len() > limits.max_token_len) {
+            return Some("token");
+        }
+        let fields = [
+            ("project", Some(self.project.as_str())),
+            ("user", self.user.as_deref()),
+            ("type", Some(self.r#type.as_str())),
+            ("registry", self.registry.as_deref()),
+            ("repository", self.repository.as_deref()),
+            ("tag", self.tag.as_deref()),
+            ("image_tag", self.image_tag.as_deref()),
+            (
+                "confirm",
+                match &self.confirm {
+                    Some(Confirm::Project(name)) => Some(name.as_str()),
+                    _ => None,
+                },
+            ),
+            ("confirm_project", self.confirm_project.as_deref()),
+            ("source", self.source.as_deref()),
+            ("path", self.path.as_deref()),
+        ];
+        if let Some((name, _)) = fields.iter().find(|(_, value)| too_long(*value)) {
+            return Some(name);
+        }
+        if self.compose_profiles.iter().flatten().any(|p| too_long(Some(p))) {
+            return Some("compose_profiles");
+        }
+        if self.scale.iter().flat_map(|s| s.keys()).any(|service| too_long(Some(service))) {
+            return Some("scale");
+        }
+        None
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ErrorPayload {
+    pub project: String,
+    pub repository: String,
+    pub message: String,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProjectConfig {
+    pub path: String,
+    // Optional overrides when the git checkout and compose file live in different directories
+    #[serde(default)]
+    pub repo_path: Option<String>,
+    #[serde(default)]
+    pub compose_path: Option<String>,
+    #[serde(default)]
+    pub rollback_backups: Option<u32>,
+    #[serde(default)]
+    pub compose_profiles: Vec<String>,
+    // Compose files passed with -f, e.g. one kept outside the checkout; relative ones resolve
+    // against compose_path. Build contexts and volumes resolve against project_directory,
+    // which defaults to compose_path too.
+    #[serde(default)]
+    pub compose_files: Vec<String>,
+    #[serde(default)]
+    pub project_directory: Option<String>,
+    // Passed as --env-file; relative paths resolve against the compose directory
+    #[serde(default)]
+    pub env_file: Option<String>,
+    #[serde(default)]
+    pub notify_success_template: Option<String>,
+    #[serde(default)]
+    pub notify_failure_template: Option<String>,
+    // Also notify when a deploy starts, not just when it finishes
+    #[serde(default)]
+    pub notify_on_start: bool,
+    // Coalesce bursts: while a deploy runs, keep only the latest request as one follow-up
+    #[serde(default)]
+    pub coalesce: bool,
+    // When set, deploys must name this repository or they are rejected with 403
+    #[serde(default)]
+    pub expected_repository: Option<String>,
+    // Fetch only the tip of the branch (--depth 1) in repo mode
+    #[serde(default)]
+    pub shallow: bool,
+    // Repo mode: refuse a fetched commit that doesn't descend from the deployed one (a force-push
+    // rewinding the branch) unless the payload sets allow_non_fast_forward
+    #[serde(default)]
+    pub require_fast_forward: bool,
+    // Run `git submodule update --init --recursive` after the pull
+    #[serde(default)]
+    pub update_submodules: bool,
+    // Ordered fetch/integrate steps replacing the default "reset to origin/main"
+    #[serde(default)]
+    pub git_steps: Vec<GitStep>,
+    // Pulled instead when the primary branch (main, or the first git step's) doesn't exist on the
+    // remote, e.g. after a rename to trunk; auth and network failures never fall back
+    #[serde(default)]
+    pub fallback_branch: Option<String>,
+    // Extra `git -c key=value` options for every git invocation (e.g. http.postBuffer)
+    #[serde(default)]
+    pub git_config: BTreeMap<String, String>,
+    // With allow_clone, an empty repo dir is initialized from this remote on the first deploy
+    #[serde(default)]
+    pub clone_url: Option<String>,
+    #[serde(default)]
+    pub allow_clone: bool,
+    // Run docker as `sudo -n docker`; needs passwordless sudo for the service user
+    #[serde(default)]
+    pub use_sudo: bool,
+    // "user@host" or "user@host:port": docker and compose talk to that host's engine over SSH,
+    // authenticating with ssh_key if set (else the service user's SSH agent and config). Only
+    // the engine is remote: git runs and the checkout stays on this host, where compose reads
+    // the files and sends the build context to the remote engine. Bind mount paths are resolved
+    // by the remote engine on its own filesystem, so they only work where that path exists too.
+    #[serde(default)]
+    pub ssh_host: Option<String>,
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    // How many deploys of this project may run at once; further requests wait their turn
+    #[serde(default)]
+    pub max_concurrency: Option<u32>,
+    // Projects sharing a resource (a database, a port range) name the same group and then deploy
+    // one at a time across the group; unset, a project only waits for itself
+    #[serde(default)]
+    pub lock_group: Option<String>,
+    // Hours deploys are accepted in (see window.rs); outside them a deploy is refused with 403
+    // unless the payload sets override_window. Empty allows deploys at any time.
+    #[serde(default)]
+    pub deploy_windows: Vec<DeployWindow>,
+    // Fixed offset the windows are written in: "UTC" (the default), "+02:00", "-05:30"
+    #[serde(default)]
+    pub deploy_timezone: Option<String>,
+    // Repo mode: services built at once (COMPOSE_PARALLEL_LIMIT); 1 builds one at a time.
+    // Falls back to BUILD_PARALLELISM, else compose's own default.
+    #[serde(default)]
+    pub build_parallelism: Option<u32>,
+    // Compose commands run under `nice -n <nice>` / `ionice -c <ionice_class>` so builds yield CPU
+    // and IO to the host's other services; fall back to DEPLOY_NICE / DEPLOY_IONICE_CLASS
+    #[serde(default)]
+    pub nice: Option<i32>,
+    #[serde(default)]
+    pub ionice_class: Option<u8>,
+    // Run `docker compose config` before `up` so a broken compose file fails the deploy early
+    #[serde(default)]
+    pub validate_compose: bool,
+    // Repo mode: refresh pre-built images with `docker compose pull` before building
+    #[serde(default)]
+    pub pull_before_build: bool,
+    // Repo mode: `docker login` to the payload's registry (or DOCKER_REGISTRY) before building, so
+    // private base images pull; skipped when no registry or Docker credentials are available
+    #[serde(default)]
+    pub build_login: bool,
+    // With build_login: run the login alongside the git fetch instead of before it
+    #[serde(default)]
+    pub parallel_login: bool,
+    // Seconds compose waits for containers to stop gracefully when recreating them
+    #[serde(default)]
+    pub stop_timeout: Option<u32>,
+    // `up --wait`: compose itself blocks until every service is running and healthy, failing the
+    // deploy if that takes longer than wait_timeout_secs. Dropped, with a warning, on a compose
+    // too old to know the flag.
+    #[serde(default)]
+    pub wait: bool,
+    #[serde(default)]
+    pub wait_timeout_secs: Option<u32>,
+    // Overrides DEPLOY_TIMEOUT_SECS for this project; 0 means no limit
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    // Argv (program + args) run directly, without `sh -c`, instead of `docker compose up`
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    // `restart` deploys run `down` + `up -d` instead of `docker compose restart`
+    #[serde(default)]
+    pub restart_recreate: bool,
+    // Image mode: credentials per registry host (e.g. "ghcr.io"), used before the DOCKER_* env
+    #[serde(default)]
+    pub registries: BTreeMap<String, RegistryCredentials>,
+    // {"user": ..., "token": ...} this project authenticates with when the payload has none, ahead
+    // of GIT_PAT_TOKEN / DOCKER_ACCESS_TOKEN and DOCKER_USER; docker_credentials covers registries
+    // without their own `registries` entry
+    #[serde(default)]
+    pub git_credentials: Option<RegistryCredentials>,
+    #[serde(default)]
+    pub docker_credentials: Option<RegistryCredentials>,
+    // Where the git/registry token comes from when the payload has none, e.g.
+    // {"provider": "command", "command": ["op", "read", "op://deploy/app/token"], "user": "deploy"}
+    #[serde(default)]
+    pub credential_provider: Option<CredentialSource>,
+    // One-off compose commands after `up`, e.g. [["run", "--rm", "app", "migrate"]]
+    #[serde(default)]
+    pub post_up_exec: Vec<Vec<String>>,
+    // Repo mode: go back to the previous commit when a post_up_exec or health_command fails
+    #[serde(default)]
+    pub post_up_rollback: bool,
+    // Repo mode: when the build or `up` fails for good (after COMPOSE_UP_RETRIES), reset to the
+    // previous commit and bring that back up, so a failed deploy doesn't leave the stack down
+    #[serde(default)]
+    pub restore_on_failure: bool,
+    // Argv run in the compose dir after `up` (and post_up_exec), retried every
+    // health_interval_secs until it exits 0; the deploy fails if it hasn't within health_timeout_secs
+    #[serde(default)]
+    pub health_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub health_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub health_interval_secs: Option<u64>,
+    // Repo and image modes: argv run once in the compose dir after the health check, e.g.
+    // ["./smoke.sh"]. Failing (or outliving smoke_test_timeout_secs) doesn't fail the deploy;
+    // it is reported as "success_with_warnings" with the command's output.
+    #[serde(default)]
+    pub smoke_test_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub smoke_test_timeout_secs: Option<u64>,
+    // Circuit breaker: after this many consecutive failures, reject deploys for failure_cooldown_secs
+    #[serde(default)]
+    pub max_consecutive_failures: Option<u32>,
+    #[serde(default)]
+    pub failure_cooldown_secs: Option<u64>,
+    // Repo mode: check each commit out into <repo_path>.releases/<commit> and switch the
+    // compose path (a symlink) to it, so the live directory never holds a half-pulled tree
+    #[serde(default)]
+    pub atomic_worktree: bool,
+    // atomic_worktree: keep this many most recent releases for POST /projects/{project}/rollback
+    // and remove older worktrees after each deploy; unset keeps every release
+    #[serde(default)]
+    pub retain_releases: Option<u32>,
+    // Compose file downloaded at deploy time instead of read from the project dir; the optional
+    // Authorization header value and sha256 (hex) are applied to / checked against the download
+    #[serde(default)]
+    pub compose_url: Option<String>,
+    #[serde(default)]
+    pub compose_url_auth: Option<String>,
+    #[serde(default)]
+    pub compose_sha256: Option<String>,
+    // Payload `options` this project accepts, e.g. ["remove_orphans", "no_build"]
+    #[serde(default)]
+    pub allowed_options: Vec<String>,
+    // Deploy types (see DEPLOY_TYPES) webhooks may request for this project; unset allows all
+    #[serde(default)]
+    pub allowed_types: Option<Vec<String>>,
+    // Deploy types that wipe state here, e.g. ["down", "teardown"]: requests for them need
+    // `confirm: true` (or confirm_project set to the project name), else 400. down --volumes needs it regardless.
+    #[serde(default)]
+    pub confirm_types: Vec<String>,
+    // Run git and docker with only PATH, HOME and a few docker/locale/proxy variables instead of
+    // the server's whole environment, plus the server variables named in env_passthrough
+    #[serde(default)]
+    pub clean_env: bool,
+    #[serde(default)]
+    pub env_passthrough: Vec<String>,
+    // Free-form metadata for grouping projects (team, environment, ...); shown by /projects,
+    // /status and notifications, never used by deploys
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Regexes over the output of `up` (or `command`), for tools that exit 0 on failure: a
+    // failure_pattern match fails the deploy, and so does a success_pattern that doesn't match
+    #[serde(default)]
+    pub failure_pattern: Option<String>,
+    #[serde(default)]
+    pub success_pattern: Option<String>,
+    // Regexes whose matches are replaced with *** in every command's captured output, before it
+    // is logged or returned, e.g. ["API_KEY=\\S+"]; MASK_ENV_VARS adds literal values for all projects
+    #[serde(default)]
+    pub mask_patterns: Vec<String>,
+    // Image mode: `cosign verify` every image in the compose file before pulling, against a
+    // public key (path or KMS URI) or, keyless, a certificate identity and OIDC issuer
+    #[serde(default)]
+    pub cosign_key: Option<String>,
+    #[serde(default)]
+    pub cosign_identity: Option<String>,
+    #[serde(default)]
+    pub cosign_oidc_issuer: Option<String>,
+}
+
+const DEFAULT_FAILURE_COOLDOWN_SECS: u64 = 600;
+const DEFAULT_HEALTH_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_SMOKE_TEST_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_HEALTH_INTERVAL_SECS: u64 = 5;
+
+#[derive(Deserialize, Clone)]
+pub struct RegistryCredentials {
+    pub user: String,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CredentialSource {
+    #[serde(flatten)]
+    pub kind: CredentialKind,
+    // Defaults to the payload's user, then DOCKER_USER
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+// See credentials::provider for what each one does
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum CredentialKind {
+    Env { var: String },
+    File { path: String },
+    Command { command: Vec<String> },
+}
+
+// Keeps tokens out of `{:?}` logs of jobs and config
+impl std::fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("user", &self.user)
+            .field("token", &"***")
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct RemoteComposeFile {
+    pub url: String,
+    pub authorization: Option<String>,
+    pub sha256: Option<String>,
+}
+
+impl std::fmt::Debug for RemoteComposeFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteComposeFile")
+            .field("url", &self.url)
+            .field("authorization", &self.authorization.as_ref().map(|_| "***"))
+            .field("sha256", &self.sha256)
+            .finish()
+    }
+}
+
+// Compiled failure_pattern / success_pattern
+#[derive(Debug, Clone)]
+pub struct OutputPatterns {
+    pub failure: Option<regex::Regex>,
+    pub success: Option<regex::Regex>,
+}
+
+impl OutputPatterns {
+    // Which pattern rejects `output`, if any
+    pub fn rejection(&self, output: &str) -> Option<String> {
+        if let Some(failure) = self.failure.as_ref().filter(|re| re.is_match(output)) {
+            return Some(format!("output matched failure_pattern `{}`", failure));
+        }
+        if let Some(success) = self.success.as_ref().filter(|re| !re.is_match(output)) {
+            return Some(format!("output lacks success_pattern `{}`", success));
+        }
+        None
+    }
+}
+
+// How image deploys check signatures, from cosign_key / cosign_identity + cosign_oidc_issuer
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImagePolicy {
+    Key(String),
+    Keyless { identity: String, issuer: String },
+}
+
+// smoke_test_command with its timeout resolved
+#[derive(Debug, Clone)]
+pub struct SmokeTest {
+    pub command: Vec<String>,
+    pub timeout: std::time::Duration,
+}
+
+// health_command with its timeout and interval resolved
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub command: Vec<String>,
+    pub timeout: std::time::Duration,
+    pub interval: std::time::Duration,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ProjectEntry {
+    Path(String),
+    Full(Box<ProjectConfig>),
+}
+
+impl ProjectEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            ProjectEntry::Path(p) => p,
+            ProjectEntry::Full(c) => &c.path,
+        }
+    }
+
+    pub fn repo_path(&self) -> &str {
+        match self {
+            ProjectEntry::Path(p) => p,
+            ProjectEntry::Full(c) => c.repo_path.as_deref().unwrap_or(&c.path),
+        }
+    }
+
+    pub fn compose_path(&self) -> &str {
+        match self {
+            ProjectEntry::Path(p) => p,
+            ProjectEntry::Full(c) => c.compose_path.as_deref().unwrap_or(&c.path),
+        }
+    }
+
+    pub fn rollback_backups(&self) -> u32 {
+        match self {
+            ProjectEntry::Path(_) => 0,
+            ProjectEntry::Full(c) => c.rollback_backups.unwrap_or(0),
+        }
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        static NO_LABELS: BTreeMap<String, String> = BTreeMap::new();
+        match self {
+            ProjectEntry::Path(_) => &NO_LABELS,
+            ProjectEntry::Full(c) => &c.labels,
+        }
+    }
+
+    // Some(passthrough names) when the project runs its commands in a clean environment
+    pub fn clean_env(&self) -> Option<Vec<String>> {
+        match self {
+            ProjectEntry::Full(c) if c.clean_env => Some(c.env_passthrough.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn tags(&self) -> &[String] {
+        match self {
+            ProjectEntry::Path(_) => &[],
+            ProjectEntry::Full(c) => &c.tags,
+        }
+    }
+
+    pub fn compose_files(&self) -> &[String] {
+        match self {
+            ProjectEntry::Path(_) => &[],
+            ProjectEntry::Full(c) => &c.compose_files,
+        }
+    }
+
+    pub fn project_directory(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.project_directory.as_deref(),
+        }
+    }
+
+    pub fn compose_profiles(&self) -> &[String] {
+        match self {
+            ProjectEntry::Path(_) => &[],
+            ProjectEntry::Full(c) => &c.compose_profiles,
+        }
+    }
+
+    pub fn git_options(&self) -> GitOptions {
+        match self {
+            ProjectEntry::Path(_) => GitOptions::default(),
+            ProjectEntry::Full(c) => GitOptions {
+                shallow: c.shallow,
+                update_submodules: c.update_submodules,
+                steps: c.git_steps.clone(),
+                config: c.git_config.clone(),
+                tag: None,
+                atomic_worktree: c.atomic_worktree,
+                clone_url: c.clone_url.clone().filter(|_| c.allow_clone),
+                pr_number: None,
+                retain_releases: c.retain_releases,
+                rollback_steps: 0,
+                commit: None,
+                require_fast_forward: c.require_fast_forward,
+                fallback_branch: c.fallback_branch.clone(),
+            },
+        }
+    }
+
+    // None unless a pattern is set; validate_config has already rejected ones that don't compile
+    pub fn output_patterns(&self) -> Option<OutputPatterns> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) if c.failure_pattern.is_none() && c.success_pattern.is_none() => None,
+            ProjectEntry::Full(c) => Some(OutputPatterns {
+                failure: c.failure_pattern.as_deref().and_then(|p| regex::Regex::new(p).ok()),
+                success: c.success_pattern.as_deref().and_then(|p| regex::Regex::new(p).ok()),
+            }),
+        }
+    }
+
+    // validate_config has already rejected ones that don't compile
+    pub fn mask_patterns(&self) -> Vec<regex::Regex> {
+        match self {
+            ProjectEntry::Path(_) => Vec::new(),
+            ProjectEntry::Full(c) => c.mask_patterns.iter().filter_map(|p| regex::Regex::new(p).ok()).collect(),
+        }
+    }
+
+    pub fn expected_repository(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.expected_repository.as_deref(),
+        }
+    }
+
+    pub fn coalesce(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.coalesce,
+        }
+    }
+
+    pub fn validate_compose(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.validate_compose,
+        }
+    }
+
+    pub fn pull_before_build(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.pull_before_build,
+        }
+    }
+
+    pub fn build_login(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.build_login,
+        }
+    }
+
+    pub fn parallel_login(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.parallel_login,
+        }
+    }
+
+    pub fn max_concurrency(&self) -> u32 {
+        match self {
+            ProjectEntry::Path(_) => 1,
+            ProjectEntry::Full(c) => c.max_concurrency.unwrap_or(1),
+        }
+    }
+
+    pub fn build_parallelism(&self) -> Option<u32> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.build_parallelism,
+        }
+    }
+
+    pub fn nice(&self) -> Option<i32> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.nice,
+        }
+    }
+
+    pub fn ionice_class(&self) -> Option<u8> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.ionice_class,
+        }
+    }
+
+    pub fn lock_group(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.lock_group.as_deref(),
+        }
+    }
+
+    // None when deploys are always allowed; validate_config has already rejected malformed windows
+    pub fn deploy_windows(&self) -> Option<DeployWindows> {
+        match self {
+            ProjectEntry::Full(c) if !c.deploy_windows.is_empty() => {
+                DeployWindows::parse(&c.deploy_windows, c.deploy_timezone.as_deref()).ok()
+            }
+            _ => None,
+        }
+    }
+
+    // Some(timeout) when `up` should run with --wait
+    pub fn compose_wait(&self) -> Option<Option<u32>> {
+        match self {
+            ProjectEntry::Full(c) if c.wait => Some(c.wait_timeout_secs),
+            _ => None,
+        }
+    }
+
+    pub fn stop_timeout(&self) -> Option<u32> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.stop_timeout,
+        }
+    }
+
+    // Some(None) when the project opts out of any deploy timeout
+    pub fn deploy_timeout(&self) -> Option<Option<std::time::Duration>> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c
+                .timeout_secs
+                .map(|secs| (secs > 0).then(|| std::time::Duration::from_secs(secs))),
+        }
+    }
+
+    pub fn command(&self) -> Option<&[String]> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.command.as_deref(),
+        }
+    }
+
+    pub fn restart_recreate(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.restart_recreate,
+        }
+    }
+
+    pub fn git_credentials(&self) -> Option<&RegistryCredentials> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.git_credentials.as_ref(),
+        }
+    }
+
+    pub fn docker_credentials(&self) -> Option<&RegistryCredentials> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.docker_credentials.as_ref(),
+        }
+    }
+
+    pub fn credential_provider(&self) -> Option<&CredentialSource> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.credential_provider.as_ref(),
+        }
+    }
+
+    pub fn registries(&self) -> BTreeMap<String, RegistryCredentials> {
+        match self {
+            ProjectEntry::Path(_) => BTreeMap::new(),
+            ProjectEntry::Full(c) => c.registries.clone(),
+        }
+    }
+
+    pub fn allowed_options(&self) -> &[String] {
+        match self {
+            ProjectEntry::Path(_) => &[],
+            ProjectEntry::Full(c) => &c.allowed_options,
+        }
+    }
+
+    pub fn requires_confirm(&self, mode: &str) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.confirm_types.iter().any(|t| t == mode),
+        }
+    }
+
+    pub fn allows_type(&self, mode: &str) -> bool {
+        match self {
+            ProjectEntry::Path(_) => true,
+            ProjectEntry::Full(c) => c.allowed_types.as_ref().is_none_or(|types| types.iter().any(|t| t == mode)),
+        }
+    }
+
+    pub fn post_up_exec(&self) -> &[Vec<String>] {
+        match self {
+            ProjectEntry::Path(_) => &[],
+            ProjectEntry::Full(c) => &c.post_up_exec,
+        }
+    }
+
+    pub fn image_policy(&self) -> Option<ImagePolicy> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => match (&c.cosign_key, &c.cosign_identity, &c.cosign_oidc_issuer) {
+                (Some(key), _, _) => Some(ImagePolicy::Key(key.clone())),
+                (None, Some(identity), Some(issuer)) => Some(ImagePolicy::Keyless {
+                    identity: identity.clone(),
+                    issuer: issuer.clone(),
+                }),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn smoke_test(&self) -> Option<SmokeTest> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.smoke_test_command.as_ref().map(|command| SmokeTest {
+                command: command.clone(),
+                timeout: std::time::Duration::from_secs(c.smoke_test_timeout_secs.unwrap_or(DEFAULT_SMOKE_TEST_TIMEOUT_SECS)),
+            }),
+        }
+    }
+
+    pub fn health_check(&self) -> Option<HealthCheck> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.health_command.as_ref().map(|command| HealthCheck {
+                command: command.clone(),
+                timeout: std::time::Duration::from_secs(c.health_timeout_secs.unwrap_or(DEFAULT_HEALTH_TIMEOUT_SECS)),
+                interval: std::time::Duration::from_secs(
+                    c.health_interval_secs.unwrap_or(DEFAULT_HEALTH_INTERVAL_SECS).max(1),
+                ),
+            }),
+        }
+    }
+
+    pub fn remote_compose_file(&self) -> Option<RemoteComposeFile> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.compose_url.as_ref().map(|url| RemoteComposeFile {
+                url: url.clone(),
+                authorization: c.compose_url_auth.clone(),
+                sha256: c.compose_sha256.as_ref().map(|sum| sum.to_ascii_lowercase()),
+            }),
+        }
+    }
+
+    pub fn post_up_rollback(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.post_up_rollback,
+        }
+    }
+
+    pub fn restore_on_failure(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.restore_on_failure,
+        }
+    }
+
+    // (threshold, cooldown seconds) when the project enables the circuit breaker
+    pub fn circuit_breaker(&self) -> Option<(u32, u64)> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c
+                .max_consecutive_failures
+                .map(|n| (n, c.failure_cooldown_secs.unwrap_or(DEFAULT_FAILURE_COOLDOWN_SECS))),
+        }
+    }
+
+    pub fn ssh_host(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.ssh_host.as_deref(),
+        }
+    }
+
+    pub fn ssh_key(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.ssh_key.as_deref(),
+        }
+    }
+
+    // Per-project flag, or USE_SUDO for every project on this host
+    pub fn use_sudo(&self) -> bool {
+        env_flag("USE_SUDO")
+            || match self {
+                ProjectEntry::Path(_) => false,
+                ProjectEntry::Full(c) => c.use_sudo,
+            }
+    }
+
+    pub fn env_file(&self) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) => c.env_file.as_deref(),
+        }
+    }
+
+    pub fn notify_on_start(&self) -> bool {
+        match self {
+            ProjectEntry::Path(_) => false,
+            ProjectEntry::Full(c) => c.notify_on_start,
+        }
+    }
+
+    pub fn notify_template(&self, success: bool) -> Option<&str> {
+        match self {
+            ProjectEntry::Path(_) => None,
+            ProjectEntry::Full(c) if success => c.notify_success_template.as_deref(),
+            ProjectEntry::Full(c) => c.notify_failure_template.as_deref(),
+        }
+    }
+}
+
+pub type ConfigFile = HashMap<String, ProjectEntry>;
+
+fn json_kind(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+// Entry by entry, so a mistake names its project (and field) instead of serde's
+// "did not match any variant of untagged enum ProjectEntry"
+fn parse_config(raw: serde_json::Value) -> Result<ConfigFile, String> {
+    let serde_json::Value::Object(projects) = raw else {
+        return Err(format!("expected an object of project names, found {}", json_kind(&raw)));
+    };
+    let mut config = ConfigFile::new();
+    for (name, value) in projects {
+        let mut entry = match value {
+            serde_json::Value::String(path) => ProjectEntry::Path(path),
+            serde_json::Value::Object(_) => match serde_path_to_error::deserialize::<_, ProjectConfig>(value) {
+                Ok(project) => ProjectEntry::Full(Box::new(project)),
+                Err(e) if e.path().to_string() == "." => return Err(format!("project '{}': {}", name, e.into_inner())),
+                Err(e) => {
+                    let path = e.path().to_string();
+                    return Err(format!("project '{}': field '{}': {}", name, path, e.into_inner()));
+                }
+            },
+            other => {
+                return Err(format!(
+                    "project '{}': expected a path string or config object, found {}",
+                    name,
+                    json_kind(&other)
+                ));
+            }
+        };
+        trim_paths(&name, &mut entry);
+        config.insert(name, entry);
+    }
+    Ok(config)
+}
+
+// A stray space or newline from hand-editing the JSON would otherwise surface much later as
+// "directory not found"; trimming is logged so the config still gets fixed
+fn trim_paths(name: &str, entry: &mut ProjectEntry) {
+    let trim = |field: &str, value: &mut String| {
+        let trimmed = value.trim();
+        if trimmed.len() != value.len() {
+            warn!("⚠️ Project '{}': trimmed surrounding whitespace from {} {:?}", name, field, value);
+            *value = trimmed.to_string();
+        }
+    };
+    match entry {
+        ProjectEntry::Path(path) => trim("path", path),
+        ProjectEntry::Full(c) => {
+            trim("path", &mut c.path);
+            for (field, value) in [
+                ("repo_path", &mut c.repo_path),
+                ("compose_path", &mut c.compose_path),
+                ("project_directory", &mut c.project_directory),
+                ("env_file", &mut c.env_file),
+                ("ssh_key", &mut c.ssh_key),
+            ] {
+                if let Some(value) = value {
+                    trim(field, value);
+                }
+            }
+            for file in &mut c.compose_files {
+                trim("compose_files entry", file);
+            }
+        }
+    }
+}
+
+// Per-deploy git settings for repo mode
+#[derive(Debug, Default, Clone)]
+pub struct GitOptions {
+    pub shallow: bool,
+    pub update_submodules: bool,
+    pub steps: Vec<GitStep>,
+    pub config: BTreeMap<String, String>,
+    // Per-deploy: reset to this tag instead of pulling a branch
+    pub tag: Option<String>,
+    // Build each commit in its own worktree and repoint the compose path symlink at it
+    pub atomic_worktree: bool,
+    // Set only when the project allows cloning into an empty repo dir
+    pub clone_url: Option<String>,
+    // Per-deploy: preview of this GitHub pull request (see DeployJob::for_pull_request)
+    pub pr_number: Option<u32>,
+    // atomic_worktree releases kept after each deploy; None keeps all
+    pub retain_releases: Option<u32>,
+    // Rollback mode: how many retained releases to go back from the live one
+    pub rollback_steps: u32,
+    // Per-deploy: reset to this commit, already in the repo, instead of fetching (redeploy-last-good)
+    pub commit: Option<String>,
+    pub require_fast_forward: bool,
+    pub fallback_branch: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GitStep {
+    pub remote: String,
+    pub branch: String,
+    #[serde(default)]
+    pub strategy: GitStrategy,
+}
+
+// How a fetched branch is integrated into the checkout
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitStrategy {
+    #[default]
+    Reset,
+    Merge,
+    FfOnly,
+}
+
+// Treats "1", "true" and "yes" (any case) as enabled
+pub fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+// A missing file is an error unless ALLOW_EMPTY_CONFIG is set, in which case we run with no projects
+pub fn read_config_file(path: &str) -> Result<String, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && env_flag("ALLOW_EMPTY_CONFIG") => {
+            warn!("⚠️⚠️⚠️ Config file '{}' not found; starting with NO projects (ALLOW_EMPTY_CONFIG is set)", path);
+            Ok("{}".to_string())
+        }
+        Err(e) => Err(format!("Failed to read config file: {}", e)),
+    }
+}
+
+// Where the config was loaded from, so a reload reads the same place
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+    File(String),
+    // CONFIG_JSON; reloading re-parses the same content
+    Inline(String),
+}
+
+impl ConfigSource {
+    // For the startup banner; inline content is never echoed
+    pub fn describe(&self) -> &str {
+        match self {
+            ConfigSource::File(path) => path,
+            ConfigSource::Inline(_) => "CONFIG_JSON",
+        }
+    }
+
+    // Read, parse and validate; errors are full messages ready to log. Every load (so every
+    // reload) lists a directory or pattern afresh, picking up added and removed fragments.
+    pub fn load(&self) -> Result<ConfigFile, String> {
+        let config = match self {
+            ConfigSource::File(path) => match config_fragments(path)? {
+                Some(files) => merge_fragments(path, &files)?,
+                None => parse_content(&read_config_file(path)?)?,
+            },
+            ConfigSource::Inline(json) => parse_content(json)?,
+        };
+        validate_config(&config).map_err(|e| format!("Invalid project config: {}", e))?;
+        Ok(config)
+    }
+}
+
+fn parse_content(content: &str) -> Result<ConfigFile, String> {
+    let mut raw: serde_json::Value =
+        serde_json::from_str(content).map_err(|e| format!("JSON format mismatch in config: {}", e))?;
+    interpolate_env(&mut raw, env_flag("CONFIG_KEEP_UNSET_VARS"))?;
+    parse_config(raw).map_err(|e| format!("JSON format mismatch in config: {}", e))
+}
+
+// A config path naming a directory means every `*.json` file directly inside it; one whose last
+// component has a `*` or `?` (e.g. /etc/graft/teams/*.json) means the files of that directory
+// matching it. Dotfiles are skipped either way. None for a plain file path.
+fn config_fragments(path: &str) -> Result<Option<Vec<std::path::PathBuf>>, String> {
+    let path = std::path::Path::new(path);
+    let (dir, pattern) = if path.is_dir() {
+        (path, "*.json")
+    } else {
+        match (path.parent(), path.file_name().and_then(|name| name.to_str())) {
+            (Some(dir), Some(name)) if name.contains(['*', '?']) => {
+                (if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir }, name)
+            }
+            _ => return Ok(None),
+        }
+    };
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("Failed to read config directory {}: {}", dir.display(), e))?;
+    let mut files: Vec<std::path::PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|file| file.is_file())
+        .filter(|file| {
+            file.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.') && wildcard_match(pattern, name))
+        })
+        .collect();
+    files.sort();
+    Ok(Some(files))
+}
+
+// `*` matches any run of characters and `?` exactly one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Fragments are read in file name order and their projects combined into one config; a project
+// name defined in two fragments fails the whole load, naming both files, rather than letting one
+// silently win. Cross-project checks (aliases, lock groups) run on the merged result.
+fn merge_fragments(source: &str, files: &[std::path::PathBuf]) -> Result<ConfigFile, String> {
+    if files.is_empty() {
+        if env_flag("ALLOW_EMPTY_CONFIG") {
+            warn!("⚠️⚠️⚠️ No config files match '{}'; starting with NO projects (ALLOW_EMPTY_CONFIG is set)", source);
+            return Ok(ConfigFile::new());
+        }
+        return Err(format!("No config files match '{}'", source));
+    }
+    let mut merged = ConfigFile::new();
+    let mut defined_in: HashMap<String, &std::path::PathBuf> = HashMap::new();
+    for file in files {
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read config file {}: {}", file.display(), e))?;
+        let fragment = parse_content(&content).map_err(|e| format!("{}: {}", file.display(), e))?;
+        for (name, entry) in fragment {
+            if let Some(first) = defined_in.get(&name) {
+                return Err(format!(
+                    "Project '{}' is defined in both {} and {}",
+                    name,
+                    first.display(),
+                    file.display()
+                ));
+            }
+            defined_in.insert(name.clone(), file);
+            merged.insert(name, entry);
+        }
+    }
+    info!("Merged {} config file(s) from {}", files.len(), source);
+    Ok(merged)
+}
+
+// Expands `${VAR}` in every string value (not keys) from the environment, so one config works on
+// hosts with different homes; `$$` is a literal `$`. An unset variable fails the load, or with
+// CONFIG_KEEP_UNSET_VARS is left as written. `${GRAFT_*}` is deploy-time context (see
+// deploy::set_deploy_env) and always left for the deploy's commands to expand.
+fn interpolate_env(value: &mut serde_json::Value, keep_unset: bool) -> Result<(), String> {
+    match value {
+        serde_json::Value::String(s) if s.contains('$') => *s = expand_vars(s, keep_unset)?,
+        serde_json::Value::Array(items) => {
+            for item in items {
+                interpolate_env(item, keep_unset)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for item in map.values_mut() {
+                interpolate_env(item, keep_unset)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_vars(input: &str, keep_unset: bool) -> Result<String, String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some((name, after)) = rest.strip_prefix("${").and_then(|r| r.split_once('}')) {
+            match env::var(name) {
+                _ if name.starts_with("GRAFT_") => out.push_str(&rest[..name.len() + 3]),
+                Ok(value) => out.push_str(&value),
+                Err(_) if keep_unset => out.push_str(&rest[..name.len() + 3]),
+                Err(_) => return Err(format!("Config references unset environment variable '{}'", name)),
+            }
+            rest = after;
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Case-insensitive, ignoring a trailing "/" or ".git" (GitHub treats "Org/Repo.git" and "org/repo" alike)
+pub(crate) fn repository_matches(expected: &str, actual: Option<&str>) -> bool {
+    fn normalize(repo: &str) -> String {
+        let repo = repo.trim().trim_end_matches('/');
+        repo.strip_suffix(".git").unwrap_or(repo).to_ascii_lowercase()
+    }
+    actual.is_some_and(|actual| normalize(actual) == normalize(expected))
+}
+
+// Exact key first. With `normalize` (NORMALIZE_PROJECT_NAMES), a miss is retried after dropping
+// everything up to the last '/' ("Org/My-App" -> "My-App") and comparing case-insensitively
+// against the keys. A normalized name matching several keys ("app" and "App") matches none.
+pub(crate) fn find_project<'a>(
+    config: &'a ConfigFile,
+    name: &str,
+    normalize: bool,
+) -> Option<(&'a str, &'a ProjectEntry)> {
+    if let Some((key, entry)) = config.get_key_value(name) {
+        return Some((key.as_str(), entry));
+    }
+    if !normalize {
+        return None;
+    }
+
+    let wanted = name.rsplit('/').next().unwrap_or(name).trim();
+    let mut matches = config.iter().filter(|(key, _)| key.eq_ignore_ascii_case(wanted));
+    match (matches.next(), matches.next()) {
+        (Some((key, entry)), None) => Some((key.as_str(), entry)),
+        (Some(_), Some(_)) => {
+            warn!("Project name '{}' matches several config keys after normalization", name);
+            None
+        }
+        _ => None,
+    }
+}
+
+// Config validation: empty paths are fatal, shared paths only warn
+pub fn validate_config(config: &ConfigFile) -> Result<(), String> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+
+    let mut names: Vec<&String> = config.keys().collect();
+    names.sort();
+
+    for name in names {
+        let entry = &config[name];
+        for path in [entry.path(), entry.repo_path(), entry.compose_path()] {
+            if path.trim().is_empty() {
+                return Err(format!("Project '{}' has an empty path", name));
+            }
+        }
+
+        for (key, value) in &entry.git_options().config {
+            if !is_valid_git_config_key(key) || !is_safe_git_config_value(value) {
+                return Err(format!("Project '{}' has an invalid git_config entry: {}", name, key));
+            }
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            if c.allow_clone && c.clone_url.as_deref().is_none_or(|url| url.trim().is_empty()) {
+                return Err(format!("Project '{}' sets allow_clone without a clone_url", name));
+            }
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            if c.compose_url.is_none() && (c.compose_url_auth.is_some() || c.compose_sha256.is_some()) {
+                return Err(format!("Project '{}' sets compose_url_auth or compose_sha256 without compose_url", name));
+            }
+        }
+        if let Some(remote) = entry.remote_compose_file() {
+            if !remote.url.starts_with("https://") && !remote.url.starts_with("http://") {
+                return Err(format!("Project '{}' has a compose_url that is not http(s)", name));
+            }
+            if remote.sha256.as_ref().is_some_and(|sum| sum.len() != 64 || !sum.chars().all(|c| c.is_ascii_hexdigit())) {
+                return Err(format!("Project '{}' has a compose_sha256 that is not 64 hex digits", name));
+            }
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            for (field, pattern) in [("failure_pattern", &c.failure_pattern), ("success_pattern", &c.success_pattern)] {
+                if let Some(Err(e)) = pattern.as_deref().map(regex::Regex::new) {
+                    return Err(format!("Project '{}' has an invalid {}: {}", name, field, e));
+                }
+            }
+            for pattern in &c.mask_patterns {
+                match regex::Regex::new(pattern) {
+                    Err(e) => return Err(format!("Project '{}' has an invalid mask pattern: {}", name, e)),
+                    // Would put *** between every character
+                    Ok(re) if re.is_match("") => {
+                        return Err(format!("Project '{}' has a mask pattern that matches empty text: {}", name, pattern));
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            if let Some(unknown) = c.confirm_types.iter().find(|t| !DEPLOY_TYPES.contains(&t.as_str())) {
+                return Err(format!(
+                    "Project '{}' has unknown type '{}' in confirm_types; known types are {}",
+                    name,
+                    unknown,
+                    DEPLOY_TYPES.join(", ")
+                ));
+            }
+            if let Some(unknown) = c.allowed_types.iter().flatten().find(|t| !DEPLOY_TYPES.contains(&t.as_str())) {
+                return Err(format!(
+                    "Project '{}' allows unknown type '{}'; known types are {}",
+                    name,
+                    unknown,
+                    DEPLOY_TYPES.join(", ")
+                ));
+            }
+        }
+
+        if let Some(unknown) = entry.allowed_options().iter().find(|o| !COMPOSE_TOGGLES.contains(&o.as_str())) {
+            return Err(format!(
+                "Project '{}' allows unknown option '{}'; known: {}",
+                name,
+                unknown,
+                COMPOSE_TOGGLES.join(", ")
+            ));
+        }
+
+        let empty_provider = match entry.credential_provider().map(|source| &source.kind) {
+            Some(CredentialKind::Env { var }) => var.trim().is_empty(),
+            Some(CredentialKind::File { path }) => path.trim().is_empty(),
+            Some(CredentialKind::Command { command }) => command.is_empty(),
+            None => false,
+        };
+        if empty_provider {
+            return Err(format!("Project '{}' has an empty credential_provider", name));
+        }
+        for (field, creds) in [("git_credentials", entry.git_credentials()), ("docker_credentials", entry.docker_credentials())] {
+            if creds.is_some_and(|c| c.user.trim().is_empty() || c.token.trim().is_empty()) {
+                return Err(format!("Project '{}' has an empty user or token in {}", name, field));
+            }
+        }
+        if let ProjectEntry::Full(c) = entry {
+            if c.require_fast_forward && c.shallow {
+                return Err(format!("Project '{}' sets require_fast_forward, which needs full history; drop shallow", name));
+            }
+        }
+        if entry.build_parallelism() == Some(0) {
+            return Err(format!("Project '{}' has build_parallelism 0; use at least 1", name));
+        }
+        if entry.nice().is_some_and(|n| !NICE_LEVELS.contains(&n)) {
+            return Err(format!("Project '{}' has nice {}; use 0 to 19", name, entry.nice().unwrap_or_default()));
+        }
+        if entry.ionice_class().is_some_and(|class| !IONICE_CLASSES.contains(&class)) {
+            return Err(format!(
+                "Project '{}' has ionice_class {}; use 2 (best-effort) or 3 (idle)",
+                name,
+                entry.ionice_class().unwrap_or_default()
+            ));
+        }
+        if entry.lock_group().is_some_and(|group| group.trim().is_empty()) {
+            return Err(format!("Project '{}' has an empty lock_group", name));
+        }
+        if let Some(host) = entry.ssh_host() {
+            if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') || host.starts_with('-') {
+                return Err(format!("Project '{}' has an invalid ssh_host '{}'; use user@host or user@host:port", name, host));
+            }
+            // sudo resets the environment that carries DOCKER_HOST
+            if entry.use_sudo() {
+                return Err(format!("Project '{}' sets both ssh_host and use_sudo; sudo is not used for remote engines", name));
+            }
+        }
+        if entry.ssh_key().is_some() && entry.ssh_host().is_none() {
+            return Err(format!("Project '{}' sets ssh_key without ssh_host", name));
+        }
+        if let ProjectEntry::Full(c) = entry {
+            if let Err(e) = DeployWindows::parse(&c.deploy_windows, c.deploy_timezone.as_deref()) {
+                return Err(format!("Project '{}': {}", name, e));
+            }
+        }
+        if entry.max_concurrency() == 0 {
+            return Err(format!("Project '{}' has max_concurrency 0; use at least 1", name));
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            if c.wait_timeout_secs.is_some() && !c.wait {
+                return Err(format!("Project '{}' sets wait_timeout_secs without wait", name));
+            }
+            if c.wait_timeout_secs == Some(0) {
+                return Err(format!("Project '{}' has wait_timeout_secs 0; use a positive number of seconds", name));
+            }
+        }
+
+        if entry.stop_timeout() == Some(0) {
+            return Err(format!("Project '{}' has stop_timeout 0; use a positive number of seconds", name));
+        }
+
+        if entry.circuit_breaker().is_some_and(|(threshold, _)| threshold == 0) {
+            return Err(format!("Project '{}' has max_consecutive_failures 0; use at least 1", name));
+        }
+
+        if entry.git_options().atomic_worktree && entry.compose_path() == entry.repo_path() {
+            return Err(format!(
+                "Project '{}' uses atomic_worktree, so compose_path must be a symlink separate from repo_path",
+                name
+            ));
+        }
+
+        if let Some(keep) = entry.git_options().retain_releases {
+            if !entry.git_options().atomic_worktree {
+                return Err(format!("Project '{}' sets retain_releases, which needs atomic_worktree", name));
+            }
+            if keep == 0 {
+                return Err(format!("Project '{}' has retain_releases 0; keep at least the live release", name));
+            }
+        }
+
+        if entry.post_up_exec().iter().any(|args| args.is_empty()) {
+            return Err(format!("Project '{}' has an empty post_up_exec entry", name));
+        }
+
+        if let Some(argv) = entry.command() {
+            if argv.first().is_none_or(|program| program.trim().is_empty()) {
+                return Err(format!("Project '{}' has an empty command; give at least a program", name));
+            }
+        }
+
+        if let ProjectEntry::Full(c) = entry {
+            match (&c.cosign_key, &c.cosign_identity, &c.cosign_oidc_issuer) {
+                (Some(_), None, None) | (None, None, None) | (None, Some(_), Some(_)) => {}
+                (Some(_), _, _) => {
+                    return Err(format!(
+                        "Project '{}' sets both cosign_key and keyless cosign_identity/cosign_oidc_issuer; pick one",
+                        name
+                    ));
+                }
+                _ => {
+                    return Err(format!(
+                        "Project '{}' needs both cosign_identity and cosign_oidc_issuer for keyless verification",
+                        name
+                    ));
+                }
+            }
+        }
+
+        if let Some(health) = entry.health_check() {
+            if health.command.first().is_none_or(|program| program.trim().is_empty()) {
+                return Err(format!("Project '{}' has an empty health_command; give at least a program", name));
+            }
+            if health.timeout.is_zero() {
+                return Err(format!("Project '{}' has health_timeout_secs 0; use at least 1", name));
+            }
+        }
+        if let Some(smoke) = entry.smoke_test() {
+            if smoke.command.first().is_none_or(|program| program.trim().is_empty()) {
+                return Err(format!("Project '{}' has an empty smoke_test_command; give at least a program", name));
+            }
+            if smoke.timeout.is_zero() {
+                return Err(format!("Project '{}' has smoke_test_timeout_secs 0; use at least 1", name));
+            }
+        }
+
+        if let Some(branch) = entry.git_options().fallback_branch {
+            if !is_valid_git_ref(&branch) {
+                return Err(format!("Project '{}' has an invalid fallback_branch: {}", name, branch));
+            }
+        }
+        for step in &entry.git_options().steps {
+            if !is_valid_git_ref(&step.remote) || !is_valid_git_ref(&step.branch) {
+                return Err(format!(
+                    "Project '{}' has an invalid git step: {} {}",
+                    name, step.remote, step.branch
+                ));
+            }
+        }
+
+        let path = entry.repo_path();
+
+        // Compare canonical paths so aliases like "/srv/app" and "/srv/app/" are caught
+        let key = std::fs::canonicalize(path)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.trim_end_matches('/').to_string());
+
+        if let Some(other) = seen.get(&key) {
+            warn!(
+                "⚠️ Projects '{}' and '{}' share the same path ({}); concurrent deploys may conflict",
+                other, name, key
+            );
+        } else {
+            seen.insert(key, name);
+        }
+    }
+
+    Ok(())
+}
+
+// Compose profile and service names end up in a shell command, so only allow compose's own charset
+pub(crate) fn is_valid_compose_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphanumeric() => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+// Dotted names like "http.postBuffer" or "core.sshCommand"
+fn is_valid_git_config_key(key: &str) -> bool {
+    key.contains('.')
+        && !key.starts_with('.')
+        && !key.ends_with('.')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+// Values are quoted for the shell anyway; metacharacters are rejected so configs stay boring
+fn is_safe_git_config_value(value: &str) -> bool {
+    !value.chars().any(|c| c.is_control() || "`$;&|<>\\'\"(){}*?!#~".contains(c))
+}
+
+// Ad-hoc paths end up in shell commands and must sit under an allowed prefix (whole components,
+// so /srv/apps doesn't allow /srv/apps-old); no `..`, nothing relative, nothing exotic
+pub(crate) fn adhoc_path_allowed(path: &str, prefixes: &[String]) -> bool {
+    let candidate = std::path::Path::new(path);
+    candidate.is_absolute()
+        && path.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/'))
+        && candidate
+            .components()
+            .all(|c| matches!(c, std::path::Component::RootDir | std::path::Component::Normal(_)))
+        && prefixes.iter().any(|prefix| candidate.starts_with(prefix) && candidate != std::path::Path::new(prefix))
+}
+
+// Remote and branch names are interpolated into a shell command; refuse anything exotic
+pub(crate) fn is_valid_git_ref(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/'))
+}
+
+// Docker's tag grammar: a word character, then up to 127 word characters, dots and dashes
+pub(crate) fn is_valid_image_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphanumeric() || c == '_' => {}
+        _ => return false,
+    }
+    tag.len() <= 128 && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> ConfigFile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    // Stops at the first deserialize_struct call, keeping the field names serde asked for
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom("captured"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+            unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    #[test]
+    fn payload_schema_covers_every_payload_field() {
+        let mut fields: &'static [&'static str] = &[];
+        assert!(WebhookPayload::deserialize(FieldNames(&mut fields)).is_err());
+        let schema = payload_schema();
+        let mut documented: Vec<&str> = schema["properties"].as_object().unwrap().keys().map(String::as_str).collect();
+        let mut fields = fields.to_vec();
+        documented.sort_unstable();
+        fields.sort_unstable();
+        assert_eq!(documented, fields);
+        assert_eq!(schema["properties"]["type"]["enum"].as_array().unwrap().len(), DEPLOY_TYPES.len());
+        // The fallbacks named are the variables credential resolution reads
+        let described = |field: &str| schema["properties"][field]["description"].as_str().unwrap().to_string();
+        assert!(described("token").contains(GIT_TOKEN_VAR) && described("token").contains(DOCKER_TOKEN_VAR));
+        assert!(described("user").contains(USER_VAR));
+    }
+
+    #[test]
+    fn blank_payload_fields_are_missing_or_unset() {
+        let payload = |json: serde_json::Value| -> WebhookPayload { serde_json::from_value(json).unwrap() };
+
+        let mut blank_type = payload(serde_json::json!({ "project": "app", "type": " " }));
+        assert_eq!(blank_type.normalize_blank_fields(false), Err("type"));
+        let mut blank_project = payload(serde_json::json!({ "project": "", "type": "repo" }));
+        assert_eq!(blank_project.normalize_blank_fields(false), Err("project"));
+
+        let mut blank_registry = payload(serde_json::json!({ "project": "app", "type": "image", "registry": "", "tag": "v1" }));
+        assert_eq!(blank_registry.normalize_blank_fields(false), Ok(()));
+        assert_eq!(blank_registry.registry, None);
+        assert_eq!(blank_registry.tag.as_deref(), Some("v1"));
+        let mut strict = payload(serde_json::json!({ "project": "app", "type": "image", "registry": "  " }));
+        assert_eq!(strict.normalize_blank_fields(true), Err("registry"));
+    }
+
+    #[test]
+    fn oversized_payload_fields_are_reported() {
+        let payload = |json: serde_json::Value| -> WebhookPayload { serde_json::from_value(json).unwrap() };
+        let limits = FieldLimits { max_len: 8, max_token_len: 16 };
+
+        let ok = payload(serde_json::json!({ "project": "app", "type": "repo", "token": "0123456789abcdef" }));
+        assert_eq!(ok.oversized_field(limits), None);
+
+        let long_project = payload(serde_json::json!({ "project": "a".repeat(9), "type": "repo" }));
+        assert_eq!(long_project.oversized_field(limits), Some("project"));
+
+        let long_token = payload(serde_json::json!({ "project": "app", "type": "repo", "token": "t".repeat(17) }));
+        assert_eq!(long_token.oversized_field(limits), Some("token"));
+
+        let long_service = payload(serde_json::json!({ "project": "app", "type": "repo", "scale": { "w".repeat(9): 2 } }));
+        assert_eq!(long_service.oversized_field(limits), Some("scale"));
+    }
+
+    #[test]
+    fn image_tags_follow_docker_tag_grammar() {
+        assert!(is_valid_image_tag("v1.4.0"));
+        assert!(is_valid_image_tag("sha-3f2a9c1_build.7"));
+        assert!(!is_valid_image_tag(".hidden"));
+        assert!(!is_valid_image_tag("-rc1"));
+        assert!(!is_valid_image_tag("1.0;rm"));
+        assert!(!is_valid_image_tag(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn git_config_rejects_shell_metacharacters() {
+        let ok = config(r#"{"app": {"path": "/srv/app", "git_config": {"core.sshCommand": "ssh -i /keys/deploy -o IdentitiesOnly=yes"}}}"#);
+        assert!(validate_config(&ok).is_ok());
+
+        let bad_value = config(r#"{"app": {"path": "/srv/app", "git_config": {"http.postBuffer": "1; rm -rf /"}}}"#);
+        assert!(validate_config(&bad_value).is_err());
+
+        let bad_key = config(r#"{"app": {"path": "/srv/app", "git_config": {"$(id)": "1"}}}"#);
+        assert!(validate_config(&bad_key).is_err());
+    }
+
+    #[test]
+    fn config_type_mistakes_name_the_project_and_field() {
+        let parse = |json: &str| parse_config(serde_json::from_str(json).unwrap()).map(|_| ()).unwrap_err();
+        assert_eq!(parse(r#"{"foo": 42}"#), "project 'foo': expected a path string or config object, found number");
+        assert_eq!(parse(r#"["/srv/app"]"#), "expected an object of project names, found array");
+        assert!(parse(r#"{"foo": {"path": "/srv/foo", "shallow": "yes"}}"#).starts_with("project 'foo': field 'shallow': invalid type"));
+        assert!(parse(r#"{"foo": {"shallow": true}}"#).contains("missing field `path`"));
+    }
+
+    #[test]
+    fn config_directories_merge_their_fragments() {
+        let dir = env::temp_dir().join(format!("graft-hook-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a-web.json"), r#"{"web": "/srv/web"}"#).unwrap();
+        std::fs::write(dir.join("b-api.json"), r#"{"api": {"path": "/srv/api"}}"#).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not config").unwrap();
+        std::fs::write(dir.join(".tmp.json"), "{").unwrap();
+
+        let load = |path: &std::path::Path| ConfigSource::File(path.display().to_string()).load();
+        let merged = load(&dir).unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["api"].path(), "/srv/api");
+        assert_eq!(load(&dir.join("b-*.json")).unwrap().len(), 1);
+
+        std::fs::write(dir.join("c-web.json"), r#"{"web": "/srv/other"}"#).unwrap();
+        let err = load(&dir).unwrap_err();
+        assert!(err.contains("Project 'web' is defined in both") && err.ends_with("c-web.json"), "{}", err);
+        assert!(wildcard_match("team-?.json", "team-a.json") && !wildcard_match("*.json", "web.json.bak"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_paths_are_trimmed() {
+        let config = parse_config(serde_json::json!({
+            "short": " /srv/short\n",
+            "full": { "path": "/srv/full ", "compose_files": ["\tdocker-compose.prod.yml"], "env_file": ".env" },
+        }))
+        .unwrap();
+        assert_eq!(config["short"].path(), "/srv/short");
+        assert_eq!(config["full"].path(), "/srv/full");
+        assert_eq!(config["full"].compose_files(), ["docker-compose.prod.yml"]);
+        assert_eq!(config["full"].env_file(), Some(".env"));
+    }
+
+    #[test]
+    fn allowed_types_restrict_webhook_types() {
+        let projects = config(r#"{"prod": {"path": "/srv/prod", "allowed_types": ["repo", "restart"]}, "dev": "/srv/dev"}"#);
+        assert!(validate_config(&projects).is_ok());
+        assert!(projects["prod"].allows_type("restart"));
+        assert!(!projects["prod"].allows_type("down"));
+        assert!(projects["dev"].allows_type("down"));
+
+        let typo = config(r#"{"prod": {"path": "/srv/prod", "allowed_types": ["deploy"]}}"#);
+        assert!(validate_config(&typo).unwrap_err().contains("unknown type 'deploy'"));
+    }
+
+    #[test]
+    fn destructive_requests_are_confirmed_by_true_or_the_project_name() {
+        let projects = config(r#"{"db": {"path": "/srv/db", "confirm_types": ["down", "teardown"]}}"#);
+        assert!(validate_config(&projects).is_ok());
+        assert!(projects["db"].requires_confirm("down"));
+        assert!(!projects["db"].requires_confirm("repo"));
+
+        let payload = |extra: serde_json::Value| -> WebhookPayload {
+            let mut body = serde_json::json!({ "project": "db", "type": "down" });
+            body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            serde_json::from_value(body).unwrap()
+        };
+        assert!(payload(serde_json::json!({ "confirm": true })).confirms());
+        assert!(payload(serde_json::json!({ "confirm": "db" })).confirms());
+        assert!(payload(serde_json::json!({ "confirm_project": "db" })).confirms());
+        assert!(!payload(serde_json::json!({})).confirms());
+        assert!(!payload(serde_json::json!({ "confirm": false })).confirms());
+        assert!(!payload(serde_json::json!({ "confirm_project": "web" })).confirms());
+
+        let typo = config(r#"{"db": {"path": "/srv/db", "confirm_types": ["wipe"]}}"#);
+        assert!(validate_config(&typo).unwrap_err().contains("unknown type 'wipe' in confirm_types"));
+    }
+
+    #[test]
+    fn adhoc_paths_must_sit_under_an_allowed_prefix() {
+        let prefixes = vec!["/srv/apps".to_string()];
+        assert!(adhoc_path_allowed("/srv/apps/preview-42", &prefixes));
+        assert!(!adhoc_path_allowed("/srv/apps", &prefixes));
+        assert!(!adhoc_path_allowed("/srv/apps-old/x", &prefixes));
+        assert!(!adhoc_path_allowed("/srv/apps/../etc", &prefixes));
+        assert!(!adhoc_path_allowed("srv/apps/x", &prefixes));
+        assert!(!adhoc_path_allowed("/srv/apps/x;id", &prefixes));
+        assert!(!adhoc_path_allowed("/srv/apps/x", &[]));
+    }
+
+    #[test]
+    fn config_strings_expand_environment_variables() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand_vars("${HOME}/apps/foo", false).unwrap(), format!("{}/apps/foo", home));
+        assert_eq!(expand_vars("cost: $$5, $x", false).unwrap(), "cost: $5, $x");
+        assert_eq!(expand_vars("app:${GRAFT_COMMIT}", false).unwrap(), "app:${GRAFT_COMMIT}");
+        assert!(expand_vars("${GRAFTHOOK_TEST_SURELY_UNSET}/x", false).is_err());
+        assert_eq!(expand_vars("${GRAFTHOOK_TEST_SURELY_UNSET}/x", true).unwrap(), "${GRAFTHOOK_TEST_SURELY_UNSET}/x");
+
+        let mut raw = serde_json::json!({ "app": { "path": "${HOME}/app", "git_steps": [{ "remote": "$$o" }] } });
+        interpolate_env(&mut raw, false).unwrap();
+        assert_eq!(raw["app"]["path"], format!("{}/app", home));
+        assert_eq!(raw["app"]["git_steps"][0]["remote"], "$o");
+    }
+
+    #[test]
+    fn normalized_lookup_strips_the_owner_and_ignores_case() {
+        let projects = config(r#"{"my-app": "/srv/my-app", "Tool": "/srv/tool", "tool": "/srv/tool2"}"#);
+
+        assert!(find_project(&projects, "Org/My-App", false).is_none());
+        assert_eq!(find_project(&projects, "Org/My-App", true).map(|(key, _)| key), Some("my-app"));
+        assert_eq!(find_project(&projects, "Tool", true).map(|(key, _)| key), Some("Tool"));
+        assert!(find_project(&projects, "org/TOOL", true).is_none());
+    }
+}
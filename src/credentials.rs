@@ -0,0 +1,145 @@
+// Pluggable sources for the token a project deploys with (credential_provider), so secrets can
+// live in a file or a secrets manager's CLI instead of the server's environment
+use std::{future::Future, pin::Pin, process::Stdio, time::Duration};
+use tokio::process::Command;
+
+use tracing::warn;
+
+use crate::config::{CredentialKind, CredentialSource};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>>;
+
+pub trait CredentialProvider: Send + Sync {
+    // For logs and readiness checks; never the token itself
+    fn describe(&self) -> String;
+    fn token(&self) -> TokenFuture<'_>;
+}
+
+// The value of an environment variable other than the GIT_PAT_TOKEN / DOCKER_ACCESS_TOKEN defaults
+pub struct EnvProvider {
+    var: String,
+}
+
+// A file holding just the token, e.g. a mounted docker or kubernetes secret
+pub struct FileProvider {
+    path: String,
+}
+
+// Whatever a command prints on stdout, e.g. `vault kv get -field=token secret/deploy`. Run
+// without a shell; its output is a secret, so neither stdout nor stderr is logged.
+pub struct CommandProvider {
+    argv: Vec<String>,
+}
+
+pub fn provider(source: &CredentialSource) -> Box<dyn CredentialProvider> {
+    match &source.kind {
+        CredentialKind::Env { var } => Box::new(EnvProvider { var: var.clone() }),
+        CredentialKind::File { path } => Box::new(FileProvider { path: path.clone() }),
+        CredentialKind::Command { command } => Box::new(CommandProvider { argv: command.clone() }),
+    }
+}
+
+// Tokens are single-line; trailing newlines from files and `echo` are not part of them
+fn trimmed(token: &str) -> Result<String, String> {
+    let token = token.trim();
+    if token.is_empty() {
+        return Err("produced an empty token".to_string());
+    }
+    Ok(token.to_string())
+}
+
+impl CredentialProvider for EnvProvider {
+    fn describe(&self) -> String {
+        format!("env {}", self.var)
+    }
+
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            let value = std::env::var(&self.var).map_err(|_| format!("{} is not set", self.var))?;
+            trimmed(&value)
+        })
+    }
+}
+
+impl CredentialProvider for FileProvider {
+    fn describe(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            let value = tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(|e| format!("reading {}: {}", self.path, e))?;
+            // A final newline is how most editors save a file; anything else is worth knowing about
+            if value.trim().len() != value.trim_end_matches('\n').len() {
+                warn!("⚠️ Trimmed surrounding whitespace from the token in {}", self.path);
+            }
+            trimmed(&value)
+        })
+    }
+}
+
+impl CredentialProvider for CommandProvider {
+    fn describe(&self) -> String {
+        format!("command {}", self.argv.first().map_or("", String::as_str))
+    }
+
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            let (program, args) = self.argv.split_first().ok_or("empty command")?;
+            let run = Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output();
+            let out = match tokio::time::timeout(COMMAND_TIMEOUT, run).await {
+                Ok(Ok(out)) => out,
+                Ok(Err(e)) => return Err(format!("failed to run {}: {}", program, e)),
+                Err(_) => return Err(format!("{} did not finish within {}s", program, COMMAND_TIMEOUT.as_secs())),
+            };
+            if !out.status.success() {
+                return Err(format!("{} exited with {}", program, out.status));
+            }
+            trimmed(&String::from_utf8_lossy(&out.stdout))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(json: serde_json::Value) -> CredentialSource {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn providers_read_env_files_and_commands() {
+        let file = std::env::temp_dir().join(format!("graft-hook-token-{}", std::process::id()));
+        std::fs::write(&file, "from-file\n").unwrap();
+        let from_file = provider(&source(serde_json::json!({ "provider": "file", "path": file })));
+        assert_eq!(from_file.token().await.unwrap(), "from-file");
+        std::fs::remove_file(&file).unwrap();
+
+        let from_env = provider(&source(serde_json::json!({ "provider": "env", "var": "CARGO_PKG_NAME" })));
+        assert_eq!(from_env.token().await.unwrap(), "graft-hook");
+
+        let command = source(serde_json::json!({ "provider": "command", "command": ["echo", "s3cret"], "user": "ci" }));
+        assert_eq!(command.user.as_deref(), Some("ci"));
+        let from_command = provider(&command);
+        assert_eq!(from_command.token().await.unwrap(), "s3cret");
+        assert_eq!(from_command.describe(), "command echo");
+    }
+
+    #[tokio::test]
+    async fn failing_or_silent_commands_are_errors() {
+        let failing = provider(&source(serde_json::json!({ "provider": "command", "command": ["false"] })));
+        assert!(failing.token().await.unwrap_err().starts_with("false exited with"));
+
+        let silent = provider(&source(serde_json::json!({ "provider": "command", "command": ["true"] })));
+        assert_eq!(silent.token().await.unwrap_err(), "produced an empty token");
+    }
+}
@@ -0,0 +1,4795 @@
+// Deploy execution: credentials, git sync, compose up, and the process runner behind them
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    env,
+    future::Future,
+    pin::Pin,
+    process::{Output, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::process::Command;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+use crate::config::{
+    is_valid_compose_name, CredentialSource, GitOptions, GitStep, GitStrategy, HealthCheck, ImagePolicy, OutputPatterns, ProjectEntry, RegistryCredentials, RemoteComposeFile,
+    SmokeTest, WebhookPayload, DOCKER_TOKEN_VAR, GIT_TOKEN_VAR, IONICE_CLASSES, MIN_SCALE, NICE_LEVELS, USER_VAR,
+};
+use crate::credentials;
+use crate::metrics::Metrics;
+
+// Per-deploy compose settings shared by all deploy functions
+#[derive(Debug, Default, Clone)]
+pub struct ComposeOptions {
+    pub profiles: Vec<String>,
+    pub scale: BTreeMap<String, u32>,
+    // Skip --build in repo mode (config-only changes)
+    pub no_build: bool,
+    pub env_file: Option<String>,
+    pub use_sudo: bool,
+    // Preflight `docker compose config` before `up`
+    pub validate: bool,
+    // Repo mode: `docker compose pull` before `up --build`
+    pub pull: bool,
+    // Repo mode: `docker login` before the build, when a registry and credentials are available
+    pub build_login: bool,
+    // ...concurrently with the git fetch, which it doesn't depend on
+    pub parallel_login: bool,
+    // `nice -n` / `ionice -c` in front of every compose command
+    pub nice: Option<i32>,
+    pub ionice_class: Option<u8>,
+    // Repo mode: COMPOSE_PARALLEL_LIMIT for `up --build`, so big stacks don't exhaust small hosts
+    pub build_parallelism: Option<u32>,
+    // Repo mode: extra attempts at `up` after a failed one; when set, the build runs on its own
+    // first so a retry doesn't rebuild
+    pub up_retries: u32,
+    // `up --timeout <secs>` for graceful container shutdown
+    pub stop_timeout: Option<u32>,
+    // `up --wait`, with `--wait-timeout <secs>` when set
+    pub wait: Option<Option<u32>>,
+    // Argv run without a shell in place of `docker compose up`
+    pub command: Option<Vec<String>>,
+    // Restart mode: `down` + `up -d` instead of `docker compose restart`
+    pub recreate_on_restart: bool,
+    // Down mode: `down --volumes`
+    pub remove_volumes: bool,
+    // Compose subcommands run after a successful `up`, e.g. ["run", "--rm", "app", "migrate"]
+    pub post_up: Vec<Vec<String>>,
+    // Repo mode: reset to the previous commit and rebuild when a post_up command or the health check fails
+    pub post_up_rollback: bool,
+    // Repo mode: the same, when the build or `up` itself fails
+    pub restore_on_failure: bool,
+    // Retried after post_up until it passes or times out
+    pub health: Option<HealthCheck>,
+    // Run once after the health check; only reported on
+    pub smoke_test: Option<SmokeTest>,
+    // Image mode: signatures every compose image must carry before it is pulled
+    pub verify_images: Option<ImagePolicy>,
+    // Downloaded by run_job into `files`
+    pub remote_file: Option<RemoteComposeFile>,
+    // Payload compose_override: written by run_job to a file added after the others, and
+    // always validated with `config -q`, which is where malformed YAML is caught
+    pub inline_override: Option<String>,
+    // failure_pattern / success_pattern checked against the output of `up`
+    pub output_patterns: Option<OutputPatterns>,
+    // `-p`: set for PR previews so they don't replace the main deploy's containers
+    pub project_name: Option<String>,
+    // Image mode: exported as TAG so `image: repo:${TAG}` pulls exactly this tag
+    pub image_tag: Option<String>,
+    // Explicit -f files; compose then runs with the project dir as --project-directory
+    pub files: Vec<String>,
+    // --project-directory in place of the project dir
+    pub project_directory: Option<String>,
+    // `up --remove-orphans` / `up --force-recreate`
+    pub remove_orphans: bool,
+    pub force_recreate: bool,
+    // Remote engine reached as DOCKER_HOST=ssh://<ssh_host>, with an optional identity file
+    pub ssh_host: Option<String>,
+    pub ssh_key: Option<String>,
+}
+
+impl ComposeOptions {
+    // Applies one of config::COMPOSE_TOGGLES; the caller has checked the project allows it
+    pub fn set_toggle(&mut self, name: &str, enabled: bool) {
+        match name {
+            "remove_orphans" => self.remove_orphans = enabled,
+            "force_recreate" => self.force_recreate = enabled,
+            "no_build" => self.no_build = enabled,
+            "pull" => self.pull = enabled,
+            _ => warn!("Ignoring unknown compose option {}", name),
+        }
+    }
+}
+
+// Why a deploy failed; Display keeps the short messages clients already rely on
+#[derive(Debug, Clone)]
+pub enum DeployError {
+    MissingCredentials(&'static str),
+    MissingRegistry,
+    // Why the configured credential_provider produced no token
+    CredentialProvider(String),
+    BinaryNotFound(&'static str),
+    EnvFileMissing(String),
+    ComposeFileMissing(String),
+    ComposeDownload(String),
+    GitPull(String),
+    GitClone(String),
+    GitTag(String),
+    GitStep(usize, String),
+    SubmoduleUpdate(String),
+    DockerLogin(String),
+    ComposeInvalid(String),
+    // Output tail plus the services that failed, when they could be identified
+    ComposeBuild(String, Vec<String>),
+    ComposePull(String, Vec<String>),
+    // COMPOSE_UP_RETRIES: `up` still failing after this many attempts of an already built stack
+    ComposeUp(u32, String, Vec<String>),
+    ComposeRestart(String),
+    ComposeStop(String),
+    PostUp(usize, String),
+    PreDeployHook(String),
+    // Seconds waited, plus the last attempt's output tail
+    Unhealthy(u64, String),
+    // Image whose signature didn't verify, plus cosign's output tail
+    SignatureInvalid(String, String),
+    // Which pattern rejected the output, plus its tail
+    OutputRejected(String, String),
+    ReleaseSwitch(String),
+    // Why there is no retained release to go back to
+    RollbackUnavailable(String),
+    // The deployed commit, and the fetched one that doesn't descend from it
+    NotFastForward(String, String),
+    // The project directory, and why a file can't be written there (read-only, full, permissions)
+    NotWritable(String, String),
+    // restore_on_failure: the build or `up` failure, and whether the previous commit came back up
+    Restored(Box<DeployError>, bool),
+    // Seconds waited for the project's lock (LOCK_WAIT_TIMEOUT_SECS) before giving up
+    ProjectBusy(u64),
+    Spawn(String),
+    Panicked(String),
+    Cancelled,
+    TimedOut(u64),
+    InvalidMode(String),
+}
+
+impl std::fmt::Display for DeployError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeployError::MissingCredentials(kind) => write!(f, "Missing {} Credentials", kind),
+            DeployError::MissingRegistry => write!(f, "Missing Registry"),
+            DeployError::CredentialProvider(_) => write!(f, "Credential provider failed"),
+            DeployError::BinaryNotFound("git") => write!(f, "Git Binary Not Found"),
+            DeployError::BinaryNotFound("cosign") => write!(f, "Cosign Binary Not Found"),
+            DeployError::BinaryNotFound(_) => write!(f, "Docker Binary Not Found"),
+            DeployError::EnvFileMissing(path) => write!(f, "Env file not found: {}", path),
+            DeployError::ComposeFileMissing(path) => write!(f, "No compose file found at {}", path),
+            DeployError::ComposeDownload(_) => write!(f, "Failed to download the compose file"),
+            DeployError::GitPull(_) => write!(f, "Git Pull Failed"),
+            DeployError::GitClone(_) => write!(f, "Initial git clone failed"),
+            DeployError::GitTag(_) => write!(f, "Git tag checkout failed"),
+            DeployError::GitStep(n, _) => write!(f, "Git step {} failed", n),
+            DeployError::SubmoduleUpdate(_) => write!(f, "Git pull success, but submodule update failed"),
+            DeployError::DockerLogin(_) => write!(f, "Docker Login Failed"),
+            DeployError::ComposeInvalid(_) => write!(f, "Compose file is invalid"),
+            DeployError::ComposeBuild(_, services) => {
+                write!(f, "Git pull success, but Compose build/up failed{}", failed_suffix(services))
+            }
+            DeployError::ComposePull(_, services) => write!(f, "Docker Compose pull/up failed{}", failed_suffix(services)),
+            DeployError::ComposeUp(attempts, _, services) => write!(
+                f,
+                "Build succeeded, but Compose up failed after {} attempt(s){}",
+                attempts,
+                failed_suffix(services)
+            ),
+            DeployError::ComposeRestart(_) => write!(f, "Docker Compose restart failed"),
+            DeployError::ComposeStop(_) => write!(f, "Docker Compose stop/down failed"),
+            DeployError::PostUp(n, _) => write!(f, "Post-deploy command {} failed", n),
+            DeployError::PreDeployHook(_) => write!(f, "Global pre-deploy hook failed"),
+            DeployError::Unhealthy(secs, _) => write!(f, "Health command did not pass within {}s", secs),
+            DeployError::SignatureInvalid(image, _) => write!(f, "Image signature verification failed for {}", image),
+            DeployError::OutputRejected(reason, _) => write!(f, "Compose exited 0, but its {}", reason),
+            DeployError::ReleaseSwitch(_) => write!(f, "Switching to the new release failed"),
+            DeployError::RollbackUnavailable(reason) => write!(f, "Cannot roll back: {}", reason),
+            DeployError::NotFastForward(deployed, fetched) => write!(
+                f,
+                "Refusing non-fast-forward deploy: {} does not descend from the deployed {}",
+                short_sha(fetched),
+                short_sha(deployed)
+            ),
+            DeployError::NotWritable(path, reason) => write!(f, "Project directory is not writable ({}): {}", reason, path),
+            DeployError::Restored(e, true) => write!(f, "{}; the previous version was restored", e),
+            DeployError::Restored(e, false) => write!(f, "{}; the previous version could not be restored", e),
+            DeployError::ProjectBusy(secs) => {
+                write!(f, "Project busy: another deploy still held its lock after {}s; try later", secs)
+            }
+            DeployError::Spawn(_) => write!(f, "Command execution error"),
+            DeployError::Panicked(_) => write!(f, "Deploy Panicked"),
+            DeployError::Cancelled => write!(f, "Deploy Cancelled"),
+            DeployError::TimedOut(secs) => write!(f, "Deploy timed out after {}s", secs),
+            DeployError::InvalidMode(_) => write!(f, "Invalid mode"),
+        }
+    }
+}
+
+impl DeployError {
+    // Tail of the failing command's stderr, when there was one
+    pub fn stderr_tail(&self) -> Option<&str> {
+        match self {
+            DeployError::Restored(e, _) => e.stderr_tail(),
+            DeployError::GitPull(s)
+            | DeployError::GitClone(s)
+            | DeployError::GitTag(s)
+            | DeployError::GitStep(_, s)
+            | DeployError::SubmoduleUpdate(s)
+            | DeployError::DockerLogin(s)
+            | DeployError::ComposeInvalid(s)
+            | DeployError::ComposeBuild(s, _)
+            | DeployError::ComposePull(s, _)
+            | DeployError::ComposeUp(_, s, _)
+            | DeployError::ComposeRestart(s)
+            | DeployError::ComposeStop(s)
+            | DeployError::PostUp(_, s)
+            | DeployError::PreDeployHook(s)
+            | DeployError::Unhealthy(_, s)
+            | DeployError::SignatureInvalid(_, s)
+            | DeployError::OutputRejected(_, s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Stable machine-readable identifier, returned to clients as `code`
+    pub fn code(&self) -> &'static str {
+        match self {
+            DeployError::MissingCredentials(_) => "MISSING_CREDENTIALS",
+            DeployError::MissingRegistry => "MISSING_REGISTRY",
+            DeployError::CredentialProvider(_) => "CREDENTIAL_PROVIDER_FAILED",
+            DeployError::BinaryNotFound(_) => "BINARY_NOT_FOUND",
+            DeployError::EnvFileMissing(_) => "ENV_FILE_MISSING",
+            DeployError::ComposeFileMissing(_) => "COMPOSE_FILE_MISSING",
+            DeployError::ComposeDownload(_) => "COMPOSE_DOWNLOAD_FAILED",
+            DeployError::GitPull(_) => "GIT_PULL_FAILED",
+            DeployError::GitClone(_) => "GIT_CLONE_FAILED",
+            DeployError::GitTag(_) => "GIT_TAG_FAILED",
+            DeployError::GitStep(..) => "GIT_STEP_FAILED",
+            DeployError::SubmoduleUpdate(_) => "SUBMODULE_UPDATE_FAILED",
+            DeployError::DockerLogin(_) => "DOCKER_LOGIN_FAILED",
+            DeployError::ComposeInvalid(_) => "COMPOSE_INVALID",
+            DeployError::ComposeBuild(..) | DeployError::ComposePull(..) => "COMPOSE_FAILED",
+            DeployError::ComposeUp(..) => "COMPOSE_UP_FAILED",
+            DeployError::ComposeRestart(_) => "COMPOSE_RESTART_FAILED",
+            DeployError::ComposeStop(_) => "COMPOSE_STOP_FAILED",
+            DeployError::PostUp(..) => "POST_UP_FAILED",
+            DeployError::PreDeployHook(_) => "PRE_DEPLOY_HOOK_FAILED",
+            DeployError::Unhealthy(..) => "HEALTH_CHECK_FAILED",
+            DeployError::SignatureInvalid(..) => "IMAGE_SIGNATURE_INVALID",
+            DeployError::OutputRejected(..) => "OUTPUT_REJECTED",
+            DeployError::ReleaseSwitch(_) => "RELEASE_SWITCH_FAILED",
+            DeployError::RollbackUnavailable(_) => "ROLLBACK_UNAVAILABLE",
+            DeployError::NotFastForward(..) => "NOT_FAST_FORWARD",
+            DeployError::NotWritable(..) => "PROJECT_DIR_NOT_WRITABLE",
+            // The failure itself; the response's `restored` tells what state it left behind
+            DeployError::Restored(e, _) => e.code(),
+            DeployError::ProjectBusy(_) => "PROJECT_BUSY",
+            DeployError::Spawn(_) => "SPAWN_FAILED",
+            DeployError::Panicked(_) => "DEPLOY_PANICKED",
+            DeployError::Cancelled => "DEPLOY_CANCELLED",
+            DeployError::TimedOut(_) => "DEPLOY_TIMED_OUT",
+            DeployError::InvalidMode(_) => "INVALID_MODE",
+        }
+    }
+
+    // restore_on_failure deploys: whether the previous version is running again
+    pub fn restored(&self) -> Option<bool> {
+        match self {
+            DeployError::Restored(_, restored) => Some(*restored),
+            _ => None,
+        }
+    }
+
+    // Services compose failed to build or start; empty when unknown or not a compose failure
+    pub fn failed_services(&self) -> &[String] {
+        match self {
+            DeployError::Restored(e, _) => e.failed_services(),
+            DeployError::ComposeBuild(_, services)
+            | DeployError::ComposePull(_, services)
+            | DeployError::ComposeUp(_, _, services) => services,
+            _ => &[],
+        }
+    }
+
+    // Underlying cause that isn't command output (spawn error, panic message, bad mode)
+    pub fn cause(&self) -> Option<&str> {
+        match self {
+            DeployError::Restored(e, _) => e.cause(),
+            DeployError::Spawn(s)
+            | DeployError::CredentialProvider(s)
+            | DeployError::ComposeDownload(s)
+            | DeployError::ReleaseSwitch(s)
+            | DeployError::Panicked(s)
+            | DeployError::InvalidMode(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+// Phase durations for repo deploys, also exported as histograms on /metrics
+#[derive(Serialize, Debug, Clone)]
+pub struct PhaseTimings {
+    pub git_seconds: f64,
+    pub build_seconds: f64,
+}
+
+// One container as reported by `docker compose ps` after the deploy
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ServiceState {
+    pub service: String,
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<String>,
+}
+
+// The commit a repo deploy left checked out, from `git log -1`
+#[derive(Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CommitInfo {
+    pub sha: String,
+    pub author: String,
+    pub subject: String,
+    // Committer date, unix seconds
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeployReport {
+    pub message: &'static str,
+    pub timings: Option<PhaseTimings>,
+    // Repo mode only; None when git couldn't describe HEAD
+    pub commit: Option<CommitInfo>,
+    // None when compose couldn't report container state (e.g. no `ps --format json`)
+    pub services: Option<Vec<ServiceState>>,
+    // Repo mode with build_login, and login mode: the registry logged into
+    pub registry_login: Option<String>,
+    // Login mode: the user it logged in as
+    pub login_user: Option<String>,
+    // When the project has a smoke_test_command; a failed one doesn't fail the deploy
+    pub smoke_test: Option<SmokeTestResult>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SmokeTestResult {
+    pub passed: bool,
+    // Tail of stdout and stderr, or why the command didn't finish
+    pub output: String,
+}
+
+pub type DeployOutcome = Result<DeployReport, DeployError>;
+
+const DEFAULT_OUTPUT_TAIL_LINES: usize = 50;
+
+// OUTPUT_TAIL_LINES caps how much command output is kept for responses and status
+fn output_tail_lines() -> usize {
+    env::var("OUTPUT_TAIL_LINES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_OUTPUT_TAIL_LINES)
+}
+
+// Keeps the last few lines of command output for status reporting; full output goes to the logs
+fn output_tail(output: &str) -> String {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    let start = lines.len().saturating_sub(output_tail_lines());
+    lines[start..].join("\n")
+}
+
+// Single-quotes a value for `sh -c`
+pub(crate) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// `sudo -n` never prompts, so a missing sudoers rule fails the deploy instead of hanging it
+pub(crate) fn docker_bin(use_sudo: bool) -> &'static str {
+    if use_sudo {
+        "sudo -n docker"
+    } else {
+        "docker"
+    }
+}
+
+// Builds the "docker compose" prefix including --env-file and any " --profile <name>" flags.
+// `--ansi never` keeps color codes out of captured output shown in responses.
+fn compose_cmd(compose: &ComposeOptions) -> String {
+    let mut cmd = priority_prefix(compose, tool_on_path);
+    cmd.push_str(&format!("{} compose --ansi never", docker_bin(compose.use_sudo)));
+    if let Some(env_file) = &compose.env_file {
+        cmd.push_str(" --env-file ");
+        cmd.push_str(&shell_quote(env_file));
+    }
+    if let Some(name) = &compose.project_name {
+        cmd.push_str(" -p ");
+        cmd.push_str(name);
+    }
+    if let Some(dir) = &compose.project_directory {
+        cmd.push_str(" --project-directory ");
+        cmd.push_str(&shell_quote(dir));
+    } else if !compose.files.is_empty() {
+        // Relative paths in the file still resolve against the project dir we `cd` into
+        cmd.push_str(" --project-directory .");
+    }
+    for file in &compose.files {
+        cmd.push_str(" -f ");
+        cmd.push_str(&shell_quote(file));
+    }
+    for profile in &compose.profiles {
+        cmd.push_str(" --profile ");
+        cmd.push_str(profile);
+    }
+    cmd
+}
+
+// "nice -n 10 ionice -c 3 " for a project that asks for it, leaving out a tool the host lacks.
+// The compose client and what it spawns inherit the priority; work done inside the Docker
+// daemon itself (BuildKit builds included) does not.
+fn priority_prefix(compose: &ComposeOptions, available: impl Fn(&str) -> bool) -> String {
+    let mut prefix = String::new();
+    if let Some(level) = compose.nice.filter(|_| available("nice")) {
+        prefix.push_str(&format!("nice -n {} ", level));
+    }
+    if let Some(class) = compose.ionice_class.filter(|_| available("ionice")) {
+        prefix.push_str(&format!("ionice -c {} ", class));
+    }
+    prefix
+}
+
+// Looked up once; a missing tool is warned about then and skipped from then on
+fn tool_on_path(tool: &str) -> bool {
+    static FOUND: std::sync::OnceLock<BTreeMap<&'static str, bool>> = std::sync::OnceLock::new();
+    let found = FOUND.get_or_init(|| {
+        let path = env::var_os("PATH").unwrap_or_default();
+        ["nice", "ionice"]
+            .into_iter()
+            .map(|name| {
+                let present = env::split_paths(&path).any(|dir| dir.join(name).is_file());
+                if !present {
+                    warn!("⚠️ {} is not on PATH; compose commands run without it", name);
+                }
+                (name, present)
+            })
+            .collect()
+    });
+    found.get(tool).copied().unwrap_or(false)
+}
+
+// Extra flags appended to "up", e.g. " --timeout 30 --remove-orphans --scale worker=3"
+fn up_flags(compose: &ComposeOptions) -> String {
+    let mut flags = compose
+        .stop_timeout
+        .map(|secs| format!(" --timeout {}", secs))
+        .unwrap_or_default();
+    if compose.remove_orphans {
+        flags.push_str(" --remove-orphans");
+    }
+    if compose.force_recreate {
+        flags.push_str(" --force-recreate");
+    }
+    for (service, count) in &compose.scale {
+        flags.push_str(&format!(" --scale {}={}", service, count));
+    }
+    flags.push_str(&wait_flags(compose));
+    flags
+}
+
+// Last in up_flags, so compose_sh can strip them again
+fn wait_flags(compose: &ComposeOptions) -> String {
+    match compose.wait {
+        None => String::new(),
+        Some(None) => " --wait".to_string(),
+        Some(Some(secs)) => format!(" --wait --wait-timeout {}", secs),
+    }
+}
+
+// Runs a compose script; an `up --wait` that compose rejects as an unknown flag is run again
+// without it, so projects keep deploying on hosts with an older compose
+async fn compose_sh(exec: &Exec, compose: &ComposeOptions, script: &str) -> std::io::Result<Output> {
+    let out = exec.sh(script).await?;
+    let wait = wait_flags(compose);
+    let unsupported = |stderr: &[u8]| {
+        let stderr = String::from_utf8_lossy(stderr);
+        stderr.contains("unknown flag: --wait") || stderr.contains("no such option: --wait")
+    };
+    if wait.is_empty() || out.status.success() || !script.contains(&wait) || !unsupported(&out.stderr) {
+        return Ok(out);
+    }
+    warn!("⚠️ This docker compose does not support --wait; running up without it");
+    exec.sh(&script.replace(&wait, "")).await
+}
+
+// The names `docker compose` looks for when no -f / COMPOSE_FILE is given
+const COMPOSE_FILE_NAMES: [&str; 4] = ["compose.yaml", "compose.yml", "docker-compose.yaml", "docker-compose.yml"];
+
+// Compose's own "no configuration file provided" is easy to misread, so look first. Skipped when
+// the directory is missing (`cd` already says so), for `command` overrides, and when COMPOSE_FILE
+// may come from the project's .env, which only compose knows how to read.
+fn check_compose_file(compose_path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    let dir = std::path::Path::new(compose_path);
+    if !dir.is_dir() || compose.command.is_some() || !compose.files.is_empty() {
+        return Ok(());
+    }
+    let found = match env::var("COMPOSE_FILE").ok().filter(|f| !f.is_empty()) {
+        Some(files) => files.split(':').all(|file| dir.join(file).is_file()),
+        None => {
+            COMPOSE_FILE_NAMES.iter().any(|name| dir.join(name).is_file())
+                || std::fs::read_to_string(dir.join(".env")).is_ok_and(|env| env.contains("COMPOSE_FILE="))
+        }
+    };
+    if found {
+        return Ok(());
+    }
+    error!("❌ No compose file found in {}", compose_path);
+    Err(DeployError::ComposeFileMissing(compose_path.to_string()))
+}
+
+// A missing --env-file makes compose fail with a terse error; check it up front
+fn check_env_file(compose_path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    if let Some(env_file) = &compose.env_file {
+        let full = std::path::Path::new(compose_path).join(env_file);
+        if !full.is_file() {
+            error!("❌ Env file not found: {}", full.display());
+            return Err(DeployError::EnvFileMissing(full.display().to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_compose_options(compose: &ComposeOptions) -> Result<(), &'static str> {
+    if let Some(bad) = compose.profiles.iter().find(|p| !is_valid_compose_name(p)) {
+        error!("Invalid compose profile: {}", bad);
+        return Err("Invalid compose profile");
+    }
+    for (service, count) in &compose.scale {
+        if !is_valid_compose_name(service) {
+            error!("Invalid service name in scale: {}", service);
+            return Err("Invalid scale service name");
+        }
+        if *count < MIN_SCALE {
+            error!("Invalid scale for {}: count must be a positive integer", service);
+            return Err("Invalid scale count");
+        }
+    }
+    Ok(())
+}
+
+// Compose prints a JSON array (older v2) or one object per line (2.21+); anything else yields None
+fn parse_compose_ps(stdout: &str) -> Option<Vec<ServiceState>> {
+    let stdout = stdout.trim();
+    let containers: Vec<serde_json::Value> = if stdout.starts_with('[') {
+        serde_json::from_str(stdout).ok()?
+    } else {
+        stdout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .ok()?
+    };
+    containers
+        .iter()
+        .map(|c| {
+            Some(ServiceState {
+                service: c.get("Service")?.as_str()?.to_string(),
+                state: c.get("State")?.as_str()?.to_string(),
+                health: c
+                    .get("Health")
+                    .and_then(|h| h.as_str())
+                    .filter(|h| !h.is_empty())
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+fn failed_suffix(services: &[String]) -> String {
+    match services.len() {
+        0 => String::new(),
+        1 => format!(" (service {})", services[0]),
+        _ => format!(" (services {})", services.join(", ")),
+    }
+}
+
+// Compose names the culprit as `service "web"` in most up errors and as `target web:` for build errors
+fn services_in_output(output: &str) -> Vec<String> {
+    let mut services = Vec::new();
+    for (marker, end) in [("service \"", '"'), ("target ", ':')] {
+        let mut rest = output;
+        while let Some(start) = rest.find(marker) {
+            rest = &rest[start + marker.len()..];
+            let name = &rest[..rest.find(end).unwrap_or(0)];
+            if is_valid_compose_name(name) && !services.iter().any(|s| s == name) {
+                services.push(name.to_string());
+            }
+        }
+    }
+    services
+}
+
+// After a failed `up`: the services named in compose's output, else whichever containers
+// `ps --all` shows as not running or unhealthy. Best effort, empty when nothing stands out.
+async fn failed_services(exec: &Exec, path: &str, compose: &ComposeOptions, output: &str) -> Vec<String> {
+    let named = services_in_output(output);
+    if !named.is_empty() {
+        return named;
+    }
+    let listed = match exec.sh(&format!("cd {} && {} ps --all --format json", path, compose_cmd(compose))).await {
+        Ok(out) if out.status.success() => parse_compose_ps(&String::from_utf8_lossy(&out.stdout)),
+        _ => None,
+    };
+    let mut services: Vec<String> = Vec::new();
+    for state in listed.unwrap_or_default() {
+        let broken = state.state != "running" || state.health.as_deref() == Some("unhealthy");
+        if broken && !services.contains(&state.service) {
+            services.push(state.service);
+        }
+    }
+    services
+}
+
+// Best effort: a failure here only drops the detail from the response
+async fn compose_services(exec: &Exec, path: &str, compose: &ComposeOptions) -> Option<Vec<ServiceState>> {
+    match exec.sh(&format!("cd {} && {} ps --format json", path, compose_cmd(compose))).await {
+        Ok(out) if out.status.success() => parse_compose_ps(&String::from_utf8_lossy(&out.stdout)),
+        Ok(out) => {
+            debug!(
+                "docker compose ps --format json failed in {}: {}",
+                path,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            debug!("Failed to spawn docker compose ps in {}: {}", path, e);
+            None
+        }
+    }
+}
+
+// `sh` exits with 127 when the program it was asked to run does not exist
+fn is_command_not_found(status: &std::process::ExitStatus) -> bool {
+    status.code() == Some(127)
+}
+
+// DOCKER_HOST wins; otherwise CONTAINER_SOCK (e.g. rootless Podman's
+// $XDG_RUNTIME_DIR/podman/podman.sock) becomes a unix:// DOCKER_HOST
+fn engine_host(docker_host: Option<&str>, container_sock: Option<&str>) -> Option<String> {
+    let set = |v: Option<&str>| v.map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+    set(docker_host).or_else(|| {
+        set(container_sock).map(|sock| if sock.contains("://") { sock } else { format!("unix://{}", sock) })
+    })
+}
+
+// Points every docker/compose child process at the configured engine socket and checks that
+// something is listening there. Returns the engine address, for the startup banner. Sets
+// DOCKER_HOST process-wide, so it must run before any other thread (the tokio runtime) starts.
+pub fn configure_engine_socket() -> Option<String> {
+    let docker_host = env::var("DOCKER_HOST").ok();
+    let container_sock = env::var("CONTAINER_SOCK").ok();
+    let host = engine_host(docker_host.as_deref(), container_sock.as_deref())?;
+    if docker_host.as_deref().map(str::trim).is_none_or(str::is_empty) {
+        // Children inherit the environment, so this covers docker, compose and cosign alike
+        env::set_var("DOCKER_HOST", &host);
+    } else if container_sock.is_some_and(|s| !s.trim().is_empty()) {
+        warn!("⚠️ Both DOCKER_HOST and CONTAINER_SOCK are set; using DOCKER_HOST={}", host);
+    }
+    if let Some(path) = host.strip_prefix("unix://") {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => info!("🔌 Container engine socket {} is reachable", path),
+            Err(e) => warn!("⚠️ Cannot connect to container engine socket {}: {}; deploys will fail", path, e),
+        }
+    }
+    Some(host)
+}
+
+// ssh_host: docker and compose (and cosign) use the remote engine. git and the compose files stay
+// on this host; compose reads the checkout here and streams the build context to the engine.
+// docker starts `ssh` itself with no way to pass flags or a config file (-F), so an ssh_key is
+// applied through an `ssh` wrapper put first on the deploy's PATH. It lives in the deploy's own
+// private dir (Exec::private_dir), never a shared path another local user could plant first.
+fn use_ssh_host(exec: &Exec, project: &str, host: &str, key: Option<&str>) -> Result<(), DeployError> {
+    info!("🔗 {}: docker runs on {}; git and the checkout stay on this host", project, host);
+    exec.set_env("DOCKER_HOST", &format!("ssh://{}", host));
+    let Some(key) = key else {
+        return Ok(());
+    };
+    let path = env::var("PATH").unwrap_or_default();
+    let dir = if exec.dry_run {
+        env::temp_dir().join("graft-hook-ssh-XXXXXX")
+    } else {
+        let wrapper = format!(
+            "#!/bin/sh\nPATH={} exec ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes \"$@\"\n",
+            shell_quote(&path),
+            shell_quote(key)
+        );
+        let written = exec.private_dir("ssh").and_then(|dir| {
+            create_private_file(&dir.join("ssh"), wrapper.as_bytes(), 0o700)?;
+            Ok(dir)
+        });
+        match written {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("❌ Failed to write the ssh wrapper for {}: {}", project, e);
+                return Err(DeployError::Spawn(format!("writing the ssh wrapper: {}", e)));
+            }
+        }
+    };
+    exec.set_env("PATH", &format!("{}:{}", dir.display(), path));
+    Ok(())
+}
+
+// Startup dependency check: every deploy mode needs docker, repo mode also needs git.
+// Returns the version line of each one found, for the startup banner.
+pub async fn check_binaries() -> BTreeMap<&'static str, String> {
+    let mut found = BTreeMap::new();
+    let required = [
+        ("docker", "all deploy modes"),
+        ("git", "repo mode and build error checks"),
+    ];
+
+    for (binary, needed_for) in required {
+        match Command::new(binary).arg("--version").output().await {
+            Ok(out) if out.status.success() => {
+                let version = String::from_utf8_lossy(&out.stdout).trim().to_string();
+                debug!("{} found: {}", binary, version);
+                found.insert(binary, version);
+            }
+            Ok(out) => {
+                warn!(
+                    "⚠️ '{} --version' exited with {}; {} may fail",
+                    binary, out.status, needed_for
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("⚠️ '{}' binary not found in PATH; {} will fail", binary, needed_for);
+            }
+            Err(e) => {
+                warn!("⚠️ Could not run '{}': {}; {} may fail", binary, e, needed_for);
+            }
+        }
+    }
+    found
+}
+
+// Everything needed to run (or re-run) a single deploy
+#[derive(Debug, Clone)]
+pub struct DeployJob {
+    // Config key, or the sanitized custom path for ad-hoc deploys
+    pub project_id: String,
+    pub config_key: Option<String>,
+    // Where compose runs (and backups are taken from)
+    pub path: String,
+    // Where git operations run; same as `path` unless configured separately
+    pub repo_path: String,
+    pub mode: String,
+    pub rollback_limit: u32,
+    pub compose: ComposeOptions,
+    pub git: GitOptions,
+    // Image mode credentials by registry, from the project config
+    pub registries: BTreeMap<String, RegistryCredentials>,
+    // credential_provider: replaces the environment as the fallback for a token missing from the payload
+    pub credentials: Option<CredentialSource>,
+    // The project's own git_credentials / docker_credentials, tried before the environment
+    pub git_credentials: Option<RegistryCredentials>,
+    pub docker_credentials: Option<RegistryCredentials>,
+    // JSON deploys carry their own credentials; signed query deploys use the environment
+    pub payload: Option<WebhookPayload>,
+    // Include the executed commands in the response (?verbose=true)
+    pub verbose: bool,
+    // Where the request came from: the payload's `source`, X-Deploy-Source, else the endpoint kind
+    pub source: String,
+    // clean_env: server variables passed through on top of CLEAN_ENV_VARS
+    pub clean_env: Option<Vec<String>>,
+    // mask_patterns: scrubbed from captured output, see Exec::add_mask
+    pub mask_patterns: Vec<regex::Regex>,
+    // Set for `Accept: application/x-ndjson` requests, which stream each phase as it starts and ends
+    pub progress: Option<Progress>,
+}
+
+// {"phase": ..., "status": "started" | "done"} events of a running deploy
+pub type Progress = tokio::sync::mpsc::UnboundedSender<serde_json::Value>;
+
+impl DeployJob {
+    // A deploy of a configured project using only its config defaults
+    pub fn from_entry(name: &str, entry: &ProjectEntry, mode: &str) -> Self {
+        DeployJob {
+            project_id: name.to_string(),
+            config_key: Some(name.to_string()),
+            path: entry.compose_path().to_string(),
+            repo_path: entry.repo_path().to_string(),
+            mode: mode.to_string(),
+            rollback_limit: entry.rollback_backups(),
+            compose: ComposeOptions {
+                profiles: entry.compose_profiles().to_vec(),
+                files: entry.compose_files().to_vec(),
+                project_directory: entry.project_directory().map(str::to_string),
+                env_file: entry.env_file().map(str::to_string),
+                use_sudo: entry.use_sudo(),
+                validate: entry.validate_compose(),
+                pull: entry.pull_before_build(),
+                build_login: entry.build_login(),
+                parallel_login: entry.parallel_login(),
+                build_parallelism: entry.build_parallelism().or_else(default_build_parallelism),
+                nice: entry.nice().or_else(default_nice),
+                ionice_class: entry.ionice_class().or_else(default_ionice_class),
+                up_retries: compose_up_retries(),
+                stop_timeout: entry.stop_timeout(),
+                wait: entry.compose_wait(),
+                command: entry.command().map(<[String]>::to_vec),
+                recreate_on_restart: entry.restart_recreate(),
+                post_up: entry.post_up_exec().to_vec(),
+                post_up_rollback: entry.post_up_rollback(),
+                restore_on_failure: entry.restore_on_failure(),
+                health: entry.health_check(),
+                smoke_test: entry.smoke_test(),
+                verify_images: entry.image_policy(),
+                remote_file: entry.remote_compose_file(),
+                output_patterns: entry.output_patterns(),
+                ssh_host: entry.ssh_host().map(str::to_string),
+                ssh_key: entry.ssh_key().map(str::to_string),
+                ..Default::default()
+            },
+            git: entry.git_options(),
+            registries: entry.registries(),
+            credentials: entry.credential_provider().cloned(),
+            git_credentials: entry.git_credentials().cloned(),
+            docker_credentials: entry.docker_credentials().cloned(),
+            payload: None,
+            verbose: false,
+            source: "api".to_string(),
+            clean_env: entry.clean_env(),
+            mask_patterns: entry.mask_patterns(),
+            progress: None,
+        }
+    }
+
+    // A PR preview: its own worktree (pr_checkout), compose project and runtime slot, so previews
+    // of several PRs coexist with each other and with the main deploy. No backups or rollback.
+    pub fn for_pull_request(mut self, pr: u32) -> Self {
+        let checkout = pr_checkout(&self.repo_path, pr);
+        self.path = match std::path::Path::new(&self.path).strip_prefix(&self.repo_path) {
+            Ok(sub) if !sub.as_os_str().is_empty() => format!("{}/{}", checkout, sub.display()),
+            _ => checkout,
+        };
+        self.project_id = format!("{}-pr-{}", self.project_id, pr);
+        self.compose.project_name = Some(
+            self.project_id
+                .to_ascii_lowercase()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+                .collect(),
+        );
+        self.compose.post_up_rollback = false;
+        self.compose.restore_on_failure = false;
+        self.rollback_limit = 0;
+        self.git.atomic_worktree = false;
+        self.git.pr_number = Some(pr);
+        self
+    }
+
+    // Where the deployed commit is checked out: the PR worktree, the release symlink, or the repo
+    pub(crate) fn checkout(&self) -> String {
+        match self.git.pr_number {
+            Some(pr) => pr_checkout(&self.repo_path, pr),
+            None if self.git.atomic_worktree => self.path.clone(),
+            None => self.repo_path.clone(),
+        }
+    }
+}
+
+// Worktree a PR preview is checked out in, beside the repo like atomic_worktree releases
+pub(crate) fn pr_checkout(repo_path: &str, pr: u32) -> String {
+    format!("{}.previews/pr-{}", repo_path.trim_end_matches('/'), pr)
+}
+
+// Resolves credentials for the job and runs the matching deploy on a guarded task
+pub async fn run_job(job: &DeployJob, metrics: Arc<Metrics>, exec: Arc<Exec>, timeout: Option<Duration>) -> DeployOutcome {
+    set_deploy_env(&exec, job);
+    if let Some(passthrough) = &job.clean_env {
+        exec.set_clean_env(passthrough);
+    }
+    for value in mask_env_values() {
+        exec.add_secret(&value);
+    }
+    for pattern in &job.mask_patterns {
+        exec.add_mask(pattern.clone());
+    }
+    if let Some(host) = &job.compose.ssh_host {
+        use_ssh_host(&exec, &job.project_id, host, job.compose.ssh_key.as_deref())?;
+    }
+
+    if let Some(script) = global_hook("GLOBAL_PRE_DEPLOY") {
+        if let Err(stderr) = run_global_hook(&exec, "pre-deploy", &script).await {
+            return Err(DeployError::PreDeployHook(stderr));
+        }
+    }
+    let result = run_mode(job, metrics, exec.clone(), timeout).await;
+    if let Some(script) = global_hook("GLOBAL_POST_DEPLOY") {
+        exec.set_env("GRAFT_RESULT", if result.is_ok() { "success" } else { "failure" });
+        // The deploy is over either way; a failing post hook only gets logged
+        let _ = run_global_hook(&exec, "post-deploy", &script).await;
+    }
+    result
+}
+
+// MASK_ENV_VARS: comma-separated server variables (e.g. SENTRY_DSN) whose values are scrubbed from
+// every deploy's commands and output
+fn mask_env_values() -> Vec<String> {
+    env::var("MASK_ENV_VARS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|name| env::var(name.trim()).ok())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+// GLOBAL_PRE_DEPLOY / GLOBAL_POST_DEPLOY: shell commands run around every deploy of every project
+fn global_hook(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|script| !script.trim().is_empty())
+}
+
+// Runs in the server's working directory (the project's may not exist yet) with the GRAFT_* context
+async fn run_global_hook(exec: &Exec, stage: &str, script: &str) -> Result<(), String> {
+    info!("Running global {} hook", stage);
+    match exec.sh(script).await {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Global {} hook exited with {}: {}", stage, out.status, stderr);
+            Err(output_tail(&stderr))
+        }
+        Err(e) => {
+            error!("Failed to spawn the global {} hook: {}", stage, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+async fn run_mode(job: &DeployJob, metrics: Arc<Metrics>, exec: Arc<Exec>, timeout: Option<Duration>) -> DeployOutcome {
+    let with_file;
+    let job = match &job.compose.remote_file {
+        Some(remote) => {
+            with_file = DeployJob {
+                compose: ComposeOptions {
+                    files: vec![download_compose_file(&exec, &job.project_id, remote).await?],
+                    ..job.compose.clone()
+                },
+                ..job.clone()
+            };
+            &with_file
+        }
+        None => job,
+    };
+    let with_override;
+    let job = match &job.compose.inline_override {
+        Some(yaml) => {
+            let mut files = base_compose_files(&job.path, &job.compose)?;
+            files.push(write_compose_override(&exec, &job.project_id, yaml)?);
+            with_override = DeployJob {
+                compose: ComposeOptions {
+                    files,
+                    validate: true,
+                    ..job.compose.clone()
+                },
+                ..job.clone()
+            };
+            &with_override
+        }
+        None => job,
+    };
+    match job.mode.as_str() {
+        "repo" | "pull" => {
+            if job.mode == "pull" {
+                info!("Mode selected: Git Pull only");
+            } else {
+                info!("Mode selected: Git Pull & Compose Build");
+            }
+            let creds = match provided_credentials(job, "Git").await {
+                Some(creds) => creds?,
+                None => git_credentials(job.payload.as_ref(), job.git_credentials.as_ref())?,
+            };
+            exec.add_secret(&creds.token);
+            let login = if job.mode == "repo" && job.compose.build_login {
+                build_login_credentials(job)
+            } else {
+                None
+            };
+            if let Some((_, docker)) = &login {
+                exec.add_secret(&docker.token);
+            }
+            let job = job.clone();
+            run_guarded(
+                async move {
+                    let login = login.as_ref().map(|(registry, docker)| (registry.as_str(), docker));
+                    let mut report = match login {
+                        Some(login) if job.compose.parallel_login => {
+                            deploy_git_with_login(&exec, &job, &creds, Some(login), &metrics).await?
+                        }
+                        Some((registry, docker)) => {
+                            docker_login(&exec, registry, docker, job.compose.use_sudo, docker_login_retries()).await?;
+                            deploy_git(&exec, &job, &creds, &metrics).await?
+                        }
+                        None => deploy_git(&exec, &job, &creds, &metrics).await?,
+                    };
+                    report.registry_login = login.map(|(registry, _)| registry.to_string());
+                    Ok(report)
+                },
+                timeout,
+            )
+            .await
+        }
+        "image" => {
+            info!("Mode selected: Docker Login & Compose Pull");
+            let (registry, creds) = match provided_credentials(job, "Docker").await {
+                Some(creds) => (target_registry(job.payload.as_ref())?, creds?),
+                None => docker_credentials(job.payload.as_ref(), &job.registries, job.docker_credentials.as_ref())?,
+            };
+            exec.add_secret(&creds.token);
+            let (path, compose) = (job.path.clone(), job.compose.clone());
+            run_guarded(
+                async move { deploy_docker(&exec, &path, &registry, &creds, &compose).await },
+                timeout,
+            )
+            .await
+        }
+        "restart" => {
+            info!("Mode selected: Compose Restart");
+            let (path, compose) = (job.path.clone(), job.compose.clone());
+            run_guarded(async move { deploy_restart(&exec, &path, &compose).await }, timeout).await
+        }
+        "wake" => {
+            info!("Mode selected: Compose Start or Up");
+            let (path, compose) = (job.path.clone(), job.compose.clone());
+            run_guarded(async move { deploy_wake(&exec, &path, &compose).await }, timeout).await
+        }
+        "stop" | "down" => {
+            info!("Mode selected: Compose {}", job.mode);
+            let (path, compose, down) = (job.path.clone(), job.compose.clone(), job.mode == "down");
+            run_guarded(async move { deploy_stop(&exec, &path, &compose, down).await }, timeout).await
+        }
+        "login" => {
+            info!("Mode selected: Docker Login Only");
+            let (registry, creds) = match provided_credentials(job, "Docker").await {
+                Some(creds) => (target_registry(job.payload.as_ref())?, creds?),
+                None => docker_credentials(job.payload.as_ref(), &job.registries, job.docker_credentials.as_ref())?,
+            };
+            exec.add_secret(&creds.token);
+            let use_sudo = job.compose.use_sudo;
+            run_guarded(async move { deploy_login(&exec, &registry, &creds, use_sudo).await }, timeout).await
+        }
+        "teardown" => {
+            info!("Mode selected: PR Preview Teardown");
+            let job = job.clone();
+            run_guarded(async move { deploy_teardown(&exec, &job).await }, timeout).await
+        }
+        "rollback" => {
+            info!("Mode selected: Roll Back {} Release(s)", job.git.rollback_steps);
+            let job = job.clone();
+            run_guarded(async move { deploy_rollback(&exec, &job).await }, timeout).await
+        }
+        _ => {
+            warn!("Invalid deployment mode: {}", job.mode);
+            Err(DeployError::InvalidMode(job.mode.clone()))
+        }
+    }
+}
+
+const COMPOSE_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Lowercase hex sha256 of the download must equal the configured one
+fn verify_sha256(body: &[u8], expected: &str) -> Result<(), String> {
+    use sha2::{Digest, Sha256};
+    let actual = hex::encode(Sha256::digest(body));
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("checksum mismatch: expected {}, got {}", expected, actual))
+    }
+}
+
+// The files compose would load on its own, which an explicit -f list would otherwise replace:
+// `files`, else COMPOSE_FILE, else the first default name plus its .override file
+fn base_compose_files(path: &str, compose: &ComposeOptions) -> Result<Vec<String>, DeployError> {
+    if !compose.files.is_empty() {
+        return Ok(compose.files.clone());
+    }
+    if let Some(files) = env::var("COMPOSE_FILE").ok().filter(|f| !f.is_empty()) {
+        return Ok(files.split(':').map(str::to_string).collect());
+    }
+    let dir = std::path::Path::new(path);
+    let Some(base) = COMPOSE_FILE_NAMES.iter().find(|name| dir.join(name).is_file()) else {
+        error!("❌ No compose file found in {} to apply the override to", path);
+        return Err(DeployError::ComposeFileMissing(path.to_string()));
+    };
+    let mut files = vec![base.to_string()];
+    let (stem, ext) = base.rsplit_once('.').expect("default names have an extension");
+    let override_file = format!("{}.override.{}", stem, ext);
+    if dir.join(&override_file).is_file() {
+        files.push(override_file);
+    }
+    Ok(files)
+}
+
+// A project id usable in a temp file name
+fn file_safe(project_id: &str) -> String {
+    project_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+// Creates `path` for writing by us alone: O_EXCL (never through a planted file or symlink) and `mode`
+fn create_private_file(path: &std::path::Path, contents: &[u8], mode: u32) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+    file.write_all(contents)
+}
+
+fn write_compose_override(exec: &Exec, project_id: &str, yaml: &str) -> Result<String, DeployError> {
+    let name = format!("{}.override.yml", file_safe(project_id));
+    if exec.dry_run {
+        return Ok(env::temp_dir().join("graft-hook-override-XXXXXX").join(name).display().to_string());
+    }
+    let written = exec.private_dir("override").and_then(|dir| {
+        let path = dir.join(&name);
+        create_private_file(&path, yaml.as_bytes(), 0o600).map(|_| path)
+    });
+    match written {
+        Ok(path) => {
+            info!("Compose override for {} written to {}", project_id, path.display());
+            Ok(path.display().to_string())
+        }
+        Err(e) => {
+            error!("❌ Writing the compose override for {} failed: {}", project_id, e);
+            Err(DeployError::Spawn(format!("writing the compose override: {}", e)))
+        }
+    }
+}
+
+// Fetches compose_url into a private temp dir of this deploy (see Exec::private_dir), so the
+// verified file is the one compose reads
+async fn download_compose_file(exec: &Exec, project_id: &str, remote: &RemoteComposeFile) -> Result<String, DeployError> {
+    let name = format!("{}.compose.yml", file_safe(project_id));
+    if exec.dry_run {
+        return Ok(env::temp_dir().join("graft-hook-compose-XXXXXX").join(name).display().to_string());
+    }
+
+    info!("Downloading compose file for {} from {}", project_id, remote.url);
+    let failed = |reason: String| {
+        error!("❌ Compose file download from {} failed: {}", remote.url, reason);
+        DeployError::ComposeDownload(reason)
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(COMPOSE_DOWNLOAD_TIMEOUT)
+        .user_agent(concat!("graft-hook/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| failed(e.to_string()))?;
+    let mut request = client.get(&remote.url);
+    if let Some(auth) = &remote.authorization {
+        request = request.header(reqwest::header::AUTHORIZATION, auth);
+    }
+    let response = request.send().await.map_err(|e| failed(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(failed(format!("server answered {}", response.status())));
+    }
+    let body = response.bytes().await.map_err(|e| failed(e.to_string()))?;
+    if let Some(expected) = &remote.sha256 {
+        verify_sha256(&body, expected).map_err(failed)?;
+    }
+
+    let path = exec
+        .private_dir("compose")
+        .map(|dir| dir.join(&name))
+        .map_err(|e| failed(format!("creating a temp dir: {}", e)))?;
+    create_private_file(&path, &body, 0o600).map_err(|e| failed(format!("writing {}: {}", path.display(), e)))?;
+    info!("✅ Compose file saved to {} ({} bytes)", path.display(), body.len());
+    Ok(path.display().to_string())
+}
+
+// Context for `command` overrides, post_up_exec steps, global hooks and compose interpolation (`${GRAFT_COMMIT}`).
+// Set on every command of the deploy; values that don't apply to the deploy are left unset.
+//   GRAFT_PROJECT  project id
+//   GRAFT_MODE     the deploy type: any of config::DEPLOY_TYPES (repo, pull, image, restart, wake,
+//                  stop, down, teardown, login), or rollback for API rollbacks
+//   GRAFT_BRANCH   repo mode: the branch pulled (first git step's, default main); unset for tag deploys
+//   GRAFT_TAG      repo mode: the tag being deployed
+//   GRAFT_PR       repo mode: the pull request a preview deploy builds (instead of GRAFT_BRANCH)
+//   GRAFT_COMMIT   repo mode: full sha checked out by the sync, set once it has run
+//   GRAFT_USER     the payload's user, if any
+//   TAG            image mode: the payload's image_tag, set by deploy_docker
+//   GRAFT_RESULT   GLOBAL_POST_DEPLOY only: success or failure
+fn set_deploy_env(exec: &Exec, job: &DeployJob) {
+    exec.set_env("GRAFT_PROJECT", &job.project_id);
+    exec.set_env("GRAFT_MODE", &job.mode);
+    if job.mode == "repo" {
+        match (&job.git.tag, job.git.pr_number) {
+            (_, Some(pr)) => exec.set_env("GRAFT_PR", &pr.to_string()),
+            (Some(tag), None) => exec.set_env("GRAFT_TAG", tag),
+            (None, None) => exec.set_env("GRAFT_BRANCH", job.git.steps.first().map_or("main", |step| step.branch.as_str())),
+        }
+    }
+    if let Some(user) = job.payload.as_ref().and_then(|p| p.user.as_deref()) {
+        exec.set_env("GRAFT_USER", user);
+    }
+}
+
+// Runs one deploy phase inside `span`, which is declared with empty duration_ms and outcome fields
+// and gets them filled in here, so the span's close shows how long the phase took and how it ended
+async fn traced<T>(span: Span, phase: impl Future<Output = Result<T, DeployError>>) -> Result<T, DeployError> {
+    let started = Instant::now();
+    let result = phase.instrument(span.clone()).await;
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    span.record("outcome", result.as_ref().map_or_else(DeployError::code, |_| "ok"));
+    result
+}
+
+// Runs a deploy on its own task so a panic fails only this request, not the server.
+// On timeout the task is aborted, which kills its running command (kill_on_drop).
+async fn run_guarded<F>(deploy: F, timeout: Option<Duration>) -> DeployOutcome
+where
+    F: std::future::Future<Output = DeployOutcome> + Send + 'static,
+{
+    // Keeps the deploy's phase spans under the request's
+    let mut handle = tokio::spawn(deploy.instrument(Span::current()));
+    let joined = match timeout {
+        Some(limit) => match tokio::time::timeout(limit, &mut handle).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                handle.abort();
+                error!("⏱️ Deploy exceeded {}s and was aborted", limit.as_secs());
+                return Err(DeployError::TimedOut(limit.as_secs()));
+            }
+        },
+        None => handle.await,
+    };
+
+    match joined {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            error!("💥 Deploy task panicked: {}", reason);
+            Err(DeployError::Panicked(reason))
+        }
+        Err(e) => {
+            error!("Deploy task was cancelled: {}", e);
+            Err(DeployError::Cancelled)
+        }
+    }
+}
+
+// Only use environment credentials if ACCESS_SECRET exists and is non-empty
+fn env_fallback(var: &str) -> Option<String> {
+    match env::var("ACCESS_SECRET") {
+        Ok(access_secret) if !access_secret.is_empty() => {
+            debug!("ACCESS_SECRET validated, checking environment for {}", var);
+            env::var(var).ok()
+        }
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub token: String,
+}
+
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = std::io::Result<Output>> + Send + 'a>>;
+
+// Abstraction over spawning processes so deploy logic can run against a fake in tests
+pub trait CommandRunner: Send + Sync {
+    // `dir` sets the working directory; None inherits ours. See CommandEnv for `env`.
+    fn run<'a>(
+        &'a self,
+        program: &'a str,
+        args: &'a [&'a str],
+        dir: Option<&'a str>,
+        env: &'a CommandEnv,
+    ) -> CommandFuture<'a>;
+}
+
+// Variables set for a command: on top of our environment, or with `clean` alone (plus the proxy vars)
+#[derive(Debug, Clone, Default)]
+pub struct CommandEnv {
+    pub vars: Vec<(String, String)>,
+    pub clean: bool,
+}
+
+// What a clean_env project's commands still get from ours, along with PROXY_VARS
+const CLEAN_ENV_VARS: [&str; 14] = [
+    "PATH",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "TMPDIR",
+    "SSH_AUTH_SOCK",
+    "DOCKER_HOST",
+    "DOCKER_CONFIG",
+    "DOCKER_CONTEXT",
+    "DOCKER_CERT_PATH",
+    "DOCKER_TLS_VERIFY",
+];
+
+pub struct SystemRunner;
+
+// Forwarded to clean_env commands too, so git and docker keep reaching the network through a proxy
+const PROXY_VARS: [&str; 8] = [
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "ALL_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "all_proxy",
+];
+
+fn proxy_env(lookup: impl Fn(&str) -> Option<String>) -> Vec<(&'static str, String)> {
+    PROXY_VARS
+        .into_iter()
+        .filter_map(|var| lookup(var).map(|value| (var, value)))
+        .collect()
+}
+
+// Each command leads its own process group so grandchildren (e.g. the `docker compose`
+// under `sh -c`) are killed with it when a timed-out or cancelled deploy drops the future
+impl CommandRunner for SystemRunner {
+    fn run<'a>(
+        &'a self,
+        program: &'a str,
+        args: &'a [&'a str],
+        dir: Option<&'a str>,
+        env: &'a CommandEnv,
+    ) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let mut command = Command::new(program);
+            if let Some(dir) = dir {
+                command.current_dir(dir);
+            }
+            if env.clean {
+                command.env_clear();
+            }
+            // No TERM: tools shouldn't think they're writing to a terminal
+            command.envs(env.vars.iter().cloned()).env_remove("TERM");
+            let child = command
+                .args(args)
+                .process_group(0)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let mut group = ProcessGroupGuard(child.id());
+            let output = child.wait_with_output().await;
+            group.0 = None;
+            output
+        })
+    }
+}
+
+// Kills the whole process group unless the command ran to completion
+struct ProcessGroupGuard(Option<u32>);
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            warn!("Killing process group {} of an unfinished command", pgid);
+            // SAFETY: killpg has no memory-safety preconditions; a stale pgid just yields ESRCH
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+// Runs the deploy's commands, keeping a redacted record of each for logs and verbose responses
+pub struct Exec {
+    runner: Arc<dyn CommandRunner>,
+    secrets: Mutex<Vec<String>>,
+    masks: Mutex<Vec<regex::Regex>>,
+    log: Mutex<Vec<String>>,
+    // GRAFT_* deploy context, see set_deploy_env
+    env: Mutex<Vec<(String, String)>>,
+    // clean_env: the passthrough variable names, see CommandEnv
+    clean_env: Mutex<Option<Vec<String>>>,
+    // Where clean_env reads our variables from; env::var outside tests
+    host_env: fn(&str) -> Option<String>,
+    // Planning: commands are recorded but not run, and deploys skip their filesystem changes
+    dry_run: bool,
+    // Lets compose_preflight skip `config -q` for compose inputs that already passed it
+    validations: Option<(String, Arc<ValidationCache>)>,
+    // Every command with its full stdout/stderr, for GET /logs; keeps the last DEPLOY_LOG_MAX_BYTES
+    transcript: Mutex<String>,
+    progress: Option<Progress>,
+    // Made by private_dir; removed when the deploy's Exec is dropped
+    temp_dirs: Mutex<Vec<std::path::PathBuf>>,
+}
+
+impl Drop for Exec {
+    fn drop(&mut self) {
+        for dir in self.temp_dirs.get_mut().unwrap().drain(..) {
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                warn!("⚠️ Failed to remove {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+const DEFAULT_DEPLOY_LOG_MAX_BYTES: usize = 1024 * 1024;
+
+fn deploy_log_max_bytes() -> usize {
+    env::var("DEPLOY_LOG_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_DEPLOY_LOG_MAX_BYTES)
+}
+
+// Fingerprint of the compose inputs that last passed `config -q`, by project
+pub type ValidationCache = Mutex<BTreeMap<String, String>>;
+
+// Answers every command with success and no output
+struct DryRunner;
+
+impl CommandRunner for DryRunner {
+    fn run<'a>(
+        &'a self,
+        _program: &'a str,
+        _args: &'a [&'a str],
+        _dir: Option<&'a str>,
+        _env: &'a CommandEnv,
+    ) -> CommandFuture<'a> {
+        Box::pin(async {
+            Ok(Output {
+                status: std::os::unix::process::ExitStatusExt::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        })
+    }
+}
+
+impl Exec {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Exec {
+            runner,
+            secrets: Mutex::new(Vec::new()),
+            masks: Mutex::new(Vec::new()),
+            log: Mutex::new(Vec::new()),
+            env: Mutex::new(Vec::new()),
+            clean_env: Mutex::new(None),
+            host_env: |name| env::var(name).ok(),
+            dry_run: false,
+            validations: None,
+            transcript: Mutex::new(String::new()),
+            progress: None,
+            temp_dirs: Mutex::new(Vec::new()),
+        }
+    }
+
+    // A fresh directory only we can use (mkdtemp: unique name, mode 0700), for files a deploy
+    // hands to compose or ssh; a shared, guessable path could be pre-created or swapped by any
+    // local user. Deleted with everything in it at the end of the deploy.
+    pub(crate) fn private_dir(&self, label: &str) -> std::io::Result<std::path::PathBuf> {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let template = env::temp_dir().join(format!("graft-hook-{}-XXXXXX", label));
+        let mut buf = std::ffi::CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(buf.as_mut_ptr().cast()) }.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        buf.pop();
+        let dir = std::path::PathBuf::from(std::ffi::OsString::from_vec(buf));
+        self.temp_dirs.lock().unwrap().push(dir.clone());
+        Ok(dir)
+    }
+
+    pub fn with_progress(mut self, progress: Option<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // The listener may be gone (client hung up); the deploy carries on regardless
+    pub(crate) fn phase(&self, phase: &str, status: &str) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.send(serde_json::json!({ "phase": phase, "status": status }));
+        }
+    }
+
+    pub fn with_validation_cache(mut self, project: &str, cache: Arc<ValidationCache>) -> Self {
+        self.validations = Some((project.to_string(), cache));
+        self
+    }
+
+    // For POST /plan: records what a deploy would run without running it
+    pub fn dry_run() -> Self {
+        let mut exec = Exec::new(Arc::new(DryRunner));
+        exec.dry_run = true;
+        exec
+    }
+
+    // Sets (or replaces) a variable for every later command of this deploy
+    pub fn set_env(&self, name: &str, value: &str) {
+        let mut env = self.env.lock().unwrap();
+        env.retain(|(n, _)| n != name);
+        env.push((name.to_string(), value.to_string()));
+    }
+
+    // A variable set with set_env, e.g. GRAFT_COMMIT once the sync has run
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        self.env.lock().unwrap().iter().find(|(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    // Later commands start from CLEAN_ENV_VARS, proxies and `passthrough` instead of our whole
+    // environment
+    pub fn set_clean_env(&self, passthrough: &[String]) {
+        *self.clean_env.lock().unwrap() = Some(passthrough.to_vec());
+    }
+
+    fn env(&self) -> CommandEnv {
+        let deploy_vars = self.env.lock().unwrap().clone();
+        match &*self.clean_env.lock().unwrap() {
+            None => CommandEnv { vars: deploy_vars, clean: false },
+            Some(passthrough) => {
+                let names = CLEAN_ENV_VARS.iter().copied().chain(passthrough.iter().map(String::as_str));
+                let mut vars: Vec<(String, String)> =
+                    proxy_env(self.host_env).into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+                vars.extend(names.filter_map(|name| (self.host_env)(name).map(|value| (name.to_string(), value))));
+                vars.extend(deploy_vars);
+                CommandEnv { vars, clean: true }
+            }
+        }
+    }
+
+    pub fn add_secret(&self, secret: &str) {
+        if !secret.is_empty() {
+            self.secrets.lock().unwrap().push(secret.to_string());
+        }
+    }
+
+    // Matches are redacted like secrets, from commands and from their captured output
+    pub fn add_mask(&self, pattern: regex::Regex) {
+        self.masks.lock().unwrap().push(pattern);
+    }
+
+    fn redact(&self, command: &str) -> String {
+        let redacted = self
+            .secrets
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(command.to_string(), |cmd, secret| cmd.replace(secret.as_str(), "***"));
+        self.masks
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(redacted, |text, mask| mask.replace_all(&text, "***").into_owned())
+    }
+
+    // Output is only rewritten when something was redacted, so bytes that aren't UTF-8 survive
+    fn scrub(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let text = String::from_utf8_lossy(&bytes);
+        let scrubbed = self.redact(&text);
+        if scrubbed == text {
+            bytes
+        } else {
+            scrubbed.into_bytes()
+        }
+    }
+
+    fn record(&self, command: &str) {
+        let command = self.redact(command);
+        debug!(command = %command, "Executing command");
+        self.transcript.lock().unwrap().push_str(&format!("$ {}\n", command));
+        self.log.lock().unwrap().push(command);
+    }
+
+    // Callers log and report what this returns, so secrets and masks are scrubbed from it here
+    fn record_output(&self, result: std::io::Result<Output>) -> std::io::Result<Output> {
+        let result = result.map(|out| Output {
+            status: out.status,
+            stdout: self.scrub(out.stdout),
+            stderr: self.scrub(out.stderr),
+        });
+        let entry = match &result {
+            Ok(out) => format!(
+                "{}{}[{}]\n",
+                String::from_utf8_lossy(&out.stdout),
+                String::from_utf8_lossy(&out.stderr),
+                out.status
+            ),
+            Err(e) => format!("[failed to run: {}]\n", e),
+        };
+        let mut transcript = self.transcript.lock().unwrap();
+        transcript.push_str(&entry);
+        let max = deploy_log_max_bytes();
+        if transcript.len() > max {
+            let mut cut = transcript.len() - max;
+            while !transcript.is_char_boundary(cut) {
+                cut += 1;
+            }
+            transcript.drain(..cut);
+        }
+        result
+    }
+
+    pub(crate) async fn sh(&self, script: &str) -> std::io::Result<Output> {
+        self.record(script);
+        self.record_output(self.runner.run("sh", &["-c", script], None, &self.env()).await)
+    }
+
+    pub(crate) async fn run(&self, program: &str, args: &[&str]) -> std::io::Result<Output> {
+        self.record(&format!("{} {}", program, args.join(" ")));
+        self.record_output(self.runner.run(program, args, None, &self.env()).await)
+    }
+
+    // Runs an argv directly in `dir`, with no shell to interpret it
+    pub(crate) async fn run_in(&self, dir: &str, argv: &[String]) -> std::io::Result<Output> {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty command"))?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.record(&format!("cd {} && {}", dir, argv.join(" ")));
+        self.record_output(self.runner.run(program, &args, Some(dir), &self.env()).await)
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.log.lock().unwrap().clone()
+    }
+
+    pub fn transcript(&self) -> String {
+        self.transcript.lock().unwrap().clone()
+    }
+}
+
+// JSON deploys prefer payload credentials, then the project's; signed query deploys use the
+// project's or the environment
+pub fn git_credentials(payload: Option<&WebhookPayload>, configured: Option<&RegistryCredentials>) -> Result<Credentials, DeployError> {
+    let (token, user) = match payload {
+        Some(p) => (
+            p.token
+                .clone()
+                .or_else(|| configured.map(|c| c.token.clone()))
+                .or_else(|| env_fallback(GIT_TOKEN_VAR)),
+            p.user
+                .clone()
+                .or_else(|| configured.map(|c| c.user.clone()))
+                .or_else(|| env_fallback(USER_VAR)),
+        ),
+        None => match configured {
+            Some(c) => (Some(c.token.clone()), Some(c.user.clone())),
+            None => (env::var(GIT_TOKEN_VAR).ok(), env::var(USER_VAR).ok()),
+        },
+    };
+
+    match (token, user) {
+        (Some(token), Some(user)) => Ok(Credentials { user, token }),
+        _ => {
+            error!("❌ Missing Git credentials (token or user) in payload or environment");
+            Err(DeployError::MissingCredentials("Git"))
+        }
+    }
+}
+
+// A token in the payload still wins; otherwise a project's credential_provider stands in for the
+// registries/environment fallback. None when the project has no provider or the payload has a token.
+pub async fn provided_credentials(job: &DeployJob, kind: &'static str) -> Option<Result<Credentials, DeployError>> {
+    let source = job.credentials.as_ref()?;
+    let payload = job.payload.as_ref();
+    if payload.is_some_and(|p| p.token.is_some()) {
+        return None;
+    }
+    let provider = credentials::provider(source);
+    let token = match provider.token().await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("❌ {} credential provider ({}) failed: {}", kind, provider.describe(), e);
+            return Some(Err(DeployError::CredentialProvider(e)));
+        }
+    };
+    debug!("Using {} credentials from {}", kind, provider.describe());
+    let user = source
+        .user
+        .clone()
+        .or_else(|| payload.and_then(|p| p.user.clone()))
+        .or_else(|| env::var(USER_VAR).ok());
+    Some(match user {
+        Some(user) => Ok(Credentials { user, token }),
+        None => {
+            error!("❌ Missing {} user for the credential provider's token", kind);
+            Err(DeployError::MissingCredentials(kind))
+        }
+    })
+}
+
+// The payload's registry, or DOCKER_REGISTRY for signed query deploys
+fn target_registry(payload: Option<&WebhookPayload>) -> Result<String, DeployError> {
+    let registry = match payload {
+        Some(p) => p.registry.clone(),
+        None => env::var("DOCKER_REGISTRY").ok(),
+    };
+    registry.ok_or_else(|| {
+        error!("❌ Missing registry in payload or environment");
+        DeployError::MissingRegistry
+    })
+}
+
+// build_login is best effort: without a registry or credentials the build runs unauthenticated,
+// as it did before, and only private base images fail
+fn build_login_credentials(job: &DeployJob) -> Option<(String, Credentials)> {
+    match docker_credentials(job.payload.as_ref(), &job.registries, job.docker_credentials.as_ref()) {
+        Ok(login) => Some(login),
+        Err(e) => {
+            warn!("⚠️ build_login is set but there is nothing to log in with ({}); building without a docker login", e);
+            None
+        }
+    }
+}
+
+// Payload credentials first, then the project's entry for the target registry, then the environment
+pub fn docker_credentials(
+    payload: Option<&WebhookPayload>,
+    registries: &BTreeMap<String, RegistryCredentials>,
+    project_default: Option<&RegistryCredentials>,
+) -> Result<(String, Credentials), DeployError> {
+    let registry = target_registry(payload)?;
+
+    let configured = registries
+        .iter()
+        .find(|(host, _)| host.trim_end_matches('/').eq_ignore_ascii_case(registry.trim_end_matches('/')))
+        .map(|(host, creds)| {
+            debug!("Using configured credentials for registry {}", host);
+            creds
+        })
+        .or(project_default);
+    let (token, user) = match payload {
+        Some(p) => (
+            p.token
+                .clone()
+                .or_else(|| configured.map(|c| c.token.clone()))
+                .or_else(|| env_fallback(DOCKER_TOKEN_VAR)),
+            p.user
+                .clone()
+                .or_else(|| configured.map(|c| c.user.clone()))
+                .or_else(|| env_fallback(USER_VAR)),
+        ),
+        None => match configured {
+            Some(c) => (Some(c.token.clone()), Some(c.user.clone())),
+            None => (env::var(DOCKER_TOKEN_VAR).ok(), env::var(USER_VAR).ok()),
+        },
+    };
+
+    match (token, user) {
+        (Some(token), Some(user)) => Ok((registry, Credentials { user, token })),
+        _ => {
+            error!("❌ Missing Docker credentials (token or user) in payload or environment");
+            Err(DeployError::MissingCredentials("Docker"))
+        }
+    }
+}
+
+// Shallow fetches only make sense on a checkout that is already shallow;
+// truncating a full clone's history would surprise whoever uses it locally
+async fn fetch_depth_flag(exec: &Exec, path: &str, git: &GitOptions) -> &'static str {
+    if !git.shallow {
+        return "";
+    }
+    let out = exec.run("git", &["-C", path, "rev-parse", "--is-shallow-repository"]).await;
+    match out {
+        Ok(out) if String::from_utf8_lossy(&out.stdout).trim() == "true" => " --depth 1",
+        _ => {
+            warn!("⚠️ {} is not a shallow checkout; fetching without --depth", path);
+            ""
+        }
+    }
+}
+
+// Inline credential helper; `-c` settings propagate to child git processes (submodules too)
+fn credential_args(creds: &Credentials) -> String {
+    format!(
+        "-c credential.helper= -c \"credential.helper=!f() {{ echo username={}; echo password={}; }}; f\"",
+        creds.user, creds.token
+    )
+}
+
+// Global options for every git invocation: the credential helper plus the project's git_config
+fn git_args(creds: &Credentials, git: &GitOptions) -> String {
+    let mut args = credential_args(creds);
+    for (key, value) in &git.config {
+        args.push_str(&format!(" -c {}", shell_quote(&format!("{}={}", key, value))));
+    }
+    args
+}
+
+const CHECKOUT_LOCK_FILE: &str = ".graft-hook.lock";
+
+// Advisory flock on <repo>/.graft-hook.lock so deploys from other processes (e.g. a cron job
+// using `flock`) serialize with ours. Closing the file releases it, so drop (panic, abort) is enough.
+struct CheckoutLock {
+    _file: std::fs::File,
+}
+
+fn flock(file: &std::fs::File, flags: libc::c_int) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the fd is owned by `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), flags) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+// Creates and removes a file in the checkout, so a read-only or full filesystem fails the deploy
+// with that reason instead of an obscure git or build error halfway through. A missing directory
+// is left to the steps that report it.
+fn probe_writable(path: &str) -> Result<(), DeployError> {
+    let probe = std::path::Path::new(path).join(format!(".graft-hook-probe-{}", std::process::id()));
+    let written = std::fs::write(&probe, b"probe").and_then(|_| std::fs::remove_file(&probe));
+    let Err(e) = written else {
+        return Ok(());
+    };
+    let _ = std::fs::remove_file(&probe);
+    match unwritable_reason(&e) {
+        Some(reason) => {
+            error!("❌ Project directory {} is not writable: {}", path, e);
+            Err(DeployError::NotWritable(path.to_string(), reason.to_string()))
+        }
+        None if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        None => {
+            warn!("⚠️ Write probe in {} failed: {}; deploying anyway", path, e);
+            Ok(())
+        }
+    }
+}
+
+// The filesystem problems worth stopping a deploy for
+fn unwritable_reason(e: &std::io::Error) -> Option<&'static str> {
+    match e.raw_os_error()? {
+        libc::EROFS => Some("read-only filesystem"),
+        libc::ENOSPC => Some("no space left on the filesystem"),
+        libc::EDQUOT => Some("disk quota exceeded"),
+        libc::EACCES | libc::EPERM => Some("permission denied"),
+        _ => None,
+    }
+}
+
+// Waits for the lock; a checkout we can't create the lock file in is deployed unlocked
+async fn lock_checkout(path: &str) -> Option<CheckoutLock> {
+    let lock_path = std::path::Path::new(path).join(CHECKOUT_LOCK_FILE);
+    let file = match std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("⚠️ Cannot open {}; deploying without the checkout lock: {}", lock_path.display(), e);
+            return None;
+        }
+    };
+    match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(()) => return Some(CheckoutLock { _file: file }),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            info!("⏳ {} is locked by another process; waiting", lock_path.display());
+        }
+        Err(e) => {
+            warn!("⚠️ Failed to lock {}; deploying without it: {}", lock_path.display(), e);
+            return None;
+        }
+    }
+    let locked = tokio::task::spawn_blocking(move || flock(&file, libc::LOCK_EX).map(|_| file)).await;
+    match locked {
+        Ok(Ok(file)) => Some(CheckoutLock { _file: file }),
+        Ok(Err(e)) => {
+            warn!("⚠️ Failed to lock {}; deploying without it: {}", lock_path.display(), e);
+            None
+        }
+        Err(e) => {
+            warn!("⚠️ Checkout lock task failed for {}: {}", lock_path.display(), e);
+            None
+        }
+    }
+}
+
+// allow_clone: turns an empty directory into a checkout tracking `url` as origin. The sync that
+// follows does the actual fetch, so credentials, shallow and tag deploys work as for any pull.
+// A directory with other content is left alone rather than risk mixing it with the repo.
+async fn init_checkout(exec: &Exec, path: &str, url: &str) -> Result<(), DeployError> {
+    let root = std::path::Path::new(path);
+    if root.join(".git").exists() || (exec.dry_run && !root.exists()) {
+        return Ok(());
+    }
+    let stray = std::fs::read_dir(root)
+        .map_err(|e| DeployError::GitClone(e.to_string()))?
+        .filter_map(Result::ok)
+        .any(|entry| entry.file_name() != CHECKOUT_LOCK_FILE);
+    if stray {
+        error!("❌ {} is not a git repository and is not empty; refusing to clone into it", path);
+        return Err(DeployError::GitClone(format!("{} is not empty", path)));
+    }
+
+    info!("📦 {} has no repository yet; initializing it from {}", path, url);
+    match exec
+        .sh(&format!("cd {} && git init -q && git remote add origin {}", path, shell_quote(url)))
+        .await
+    {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            Err(DeployError::BinaryNotFound("git"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ git init failed in {}: {}", path, stderr);
+            Err(DeployError::GitClone(output_tail(&stderr)))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for git init in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+pub async fn deploy_git(exec: &Exec, job: &DeployJob, creds: &Credentials, metrics: &Metrics) -> DeployOutcome {
+    deploy_git_with_login(exec, job, creds, None, metrics).await
+}
+
+// parallel_login: `login` (registry, credentials) runs while git syncs; a git failure is reported
+// over a login failure, since the login only matters once there is something to build
+async fn deploy_git_with_login(
+    exec: &Exec,
+    job: &DeployJob,
+    creds: &Credentials,
+    login: Option<(&str, &Credentials)>,
+    metrics: &Metrics,
+) -> DeployOutcome {
+    // A fresh host may not even have the directory yet; the lock file needs it
+    if job.git.clone_url.is_some() && !exec.dry_run {
+        if let Err(e) = std::fs::create_dir_all(&job.repo_path) {
+            error!("❌ Failed to create {}: {}", job.repo_path, e);
+            return Err(DeployError::GitClone(e.to_string()));
+        }
+    }
+    if !exec.dry_run {
+        probe_writable(&job.repo_path)?;
+    }
+    let _lock = if exec.dry_run { None } else { lock_checkout(&job.repo_path).await };
+    if let Some(url) = &job.git.clone_url {
+        init_checkout(exec, &job.repo_path, url).await?;
+    }
+
+    // Only needed to undo a failed build or `up`, post_up command or health check
+    let undoable = job.mode == "repo"
+        && (job.compose.restore_on_failure
+            || job.compose.post_up_rollback && (!job.compose.post_up.is_empty() || job.compose.health.is_some()));
+    // A redeploy of a known commit goes back on purpose; previews build whatever the PR holds
+    let guard = job.git.require_fast_forward && job.git.commit.is_none() && job.git.pr_number.is_none();
+    let checkout = job.checkout();
+    let deployed = if guard { current_head(exec, &checkout).await } else { None };
+    let previous = match (undoable || deployed.is_some(), job.git.atomic_worktree) {
+        (false, _) => None,
+        (true, false) => current_head(exec, &job.repo_path).await.map(Previous::Commit),
+        (true, true) => std::fs::read_link(&job.path).ok().map(Previous::Release),
+    };
+
+    // Time the two phases separately so slow deploys can be attributed to network or build
+    let started = Instant::now();
+    exec.phase("git", "started");
+    let span = info_span!("git_pull", duration_ms = field::Empty, outcome = field::Empty);
+    let sync = async {
+        let synced = traced(span, async {
+            match job.git.pr_number {
+                Some(pr) => sync_pull_request(exec, job, creds, pr, &checkout).await,
+                None if job.git.atomic_worktree => sync_worktree(exec, job, creds).await,
+                None => sync_repo(exec, &job.repo_path, creds, &job.git).await,
+            }
+        })
+        .await;
+        (synced, started.elapsed().as_secs_f64())
+    };
+    let logged_in = async {
+        match login {
+            Some((registry, docker)) => docker_login(exec, registry, docker, job.compose.use_sudo, docker_login_retries()).await,
+            None => Ok(()),
+        }
+    };
+    let ((synced, git_seconds), logged_in) = tokio::join!(sync, logged_in);
+    metrics.observe("graft_git_duration_seconds", &job.project_id, git_seconds);
+    synced?;
+    logged_in?;
+    let checkout = checkout.as_str();
+    if let Some(deployed) = &deployed {
+        if let Err(e) = check_fast_forward(exec, checkout, deployed).await {
+            if let Some(previous) = &previous {
+                restore_checkout(exec, job, previous).await;
+            }
+            return Err(e);
+        }
+    }
+    exec.phase("git", "done");
+    if let Some(commit) = current_head(exec, checkout).await {
+        exec.set_env("GRAFT_COMMIT", &commit);
+    }
+
+    // Pull deploys (e.g. static files served live) stop here, before any docker step
+    if job.mode == "pull" {
+        info!("✅ {} pulled; no container action for pull deploys", job.project_id);
+        return Ok(DeployReport {
+            message: "Success: Repo Pulled",
+            timings: None,
+            services: None,
+            commit: commit_info(exec, checkout).await,
+            registry_login: None,
+            login_user: None,
+            smoke_test: None,
+        });
+    }
+
+    let started = Instant::now();
+    exec.phase("build", "started");
+    let span = info_span!("compose_build", duration_ms = field::Empty, outcome = field::Empty);
+    let built = traced(span, compose_build_up(exec, &job.path, &job.compose)).await;
+    let build_seconds = started.elapsed().as_secs_f64();
+    metrics.observe("graft_build_duration_seconds", &job.project_id, build_seconds);
+    if let Err(e) = built {
+        return Err(match &previous {
+            Some(previous) if job.compose.restore_on_failure => {
+                DeployError::Restored(Box::new(e), roll_back(exec, job, previous).await)
+            }
+            _ => e,
+        });
+    }
+    exec.phase("build", "done");
+
+    let checked = match run_post_up(exec, &job.path, &job.compose).await {
+        Ok(()) => wait_healthy(exec, &job.path, &job.compose).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = checked {
+        if let Some(previous) = previous.as_ref().filter(|_| job.compose.post_up_rollback) {
+            roll_back(exec, job, previous).await;
+        }
+        return Err(e);
+    }
+
+    let message = if job.compose.no_build {
+        "Success: Repo Pulled and Containers Recreated"
+    } else {
+        "Success: Repo Pulled and Containers Rebuilt"
+    };
+    let smoke_test = run_smoke_test(exec, &job.path, &job.compose).await;
+    Ok(DeployReport {
+        message,
+        timings: Some(PhaseTimings {
+            git_seconds,
+            build_seconds,
+        }),
+        services: compose_services(exec, &job.path, &job.compose).await,
+        commit: commit_info(exec, checkout).await,
+        registry_login: None,
+        login_user: None,
+        smoke_test,
+    })
+}
+
+// Fields are separated by the unit separator, which can't appear in a subject line
+fn parse_commit_info(stdout: &str) -> Option<CommitInfo> {
+    let mut fields = stdout.trim_end_matches('\n').splitn(4, '\x1f');
+    Some(CommitInfo {
+        sha: fields.next()?.to_string(),
+        author: fields.next()?.to_string(),
+        subject: fields.next()?.to_string(),
+        timestamp: fields.next()?.trim().parse().ok()?,
+    })
+}
+
+// Best effort, like compose_services: only drops the detail from the response
+async fn commit_info(exec: &Exec, path: &str) -> Option<CommitInfo> {
+    let out = exec
+        .run("git", &["-C", path, "log", "-1", "--format=%h%x1f%an%x1f%s%x1f%ct"])
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    parse_commit_info(&String::from_utf8_lossy(&out.stdout))
+}
+
+async fn current_head(exec: &Exec, path: &str) -> Option<String> {
+    let out = exec.run("git", &["-C", path, "rev-parse", "HEAD"]).await.ok()?;
+    let head = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (out.status.success() && !head.is_empty()).then_some(head)
+}
+
+// What a failed post_up command or health check rolls back to: a commit in place, or the previous release symlink target
+enum Previous {
+    Commit(String),
+    Release(std::path::PathBuf),
+}
+
+// require_fast_forward: the fetched HEAD must have the deployed commit in its history
+async fn check_fast_forward(exec: &Exec, checkout: &str, deployed: &str) -> Result<(), DeployError> {
+    let Some(fetched) = current_head(exec, checkout).await else {
+        return Ok(());
+    };
+    match exec.run("git", &["-C", checkout, "merge-base", "--is-ancestor", deployed, &fetched]).await {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) if out.status.code() == Some(1) => {
+            error!("🛑 {} does not descend from the deployed {} in {}; refusing it", fetched, deployed, checkout);
+            Err(DeployError::NotFastForward(deployed.to_string(), fetched))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Could not compare {} with the deployed {}: {}", fetched, deployed, stderr);
+            Err(DeployError::GitPull(output_tail(&stderr)))
+        }
+        Err(e) => Err(DeployError::Spawn(e.to_string())),
+    }
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(12)]
+}
+
+// Best effort: the deploy has already failed, this only tries to leave the old version running.
+// True when it is.
+async fn roll_back(exec: &Exec, job: &DeployJob, previous: &Previous) -> bool {
+    if !restore_checkout(exec, job, previous).await {
+        return false;
+    }
+    match compose_build_up(exec, &job.path, &job.compose).await {
+        Ok(()) => {
+            info!("✅ Rolled {} back", job.project_id);
+            true
+        }
+        Err(e) => {
+            error!("❌ Rollback rebuild failed: {}", e);
+            false
+        }
+    }
+}
+
+// Puts the previous commit or release back in place; false when that failed
+async fn restore_checkout(exec: &Exec, job: &DeployJob, previous: &Previous) -> bool {
+    match previous {
+        Previous::Commit(commit) => {
+            warn!("↩️ Rolling {} back to {}", job.project_id, commit);
+            match exec.run("git", &["-C", &job.repo_path, "reset", "--hard", commit]).await {
+                Ok(out) if out.status.success() => true,
+                Ok(out) => {
+                    error!("Rollback reset failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+                    false
+                }
+                Err(e) => {
+                    error!("Failed to spawn git for rollback: {}", e);
+                    false
+                }
+            }
+        }
+        Previous::Release(release) => {
+            warn!("↩️ Switching {} back to {}", job.project_id, release.display());
+            if let Err(e) = switch_release(&job.path, release) {
+                error!("Rollback switch failed: {}", e);
+                return false;
+            }
+            true
+        }
+    }
+}
+
+// Points `link` at `target` with a rename, so readers see either the old tree or the new one
+fn switch_release(link: &str, target: &std::path::Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(link).is_ok_and(|meta| !meta.file_type().is_symlink()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a symlink", link),
+        ));
+    }
+    let staging = format!("{}.graft-next", link.trim_end_matches('/'));
+    let _ = std::fs::remove_file(&staging);
+    std::os::unix::fs::symlink(target, &staging)?;
+    std::fs::rename(&staging, link)
+}
+
+// What a fetch without integration targets: the PR, the tag, else the first git step's branch, else origin main
+fn fetch_target(git: &GitOptions) -> (&str, String) {
+    if let Some(pr) = git.pr_number {
+        return ("origin", format!("pull/{}/head", pr));
+    }
+    match (&git.tag, git.steps.first()) {
+        (Some(tag), _) => ("origin", format!("refs/tags/{0}:refs/tags/{0}", tag)),
+        (None, Some(step)) => (step.remote.as_str(), step.branch.clone()),
+        (None, None) => ("origin", "main".to_string()),
+    }
+}
+
+// POST /plan: what a deploy would do. Repo and pull plans fetch the target (no reset) to compare
+// it with the checkout; the rest is a dry run of the deploy recording its commands.
+#[derive(Serialize, Debug)]
+pub struct DeployPlan {
+    pub project: String,
+    pub mode: String,
+    pub config: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_commits: Option<u64>,
+    pub commands: Vec<String>,
+    // Why the fetch or the dry run failed; the real deploy would most likely fail the same way
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// One line of the /webhook/test readiness report
+#[derive(Serialize, Debug)]
+pub struct ReadinessCheck {
+    pub check: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+// Everything a deploy of `job` needs before its first command, checked without running any:
+// credentials, directories, compose and env files
+pub fn readiness_checks(job: &DeployJob) -> Vec<ReadinessCheck> {
+    let check = |check, result: Result<String, String>| match result {
+        Ok(detail) => ReadinessCheck { check, ok: true, detail },
+        Err(detail) => ReadinessCheck { check, ok: false, detail },
+    };
+    let is_dir = |path: &str| std::path::Path::new(path).is_dir();
+    let repo_mode = matches!(job.mode.as_str(), "repo" | "pull");
+    let mut checks = Vec::new();
+
+    let provided = job
+        .credentials
+        .as_ref()
+        .filter(|_| job.payload.as_ref().is_none_or(|p| p.token.is_none()))
+        .map(|source| credentials::provider(source).describe());
+    let credentials = match (job.mode.as_str(), provided) {
+        // Not run here: a readiness check shouldn't fetch secrets
+        ("repo" | "pull" | "image" | "login", Some(provider)) => Ok(format!("token from {}", provider)),
+        ("repo" | "pull", None) => git_credentials(job.payload.as_ref(), job.git_credentials.as_ref()).map(|c| format!("git as {}", c.user)),
+        ("image" | "login", None) => docker_credentials(job.payload.as_ref(), &job.registries, job.docker_credentials.as_ref())
+            .map(|(registry, c)| format!("{} as {}", registry, c.user)),
+        _ => Ok("not needed".to_string()),
+    };
+    checks.push(check("credentials", credentials.map_err(|e| e.to_string())));
+
+    if repo_mode {
+        let checkout = std::path::Path::new(&job.repo_path);
+        checks.push(check(
+            "repo_path",
+            if checkout.join(".git").exists() {
+                Ok(format!("{} is a git checkout", job.repo_path))
+            } else if job.git.clone_url.is_some() {
+                Ok(format!("{} will be cloned on the first deploy", job.repo_path))
+            } else {
+                Err(format!("{} is not a git checkout", job.repo_path))
+            },
+        ));
+    }
+    if !matches!(job.mode.as_str(), "pull" | "teardown" | "login") {
+        let path = if is_dir(&job.path) {
+            check_compose_file(&job.path, &job.compose)
+                .and_then(|()| check_env_file(&job.path, &job.compose))
+                .map(|()| format!("{} has its compose and env files", job.path))
+                .map_err(|e| e.to_string())
+        } else if repo_mode {
+            // A repo deploy may still be about to create the compose dir from git
+            Ok(format!("{} will come from the checkout", job.path))
+        } else {
+            Err(format!("{} does not exist", job.path))
+        };
+        checks.push(check("compose_path", path));
+    }
+    checks
+}
+
+// The resolved settings a deploy of `job` runs with; credentials are left out
+fn describe_job(job: &DeployJob) -> serde_json::Value {
+    let compose = &job.compose;
+    serde_json::json!({
+        "path": job.path,
+        "repo_path": job.repo_path,
+        "compose": {
+            "profiles": compose.profiles,
+            "scale": compose.scale,
+            "files": compose.files,
+            "project_directory": compose.project_directory,
+            "env_file": compose.env_file,
+            "use_sudo": compose.use_sudo,
+            "no_build": compose.no_build,
+            "pull": compose.pull,
+            "remove_orphans": compose.remove_orphans,
+            "force_recreate": compose.force_recreate,
+            "stop_timeout": compose.stop_timeout,
+            "wait": compose.wait.is_some(),
+            "command": compose.command,
+            "post_up": compose.post_up,
+            "health_command": compose.health.as_ref().map(|h| &h.command),
+            "verify_images": compose.verify_images.is_some(),
+        },
+        "git": {
+            "tag": job.git.tag,
+            "steps": job.git.steps.iter().map(|s| format!("{}/{}", s.remote, s.branch)).collect::<Vec<_>>(),
+            "shallow": job.git.shallow,
+            "update_submodules": job.git.update_submodules,
+            "atomic_worktree": job.git.atomic_worktree,
+            "pr_number": job.git.pr_number,
+        },
+        "compose_project": job.compose.project_name,
+        "rollback_backups": job.rollback_limit,
+    })
+}
+
+pub async fn plan_job(job: &DeployJob, runner: Arc<dyn CommandRunner>) -> DeployPlan {
+    let mut plan = DeployPlan {
+        project: job.project_id.clone(),
+        mode: job.mode.clone(),
+        config: describe_job(job),
+        target: None,
+        current_commit: None,
+        target_commit: None,
+        new_commits: None,
+        commands: Vec::new(),
+        error: None,
+    };
+
+    if matches!(job.mode.as_str(), "repo" | "pull") {
+        if let Err(e) = plan_fetch(&mut plan, job, runner).await {
+            plan.error = Some(e.to_string());
+            return plan;
+        }
+    }
+
+    let dry = Arc::new(Exec::dry_run());
+    if let Err(e) = run_job(job, Arc::new(Metrics::default()), dry.clone(), None).await {
+        plan.error = Some(e.to_string());
+    }
+    plan.commands = dry.commands();
+    plan
+}
+
+async fn plan_fetch(plan: &mut DeployPlan, job: &DeployJob, runner: Arc<dyn CommandRunner>) -> Result<(), DeployError> {
+    let exec = Exec::new(runner);
+    let creds = match provided_credentials(job, "Git").await {
+        Some(creds) => creds?,
+        None => git_credentials(job.payload.as_ref(), job.git_credentials.as_ref())?,
+    };
+    exec.add_secret(&creds.token);
+    let (remote, refspec) = fetch_target(&job.git);
+    plan.target = Some(format!("{} {}", remote, refspec));
+
+    let repo = &job.repo_path;
+    info!("Planning {}: fetching {} {} without resetting", job.project_id, remote, refspec);
+    match exec
+        .sh(&format!("cd {} && git {} fetch {} {}", repo, git_args(&creds, &job.git), remote, refspec))
+        .await
+    {
+        Ok(out) if out.status.success() => {}
+        Ok(out) => return Err(DeployError::GitPull(output_tail(&String::from_utf8_lossy(&out.stderr)))),
+        Err(e) => return Err(DeployError::Spawn(e.to_string())),
+    }
+
+    let checkout = job.checkout();
+    let current = current_head(&exec, &checkout).await;
+    let target = exec.run("git", &["-C", repo, "rev-parse", "FETCH_HEAD"]).await.ok().and_then(|out| {
+        let sha = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        (out.status.success() && !sha.is_empty()).then_some(sha)
+    });
+    if let (Some(current), Some(target)) = (&current, &target) {
+        let range = format!("{}..{}", current, target);
+        if let Ok(out) = exec.run("git", &["-C", repo, "rev-list", "--count", &range]).await {
+            plan.new_commits = String::from_utf8_lossy(&out.stdout).trim().parse().ok();
+        }
+    }
+    plan.current_commit = current.map(|sha| sha.chars().take(12).collect());
+    plan.target_commit = target.map(|sha| sha.chars().take(12).collect());
+    Ok(())
+}
+
+// atomic_worktree: fetch into repo_path without touching its checkout, materialize the commit
+// as a worktree under <repo_path>.releases/<commit>, then switch the compose path symlink to it.
+// Only the first git step's remote/branch is used; merge strategies don't apply here.
+async fn sync_worktree(exec: &Exec, job: &DeployJob, creds: &Credentials) -> Result<(), DeployError> {
+    let (repo, git) = (&job.repo_path, &job.git);
+    let args = git_args(creds, git);
+    let depth = fetch_depth_flag(exec, repo, git).await;
+    let (remote, refspec) = fetch_target(git);
+
+    let fetched = match &git.commit {
+        // A known commit (redeploy-last-good) is already in the repo; just resolve its release name
+        Some(commit) => {
+            info!("Redeploying commit {} from {}", commit, repo);
+            exec.sh(&format!("cd {} && git rev-parse --short=12 {}", repo, commit)).await
+        }
+        None => {
+            info!("Fetching {} {} into {} for a new release", remote, refspec, repo);
+            exec.sh(&format!(
+                "cd {} && git {} fetch{} {} {} && git rev-parse --short=12 FETCH_HEAD",
+                repo, args, depth, remote, refspec
+            ))
+            .await
+        }
+    };
+    let commit = match fetched {
+        Ok(out) if out.status.success() && exec.dry_run => "<commit>".to_string(),
+        Ok(out) if out.status.success() => {
+            String::from_utf8_lossy(&out.stdout).lines().last().unwrap_or("").trim().to_string()
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            return Err(DeployError::BinaryNotFound("git"));
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Git fetch failed in {}: {}", repo, stderr);
+            return Err(DeployError::GitPull(output_tail(&stderr)));
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for git fetch in {}: {}", repo, e);
+            return Err(DeployError::Spawn(e.to_string()));
+        }
+    };
+    if commit.is_empty() {
+        return Err(DeployError::GitPull("could not resolve the fetched commit".to_string()));
+    }
+
+    let release = format!("{}.releases/{}", repo.trim_end_matches('/'), commit);
+    if std::path::Path::new(&release).is_dir() {
+        info!("Release {} already checked out", release);
+    } else {
+        info!("Creating worktree {}", release);
+        match exec
+            .sh(&format!("cd {} && git worktree add --force --detach {} {}", repo, shell_quote(&release), commit))
+            .await
+        {
+            Ok(out) if out.status.success() => {}
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("❌ git worktree add failed in {}: {}", repo, stderr);
+                return Err(DeployError::GitPull(output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for git worktree in {}: {}", repo, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+    update_submodules(exec, &release, &args, git).await?;
+    if exec.dry_run {
+        return Ok(());
+    }
+
+    switch_release(&job.path, std::path::Path::new(&release)).map_err(|e| {
+        error!("❌ Failed to point {} at {}: {}", job.path, release, e);
+        DeployError::ReleaseSwitch(e.to_string())
+    })?;
+    info!("✅ {} now points at {}", job.path, release);
+    if let Err(e) = record_release(repo, &commit) {
+        warn!("Failed to record release {} in the history: {}", commit, e);
+    }
+    if let Some(keep) = git.retain_releases {
+        prune_releases(exec, repo, keep as usize).await;
+    }
+    Ok(())
+}
+
+fn releases_dir(repo: &str) -> String {
+    format!("{}.releases", repo.trim_end_matches('/'))
+}
+
+// <repo_path>.releases/.history: released commits, oldest first, one per line
+fn release_history(repo: &str) -> Vec<String> {
+    std::fs::read_to_string(format!("{}/.history", releases_dir(repo)))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|commit| !commit.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn write_release_history(repo: &str, history: &[String]) -> std::io::Result<()> {
+    let dir = releases_dir(repo);
+    let tmp = format!("{}/.history.{}.tmp", dir, std::process::id());
+    std::fs::write(&tmp, history.iter().map(|c| format!("{}\n", c)).collect::<String>())?;
+    std::fs::rename(&tmp, format!("{}/.history", dir))
+}
+
+// Moves `commit` to the newest end, so a redeployed release counts as recent again
+fn record_release(repo: &str, commit: &str) -> std::io::Result<()> {
+    let mut history = release_history(repo);
+    history.retain(|c| c != commit);
+    history.push(commit.to_string());
+    write_release_history(repo, &history)
+}
+
+// Best effort: removes the worktrees of all but the newest `keep` releases
+async fn prune_releases(exec: &Exec, repo: &str, keep: usize) {
+    let mut history = release_history(repo);
+    if history.len() <= keep {
+        return;
+    }
+    let expired: Vec<String> = history.drain(..history.len() - keep).collect();
+    for commit in &expired {
+        let release = format!("{}/{}", releases_dir(repo), commit);
+        info!("Removing old release {}", release);
+        match exec.sh(&format!("cd {} && git worktree remove --force {}", repo, shell_quote(&release))).await {
+            Ok(out) if out.status.success() => {}
+            Ok(out) => warn!("Failed to remove {}: {}", release, String::from_utf8_lossy(&out.stderr).trim()),
+            Err(e) => warn!("Failed to spawn git to remove {}: {}", release, e),
+        }
+    }
+    if let Err(e) = exec.run("git", &["-C", repo, "worktree", "prune"]).await {
+        warn!("Failed to spawn git worktree prune: {}", e);
+    }
+    if let Err(e) = write_release_history(repo, &history) {
+        warn!("Failed to update the release history: {}", e);
+    }
+}
+
+// Repoints the live symlink `rollback_steps` releases back from the one it points at now and
+// brings that release's containers up. The history is left as is, so repeating a rollback
+// keeps walking back rather than bouncing between two releases.
+pub async fn deploy_rollback(exec: &Exec, job: &DeployJob) -> DeployOutcome {
+    if !job.git.atomic_worktree {
+        return Err(DeployError::RollbackUnavailable("the project doesn't use atomic_worktree".to_string()));
+    }
+    let steps = job.git.rollback_steps.max(1) as usize;
+    let history = release_history(&job.repo_path);
+    let live = std::fs::read_link(&job.path)
+        .ok()
+        .and_then(|target| target.file_name().map(|name| name.to_string_lossy().into_owned()));
+    let Some(position) = live.and_then(|live| history.iter().position(|c| *c == live)) else {
+        return Err(DeployError::RollbackUnavailable(format!("{} isn't a retained release", job.path)));
+    };
+    if steps > position {
+        return Err(DeployError::RollbackUnavailable(format!(
+            "only {} older release(s) are retained",
+            position
+        )));
+    }
+    let commit = &history[position - steps];
+    let release = format!("{}/{}", releases_dir(&job.repo_path), commit);
+    if !std::path::Path::new(&release).is_dir() {
+        return Err(DeployError::RollbackUnavailable(format!("{} no longer exists", release)));
+    }
+
+    warn!("↩️ Switching {} back {} release(s) to {}", job.project_id, steps, commit);
+    switch_release(&job.path, std::path::Path::new(&release)).map_err(|e| {
+        error!("❌ Failed to point {} at {}: {}", job.path, release, e);
+        DeployError::ReleaseSwitch(e.to_string())
+    })?;
+    exec.set_env("GRAFT_COMMIT", commit);
+    compose_build_up(exec, &job.path, &job.compose).await?;
+    Ok(DeployReport {
+        message: "Success: Rolled Back to a Retained Release",
+        timings: None,
+        services: compose_services(exec, &job.path, &job.compose).await,
+        commit: commit_info(exec, &job.path).await,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+    })
+}
+
+// Fetches refs/pull/<pr>/head into repo_path (its own checkout untouched) and checks it out in
+// the PR's worktree, creating that on the first deploy of the PR
+async fn sync_pull_request(
+    exec: &Exec,
+    job: &DeployJob,
+    creds: &Credentials,
+    pr: u32,
+    checkout: &str,
+) -> Result<(), DeployError> {
+    let (repo, git) = (&job.repo_path, &job.git);
+    let args = git_args(creds, git);
+    let depth = fetch_depth_flag(exec, repo, git).await;
+
+    info!("Fetching pull request #{} into {}", pr, repo);
+    let switch = if std::path::Path::new(checkout).exists() {
+        format!("git -C {} checkout --force --detach", shell_quote(checkout))
+    } else {
+        format!("git worktree add --force --detach {}", shell_quote(checkout))
+    };
+    let (remote, refspec) = fetch_target(git);
+    let script = format!(
+        "cd {} && git {} fetch{} {} {} && {} \"$(git rev-parse FETCH_HEAD)\"",
+        repo, args, depth, remote, refspec, switch
+    );
+    match exec.sh(&script).await {
+        Ok(out) if out.status.success() => {}
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            return Err(DeployError::BinaryNotFound("git"));
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Fetching pull request #{} failed in {}: {}", pr, repo, stderr);
+            return Err(DeployError::GitPull(output_tail(&stderr)));
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for git fetch in {}: {}", repo, e);
+            return Err(DeployError::Spawn(e.to_string()));
+        }
+    }
+    update_submodules(exec, checkout, &args, git).await
+}
+
+// Each entry is appended to the project's `docker compose` prefix, args quoted individually
+async fn run_post_up(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    if compose.post_up.is_empty() {
+        return Ok(());
+    }
+    exec.phase("post_up", "started");
+    for (i, args) in compose.post_up.iter().enumerate() {
+        let step = args.iter().map(|a| shell_quote(a)).collect::<Vec<_>>().join(" ");
+        compose_steps(exec, path, compose, &[step], |stderr| DeployError::PostUp(i + 1, stderr)).await?;
+    }
+    exec.phase("post_up", "done");
+    Ok(())
+}
+
+// The deploy has already succeeded; this only decides whether it did so with warnings
+async fn run_smoke_test(exec: &Exec, path: &str, compose: &ComposeOptions) -> Option<SmokeTestResult> {
+    let smoke = compose.smoke_test.as_ref()?;
+    info!("Running smoke test {:?} in {} (up to {}s)", smoke.command, path, smoke.timeout.as_secs());
+    exec.phase("smoke_test", "started");
+    let (passed, output) = match tokio::time::timeout(smoke.timeout, exec.run_in(path, &smoke.command)).await {
+        Ok(Ok(out)) => {
+            let output = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+            (out.status.success(), output_tail(&output))
+        }
+        Ok(Err(e)) => (false, format!("failed to run the smoke test: {}", e)),
+        Err(_) => (false, format!("smoke test did not finish within {}s", smoke.timeout.as_secs())),
+    };
+    if passed {
+        info!("✅ Smoke test passed");
+    } else {
+        warn!("⚠️ Smoke test failed in {}; the deploy stands: {}", path, output);
+    }
+    exec.phase("smoke_test", "done");
+    Some(SmokeTestResult { passed, output })
+}
+
+// Runs the health command until it exits 0; each attempt is cut off at the remaining time
+async fn wait_healthy(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    let Some(health) = &compose.health else {
+        return Ok(());
+    };
+    info!("Waiting up to {}s for {:?} to pass in {}", health.timeout.as_secs(), health.command, path);
+    exec.phase("health", "started");
+    let deadline = Instant::now() + health.timeout;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let last = match tokio::time::timeout(remaining, exec.run_in(path, &health.command)).await {
+            Ok(Ok(out)) if out.status.success() => {
+                info!("✅ Health command passed on attempt {}", attempt);
+                exec.phase("health", "done");
+                return Ok(());
+            }
+            Ok(Ok(out)) => {
+                let output = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+                debug!("Health command attempt {} exited with {}", attempt, out.status);
+                output_tail(&output)
+            }
+            Ok(Err(e)) => {
+                error!("Failed to spawn health command in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+            Err(_) => "health command was still running at the deadline".to_string(),
+        };
+        if Instant::now() + health.interval >= deadline {
+            error!("❌ Health command failed {} time(s) in {}: {}", attempt, path, last);
+            return Err(DeployError::Unhealthy(health.timeout.as_secs(), last));
+        }
+        tokio::time::sleep(health.interval).await;
+    }
+}
+
+async fn sync_repo(exec: &Exec, path: &str, creds: &Credentials, git: &GitOptions) -> Result<(), DeployError> {
+    let args = git_args(creds, git);
+    let depth = fetch_depth_flag(exec, path, git).await;
+    if let Some(commit) = &git.commit {
+        checkout_commit(exec, path, commit).await?;
+    } else if let Some(tag) = &git.tag {
+        if !git.steps.is_empty() {
+            info!("Tag {} requested; skipping the configured git steps", tag);
+        }
+        checkout_tag(exec, path, &args, tag, depth).await?;
+    } else if git.steps.is_empty() {
+        match (force_pull(exec, path, &args, depth, "main").await, &git.fallback_branch) {
+            (Err(DeployError::GitPull(stderr)), Some(fallback)) if is_missing_branch(&stderr) => {
+                warn!("⚠️ Branch main not found on the remote of {}; falling back to {}", path, fallback);
+                force_pull(exec, path, &args, depth, fallback).await?;
+            }
+            (pulled, _) => pulled?,
+        }
+    } else {
+        match (run_git_steps(exec, path, &args, &git.steps, depth).await, &git.fallback_branch) {
+            (Err(DeployError::GitStep(1, stderr)), Some(fallback)) if is_missing_branch(&stderr) => {
+                warn!("⚠️ Branch {} not found on the remote of {}; falling back to {}", git.steps[0].branch, path, fallback);
+                let mut steps = git.steps.clone();
+                steps[0].branch = fallback.clone();
+                run_git_steps(exec, path, &args, &steps, depth).await?;
+            }
+            (pulled, _) => pulled?,
+        }
+    }
+    update_submodules(exec, path, &args, git).await
+}
+
+// What `git fetch` says when the branch asked for isn't on the remote
+fn is_missing_branch(stderr: &str) -> bool {
+    stderr.contains("couldn't find remote ref")
+}
+
+async fn force_pull(exec: &Exec, path: &str, git_args: &str, depth: &str, branch: &str) -> Result<(), DeployError> {
+    // Force Git Pull (Fetch + Reset Hard)
+    // This ensures that local changes or untracked file conflicts (like docker-compose.yml)
+    // are overwritten by the remote state.
+    info!("Starting Force Git Pull (fetch & reset --hard) of {} in {}", branch, path);
+    let pull_output = exec
+        .sh(&format!(
+            "cd {} && \
+             git {} fetch{} origin {} && \
+             git reset --hard origin/{}",
+            path,
+            git_args,
+            depth,
+            branch,
+            branch
+        ))
+        .await;
+
+    match pull_output {
+        Ok(out) if out.status.success() => {
+            info!("✅ Git pull successful");
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            return Err(DeployError::BinaryNotFound("git"));
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Git pull failed in {}: {}", path, stderr);
+            return Err(DeployError::GitPull(output_tail(&stderr)));
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for git pull in {}: {}", path, e);
+            return Err(DeployError::Spawn(e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+// Redeploying a known commit: no fetch, it was checked out here before
+async fn checkout_commit(exec: &Exec, path: &str, commit: &str) -> Result<(), DeployError> {
+    info!("Resetting {} to commit {}", path, commit);
+    match exec.sh(&format!("cd {} && git reset --hard {}", path, commit)).await {
+        Ok(out) if out.status.success() => {
+            info!("✅ Checked out commit {}", commit);
+            Ok(())
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            Err(DeployError::BinaryNotFound("git"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Resetting to commit {} failed in {}: {}", commit, path, stderr);
+            Err(DeployError::GitPull(output_tail(&stderr)))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for commit checkout in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// Release deploys: fetch just the tag and hard-reset the checkout to it
+async fn checkout_tag(exec: &Exec, path: &str, git_args: &str, tag: &str, depth: &str) -> Result<(), DeployError> {
+    info!("Checking out tag {} in {}", tag, path);
+    let output = exec
+        .sh(&format!(
+            "cd {} && git {} fetch{} --force origin tag {} && git reset --hard refs/tags/{}",
+            path, git_args, depth, tag, tag
+        ))
+        .await;
+
+    match output {
+        Ok(out) if out.status.success() => {
+            info!("✅ Checked out tag {}", tag);
+            Ok(())
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ git binary not found in PATH");
+            Err(DeployError::BinaryNotFound("git"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Checking out tag {} failed in {}: {}", tag, path, stderr);
+            Err(DeployError::GitTag(output_tail(&stderr)))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for tag checkout in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// Configured fetch/merge steps, run in order; the first failure aborts before anything is built
+async fn run_git_steps(
+    exec: &Exec,
+    path: &str,
+    git_args: &str,
+    steps: &[GitStep],
+    depth: &str,
+) -> Result<(), DeployError> {
+    for (i, step) in steps.iter().enumerate() {
+        let integrate = match step.strategy {
+            GitStrategy::Reset => "reset --hard FETCH_HEAD",
+            GitStrategy::Merge => "-c user.name=graft-hook -c user.email=graft-hook@localhost merge --no-edit FETCH_HEAD",
+            GitStrategy::FfOnly => "merge --ff-only FETCH_HEAD",
+        };
+        info!(
+            "Git step {}/{}: fetch {} {} ({:?}) in {}",
+            i + 1,
+            steps.len(),
+            step.remote,
+            step.branch,
+            step.strategy,
+            path
+        );
+
+        let output = exec
+            .sh(&format!(
+                "cd {} && git {} fetch{} {} {} && git {}",
+                path,
+                git_args,
+                depth,
+                step.remote,
+                step.branch,
+                integrate
+            ))
+            .await;
+
+        match output {
+            Ok(out) if out.status.success() => {}
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ git binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("git"));
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!(
+                    "❌ Git step {} ({} {}) failed in {}: {}",
+                    i + 1,
+                    step.remote,
+                    step.branch,
+                    path,
+                    stderr
+                );
+                return Err(DeployError::GitStep(i + 1, output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for git step in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+
+    info!("✅ All {} git steps completed", steps.len());
+    Ok(())
+}
+
+// Submodules run after the checkout is in its final state
+async fn update_submodules(exec: &Exec, path: &str, git_args: &str, git: &GitOptions) -> Result<(), DeployError> {
+    if git.update_submodules {
+        info!("Updating git submodules in {}", path);
+        let submodule_output = exec
+            .sh(&format!(
+                "cd {} && git {} submodule update --init --recursive",
+                path,
+                git_args
+            ))
+            .await;
+
+        match submodule_output {
+            Ok(out) if out.status.success() => {
+                info!("✅ Submodules updated");
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("❌ Submodule update failed in {}: {}", path, stderr);
+                return Err(DeployError::SubmoduleUpdate(output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for submodule update in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Hash of the compose command plus the mtime and content of every file `config` would read
+// (compose files, .env, the env file); None when no compose file is found, so nothing is cached
+fn compose_fingerprint(path: &str, compose: &ComposeOptions) -> Option<String> {
+    use sha2::{Digest, Sha256};
+    let dir = std::path::Path::new(path);
+    let mut compose_files: Vec<String> = if !compose.files.is_empty() {
+        compose.files.clone()
+    } else {
+        match env::var("COMPOSE_FILE").ok().filter(|f| !f.is_empty()) {
+            Some(files) => files.split(':').map(str::to_string).collect(),
+            None => COMPOSE_FILE_NAMES.iter().map(|name| name.to_string()).collect(),
+        }
+    };
+    compose_files.retain(|file| dir.join(file).is_file());
+    if compose_files.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(compose_cmd(compose));
+    let extra = [Some(".env".to_string()), compose.env_file.clone()];
+    for file in compose_files.iter().chain(extra.iter().flatten()) {
+        let full = dir.join(file);
+        let (Ok(meta), Ok(content)) = (std::fs::metadata(&full), std::fs::read(&full)) else {
+            continue;
+        };
+        let mtime = meta.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+        hasher.update(format!("\0{}\0{}\0", full.display(), mtime.as_nanos()));
+        hasher.update(&content);
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+// Parses the compose file (with interpolation) without touching running containers
+async fn compose_preflight(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    if !compose.validate {
+        return Ok(());
+    }
+    let fingerprint = exec.validations.as_ref().and_then(|_| compose_fingerprint(path, compose));
+    if let (Some((project, cache)), Some(fingerprint)) = (&exec.validations, &fingerprint) {
+        if cache.lock().unwrap().get(project) == Some(fingerprint) {
+            info!("Compose config in {} unchanged since it last validated; skipping", path);
+            return Ok(());
+        }
+    }
+    info!("Validating compose config in {}", path);
+    match exec.sh(&format!("cd {} && {} config -q", path, compose_cmd(compose))).await {
+        Ok(out) if out.status.success() => {
+            if let (Some((project, cache)), Some(fingerprint)) = (&exec.validations, fingerprint) {
+                cache.lock().unwrap().insert(project.clone(), fingerprint);
+            }
+            Ok(())
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ docker binary not found in PATH");
+            Err(DeployError::BinaryNotFound("docker"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Compose config is invalid in {}: {}", path, stderr);
+            Err(DeployError::ComposeInvalid(output_tail(&stderr)))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for compose config in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// A zero exit isn't the last word when the project sets failure_pattern / success_pattern
+fn check_output(compose: &ComposeOptions, out: &Output) -> Result<(), DeployError> {
+    let Some(patterns) = &compose.output_patterns else {
+        return Ok(());
+    };
+    let output = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+    match patterns.rejection(&output) {
+        Some(reason) => {
+            error!("❌ Compose succeeded but its {}", reason);
+            Err(DeployError::OutputRejected(reason, output_tail(&output)))
+        }
+        None => Ok(()),
+    }
+}
+
+async fn compose_build_up(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    check_compose_file(path, compose)?;
+    check_env_file(path, compose)?;
+    compose_preflight(exec, path, compose).await?;
+
+    // Best effort: images that only exist locally can't be pulled, and that's fine
+    if compose.pull {
+        info!("Pulling pre-built images in {}", path);
+        match exec
+            .sh(&format!("cd {} && {} pull --ignore-buildable", path, compose_cmd(compose)))
+            .await
+        {
+            Ok(out) if out.status.success() => info!("✅ Pre-built images refreshed"),
+            Ok(out) => warn!(
+                "⚠️ docker compose pull failed in {}; continuing with the build: {}",
+                path,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+            Err(e) => warn!("⚠️ Failed to spawn docker compose pull in {}: {}", path, e),
+        }
+    }
+
+    // 2. Trigger Docker Compose Build and Up
+    let output = match &compose.command {
+        Some(argv) => {
+            info!("Running configured command {:?} in {}", argv, path);
+            exec.run_in(path, argv).await
+        }
+        None if compose.up_retries > 0 => return compose_build_then_up(exec, path, compose).await,
+        None => {
+            let build = if compose.no_build { "" } else { " --build" };
+            if let Some(limit) = compose.build_parallelism.filter(|_| !compose.no_build) {
+                info!("Limiting compose to {} parallel build(s)", limit);
+                exec.set_env("COMPOSE_PARALLEL_LIMIT", &limit.to_string());
+            }
+            info!("Running: docker compose up -d{} in {}", build, path);
+            compose_sh(exec, compose, &format!("cd {} && {} up -d{}{}", path, compose_cmd(compose), build, up_flags(compose)))
+                .await
+        }
+    };
+
+    match output {
+        Ok(out) if out.status.success() => {
+            check_output(compose, &out)?;
+            info!("✅ Container(s) rebuilt and restarted successfully via Docker Compose");
+            Ok(())
+        }
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ docker binary not found in PATH");
+            Err(DeployError::BinaryNotFound("docker"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("Docker Compose build/up failed in {}: {}", path, stderr);
+            let services = failed_services(exec, path, compose, &stderr).await;
+            Err(DeployError::ComposeBuild(output_tail(&stderr), services))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for Docker Compose command in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// `build`, then `up -d` retried with backoff: a failed build is final, a failed up (a base image
+// pull timing out, a volume creation race) is tried again against the images already built
+async fn compose_build_then_up(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    if !compose.no_build {
+        if let Some(limit) = compose.build_parallelism {
+            info!("Limiting compose to {} parallel build(s)", limit);
+            exec.set_env("COMPOSE_PARALLEL_LIMIT", &limit.to_string());
+        }
+        info!("Running: docker compose build in {}", path);
+        match exec.sh(&format!("cd {} && {} build", path, compose_cmd(compose))).await {
+            Ok(out) if out.status.success() => check_output(compose, &out)?,
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ docker binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("docker"));
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("Docker Compose build failed in {}: {}", path, stderr);
+                let services = failed_services(exec, path, compose, &stderr).await;
+                return Err(DeployError::ComposeBuild(output_tail(&stderr), services));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for Docker Compose build in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+
+    let mut attempt = 0;
+    loop {
+        info!("Running: docker compose up -d in {}", path);
+        match compose_sh(exec, compose, &format!("cd {} && {} up -d{}", path, compose_cmd(compose), up_flags(compose))).await {
+            Ok(out) if out.status.success() => {
+                check_output(compose, &out)?;
+                info!("✅ Container(s) rebuilt and restarted successfully via Docker Compose");
+                return Ok(());
+            }
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ docker binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("docker"));
+            }
+            Ok(out) if attempt < compose.up_retries => {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                warn!(
+                    "⚠️ docker compose up failed in {} (attempt {}/{}); retrying in {:.1}s: {}",
+                    path,
+                    attempt,
+                    compose.up_retries + 1,
+                    delay.as_secs_f64(),
+                    String::from_utf8_lossy(&out.stderr).trim()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("Docker Compose up failed in {} after {} attempt(s): {}", path, attempt + 1, stderr);
+                let services = failed_services(exec, path, compose, &stderr).await;
+                return Err(DeployError::ComposeUp(attempt + 1, output_tail(&stderr), services));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for Docker Compose command in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+}
+
+const DEFAULT_DOCKER_LOGIN_RETRIES: u32 = 2;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+const DEFAULT_RETRY_MAX_DELAY_SECS: u64 = 60;
+const DEFAULT_RETRY_JITTER: f64 = 0.5;
+
+// Backoff before retry `attempt` (0-based), used by every retry site: 2s, 4s, 8s, ... capped at
+// RETRY_MAX_DELAY_SECS, then shortened by a random share of up to RETRY_JITTER (0-1) so projects
+// failing together (e.g. a registry outage) don't retry in lockstep
+pub(crate) fn retry_delay(attempt: u32) -> Duration {
+    let max = env::var("RETRY_MAX_DELAY_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_RETRY_MAX_DELAY_SECS);
+    let jitter = env::var("RETRY_JITTER")
+        .ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|j| (0.0..=1.0).contains(j))
+        .unwrap_or(DEFAULT_RETRY_JITTER);
+    let delay = (RETRY_BASE_DELAY * 2u32.saturating_pow(attempt)).min(Duration::from_secs(max));
+    delay.mul_f64(1.0 - jitter * random_fraction())
+}
+
+// Uniform in [0, 1); std's per-instance hasher keys are random enough for spreading retries
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// BUILD_PARALLELISM: build_parallelism for projects that don't set their own; 0 or unset leaves
+// compose's default
+fn default_build_parallelism() -> Option<u32> {
+    env::var("BUILD_PARALLELISM")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+}
+
+// DEPLOY_NICE / DEPLOY_IONICE_CLASS: nice and ionice_class for projects that don't set their
+// own; out-of-range values are ignored with a warning
+fn default_nice() -> Option<i32> {
+    let level = env::var("DEPLOY_NICE").ok().filter(|v| !v.trim().is_empty())?;
+    match level.trim().parse() {
+        Ok(n) if NICE_LEVELS.contains(&n) => Some(n),
+        _ => {
+            warn!("⚠️ Ignoring DEPLOY_NICE={}: use 0 to 19", level);
+            None
+        }
+    }
+}
+
+fn default_ionice_class() -> Option<u8> {
+    let class = env::var("DEPLOY_IONICE_CLASS").ok().filter(|v| !v.trim().is_empty())?;
+    match class.trim().parse() {
+        Ok(c) if IONICE_CLASSES.contains(&c) => Some(c),
+        _ => {
+            warn!("⚠️ Ignoring DEPLOY_IONICE_CLASS={}: use 2 (best-effort) or 3 (idle)", class);
+            None
+        }
+    }
+}
+
+// COMPOSE_UP_RETRIES: see ComposeOptions::up_retries; 0 or unset keeps the single `up --build`
+fn compose_up_retries() -> u32 {
+    env::var("COMPOSE_UP_RETRIES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// DOCKER_LOGIN_RETRIES: extra attempts after a transient login failure
+fn docker_login_retries() -> u32 {
+    env::var("DOCKER_LOGIN_RETRIES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_DOCKER_LOGIN_RETRIES)
+}
+
+// Network trouble and rate limits are worth retrying; a rejected login never is
+fn is_transient_login_error(stderr: &str) -> bool {
+    let stderr = stderr.to_ascii_lowercase();
+    if ["unauthorized", "denied", "incorrect username or password", "authentication required"]
+        .iter()
+        .any(|s| stderr.contains(s))
+    {
+        return false;
+    }
+    [
+        "timeout",
+        "timed out",
+        "connection refused",
+        "connection reset",
+        "no such host",
+        "temporary failure in name resolution",
+        "toomanyrequests",
+        "too many requests",
+        "502 bad gateway",
+        "503 service unavailable",
+        "504 gateway timeout",
+        "eof",
+    ]
+    .iter()
+    .any(|s| stderr.contains(s))
+}
+
+async fn docker_login(
+    exec: &Exec,
+    registry: &str,
+    creds: &Credentials,
+    use_sudo: bool,
+    retries: u32,
+) -> Result<(), DeployError> {
+    let mut attempt = 0;
+    loop {
+        info!("Attempting Docker login to {}", registry);
+        let login_output = exec
+            .sh(&format!(
+                "echo {} | {} login {} -u {} --password-stdin",
+                creds.token,
+                docker_bin(use_sudo),
+                registry,
+                creds.user
+            ))
+            .await;
+
+        match login_output {
+            Ok(out) if out.status.success() => {
+                info!("✅ Docker login successful");
+                return Ok(());
+            }
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ docker binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("docker"));
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                if attempt < retries && is_transient_login_error(&stderr) {
+                    let delay = retry_delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        "⚠️ Docker login to {} failed transiently ({}); retry {}/{} in {:.1}s",
+                        registry,
+                        stderr.trim(),
+                        attempt,
+                        retries,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                error!("❌ Docker login failed for {}: {}", registry, stderr);
+                return Err(DeployError::DockerLogin(output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for docker login: {}", e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+}
+
+// Images the compose file references, as `docker compose config --images` resolves them
+async fn compose_images(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<Vec<String>, DeployError> {
+    match exec.sh(&format!("cd {} && {} config --images", path, compose_cmd(compose))).await {
+        Ok(out) if out.status.success() => Ok(String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|image| !image.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ docker binary not found in PATH");
+            Err(DeployError::BinaryNotFound("docker"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("❌ Could not list compose images in {}: {}", path, stderr);
+            Err(DeployError::ComposeInvalid(output_tail(&stderr)))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for compose config in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// `cosign verify` each image before anything is pulled; the first unsigned one aborts the deploy
+async fn verify_images(exec: &Exec, path: &str, compose: &ComposeOptions, policy: &ImagePolicy) -> Result<(), DeployError> {
+    let policy_args: Vec<&str> = match policy {
+        ImagePolicy::Key(key) => vec!["--key", key],
+        ImagePolicy::Keyless { identity, issuer } => {
+            vec!["--certificate-identity", identity, "--certificate-oidc-issuer", issuer]
+        }
+    };
+    for image in compose_images(exec, path, compose).await? {
+        info!("Verifying the signature of {}", image);
+        let mut args = vec!["verify"];
+        args.extend(&policy_args);
+        args.push(&image);
+        match exec.run("cosign", &args).await {
+            Ok(out) if out.status.success() => {}
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ cosign binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("cosign"));
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("❌ Signature verification failed for {}: {}", image, stderr);
+                return Err(DeployError::SignatureInvalid(image, output_tail(&stderr)));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                error!("❌ cosign binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("cosign"));
+            }
+            Err(e) => {
+                error!("Failed to spawn cosign for {}: {}", image, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+    info!("✅ All images in {} carry valid signatures", path);
+    Ok(())
+}
+
+pub async fn deploy_docker(
+    exec: &Exec,
+    path: &str,
+    registry: &str,
+    creds: &Credentials,
+    compose: &ComposeOptions,
+) -> DeployOutcome {
+    if let Some(tag) = &compose.image_tag {
+        info!("Pulling image tag {} (exported as TAG)", tag);
+        exec.set_env("TAG", tag);
+    }
+
+    // 1. Handle Authentication
+    exec.phase("login", "started");
+    docker_login(exec, registry, creds, compose.use_sudo, docker_login_retries()).await?;
+    exec.phase("login", "done");
+
+    // 2. Trigger Docker Compose with --pull always
+    check_compose_file(path, compose)?;
+    check_env_file(path, compose)?;
+    compose_preflight(exec, path, compose).await?;
+    if let Some(policy) = &compose.verify_images {
+        verify_images(exec, path, compose, policy).await?;
+    }
+    exec.phase("up", "started");
+    let span = info_span!("compose_up", duration_ms = field::Empty, outcome = field::Empty);
+    traced(span, compose_pull_up(exec, path, compose)).await?;
+    exec.phase("up", "done");
+    info!("✅ Container(s) updated and restarted successfully via Docker Compose");
+    run_post_up(exec, path, compose).await?;
+    wait_healthy(exec, path, compose).await?;
+    let smoke_test = run_smoke_test(exec, path, compose).await;
+    Ok(DeployReport {
+        message: "Success: Images Pulled and Containers Restarted",
+        timings: None,
+        services: compose_services(exec, path, compose).await,
+        commit: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test,
+    })
+}
+
+// Checks registry credentials on their own: the login image mode starts with, and nothing else
+async fn deploy_login(exec: &Exec, registry: &str, creds: &Credentials, use_sudo: bool) -> DeployOutcome {
+    exec.phase("login", "started");
+    docker_login(exec, registry, creds, use_sudo, docker_login_retries()).await?;
+    exec.phase("login", "done");
+    Ok(DeployReport {
+        message: "Success: Registry Login Verified",
+        timings: None,
+        services: None,
+        commit: None,
+        registry_login: Some(registry.to_string()),
+        login_user: Some(creds.user.clone()),
+        smoke_test: None,
+    })
+}
+
+// Image mode's `up -d --pull always` (or the configured command)
+async fn compose_pull_up(exec: &Exec, path: &str, compose: &ComposeOptions) -> Result<(), DeployError> {
+    let output = match &compose.command {
+        Some(argv) => {
+            info!("Running configured command {:?} in {}", argv, path);
+            exec.run_in(path, argv).await
+        }
+        None => {
+            info!("Running: docker compose up -d --pull always in {}", path);
+            compose_sh(exec, compose, &format!("cd {} && {} up -d --pull always{}", path, compose_cmd(compose), up_flags(compose)))
+                .await
+        }
+    };
+
+    match output {
+        Ok(out) if out.status.success() => check_output(compose, &out),
+        Ok(out) if is_command_not_found(&out.status) => {
+            error!("❌ docker binary not found in PATH");
+            Err(DeployError::BinaryNotFound("docker"))
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            error!("Docker Compose failed in {}: {}", path, stderr);
+            let services = failed_services(exec, path, compose, &stderr).await;
+            Err(DeployError::ComposePull(output_tail(&stderr), services))
+        }
+        Err(e) => {
+            error!("Failed to spawn shell for Docker Compose command in {}: {}", path, e);
+            Err(DeployError::Spawn(e.to_string()))
+        }
+    }
+}
+
+// " --timeout <secs>" for stop/down/restart when the project sets stop_timeout
+fn stop_flags(compose: &ComposeOptions) -> String {
+    compose
+        .stop_timeout
+        .map(|secs| format!(" --timeout {}", secs))
+        .unwrap_or_default()
+}
+
+// Runs compose subcommands in order, stopping at the first failure
+async fn compose_steps(
+    exec: &Exec,
+    path: &str,
+    compose: &ComposeOptions,
+    steps: &[String],
+    failed: impl Fn(String) -> DeployError,
+) -> Result<(), DeployError> {
+    for step in steps {
+        info!("Running: docker compose {} in {}", step, path);
+        match compose_sh(exec, compose, &format!("cd {} && {} {}", path, compose_cmd(compose), step)).await {
+            Ok(out) if out.status.success() => {}
+            Ok(out) if is_command_not_found(&out.status) => {
+                error!("❌ docker binary not found in PATH");
+                return Err(DeployError::BinaryNotFound("docker"));
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("Docker Compose {} failed in {}: {}", step, path, stderr);
+                return Err(failed(output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for Docker Compose command in {}: {}", path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// RECREATE_DELAY_MS: pause between `down` and `up` so the OS releases ports and networks first
+fn recreate_delay() -> Duration {
+    env::var("RECREATE_DELAY_MS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::ZERO)
+}
+
+// Restarts the stack as-is: no git, no registry, no build
+pub async fn deploy_restart(exec: &Exec, path: &str, compose: &ComposeOptions) -> DeployOutcome {
+    check_compose_file(path, compose)?;
+    check_env_file(path, compose)?;
+    if compose.recreate_on_restart {
+        let down = format!("down{}", stop_flags(compose));
+        compose_steps(exec, path, compose, &[down], DeployError::ComposeRestart).await?;
+        let delay = recreate_delay();
+        if !delay.is_zero() && !exec.dry_run {
+            info!("Waiting {}ms before recreating containers", delay.as_millis());
+            tokio::time::sleep(delay).await;
+        }
+        let up = format!("up -d{}", up_flags(compose));
+        compose_steps(exec, path, compose, &[up], DeployError::ComposeRestart).await?;
+    } else {
+        let restart = format!("restart{}", stop_flags(compose));
+        compose_steps(exec, path, compose, &[restart], DeployError::ComposeRestart).await?;
+    }
+
+    info!("✅ Container(s) restarted via Docker Compose");
+    Ok(DeployReport {
+        message: "Success: Containers Restarted",
+        timings: None,
+        services: compose_services(exec, path, compose).await,
+        commit: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+    })
+}
+
+// Cheapest way back to running: `start` when the stack's containers exist (even stopped),
+// `up -d` when there are none yet, e.g. after a `down`
+pub async fn deploy_wake(exec: &Exec, path: &str, compose: &ComposeOptions) -> DeployOutcome {
+    check_compose_file(path, compose)?;
+    check_env_file(path, compose)?;
+    let existing = match exec.sh(&format!("cd {} && {} ps -a -q", path, compose_cmd(compose))).await {
+        Ok(out) if out.status.success() => !String::from_utf8_lossy(&out.stdout).trim().is_empty(),
+        // Let `up` report whatever is wrong
+        _ => false,
+    };
+    let step = if existing {
+        "start".to_string()
+    } else {
+        info!("No containers exist in {}; creating them", path);
+        format!("up -d{}", up_flags(compose))
+    };
+    compose_steps(exec, path, compose, &[step], DeployError::ComposeRestart).await?;
+
+    info!("✅ Stack in {} is up", path);
+    Ok(DeployReport {
+        message: if existing { "Success: Containers Started" } else { "Success: Containers Created" },
+        timings: None,
+        services: compose_services(exec, path, compose).await,
+        commit: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+    })
+}
+
+// `stop` keeps containers around for a quick start; `down` removes them (and volumes if asked)
+pub async fn deploy_stop(exec: &Exec, path: &str, compose: &ComposeOptions, down: bool) -> DeployOutcome {
+    check_compose_file(path, compose)?;
+    check_env_file(path, compose)?;
+    let step = match (down, compose.remove_volumes) {
+        (false, _) => format!("stop{}", stop_flags(compose)),
+        (true, false) => format!("down{}", stop_flags(compose)),
+        (true, true) => {
+            warn!("⚠️ Removing volumes of {}", path);
+            format!("down --volumes{}", stop_flags(compose))
+        }
+    };
+    compose_steps(exec, path, compose, &[step], DeployError::ComposeStop).await?;
+
+    info!("✅ Stack in {} {}", path, if down { "brought down" } else { "stopped" });
+    Ok(DeployReport {
+        message: if down { "Success: Stack Brought Down" } else { "Success: Containers Stopped" },
+        timings: None,
+        services: compose_services(exec, path, compose).await,
+        commit: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+    })
+}
+
+// Removes a PR preview (see DeployJob::for_pull_request): its containers and volumes, then its
+// worktree. Safe to repeat; a preview that was never deployed has nothing to remove.
+pub async fn deploy_teardown(exec: &Exec, job: &DeployJob) -> DeployOutcome {
+    let Some(pr) = job.git.pr_number else {
+        return Err(DeployError::InvalidMode("teardown without a pr_number".to_string()));
+    };
+    let checkout = pr_checkout(&job.repo_path, pr);
+    let _lock = if exec.dry_run { None } else { lock_checkout(&job.repo_path).await };
+
+    // Compose finds the containers by project name, so the checkout only matters for interpolation
+    let dir = if std::path::Path::new(&job.path).is_dir() { &job.path } else { &job.repo_path };
+    warn!("⚠️ Tearing down preview of PR #{} ({}) with its volumes", pr, job.project_id);
+    let step = format!("down --volumes --remove-orphans{}", stop_flags(&job.compose));
+    compose_steps(exec, dir, &job.compose, &[step], DeployError::ComposeStop).await?;
+
+    if std::path::Path::new(&checkout).exists() {
+        info!("Removing worktree {}", checkout);
+        match exec
+            .sh(&format!("cd {} && git worktree remove --force {} && git worktree prune", job.repo_path, shell_quote(&checkout)))
+            .await
+        {
+            Ok(out) if out.status.success() => {}
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                error!("❌ git worktree remove failed in {}: {}", job.repo_path, stderr);
+                return Err(DeployError::GitPull(output_tail(&stderr)));
+            }
+            Err(e) => {
+                error!("Failed to spawn shell for git worktree in {}: {}", job.repo_path, e);
+                return Err(DeployError::Spawn(e.to_string()));
+            }
+        }
+    }
+
+    info!("✅ Preview of PR #{} removed", pr);
+    Ok(DeployReport {
+        message: "Success: Preview Torn Down",
+        timings: None,
+        services: None,
+        commit: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    // Records every command; the first rule whose pattern appears in a command decides its result
+    #[derive(Default)]
+    struct MockRunner {
+        rules: Vec<(&'static str, i32, &'static str)>,
+        // (pattern, stdout) for commands whose output the deploy reads
+        stdout: Vec<(&'static str, &'static str)>,
+        delay: Option<Duration>,
+        calls: Mutex<Vec<String>>,
+        // Environment each call was given, in call order
+        envs: Mutex<Vec<Vec<(String, String)>>>,
+    }
+
+    impl MockRunner {
+        fn failing(pattern: &'static str, code: i32, stderr: &'static str) -> Self {
+            MockRunner {
+                rules: vec![(pattern, code, stderr)],
+                ..Default::default()
+            }
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run<'a>(
+            &'a self,
+            program: &'a str,
+            args: &'a [&'a str],
+            _dir: Option<&'a str>,
+            env: &'a CommandEnv,
+        ) -> CommandFuture<'a> {
+            Box::pin(async move {
+                let line = format!("{} {}", program, args.join(" "));
+                self.calls.lock().unwrap().push(line.clone());
+                self.envs.lock().unwrap().push(env.vars.clone());
+                if let Some(delay) = self.delay {
+                    tokio::time::sleep(delay).await;
+                }
+                let (code, stderr) = self
+                    .rules
+                    .iter()
+                    .find(|(pattern, _, _)| line.contains(pattern))
+                    .map(|(_, code, stderr)| (*code, *stderr))
+                    .unwrap_or((0, ""));
+                let stdout = self
+                    .stdout
+                    .iter()
+                    .find(|(pattern, _)| line.contains(pattern))
+                    .map_or("", |(_, stdout)| *stdout);
+                Ok(Output {
+                    status: std::process::ExitStatus::from_raw(code << 8),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: stderr.as_bytes().to_vec(),
+                })
+            })
+        }
+    }
+
+    fn job(mode: &str, with_credentials: bool) -> DeployJob {
+        let payload: WebhookPayload = serde_json::from_value(if with_credentials {
+            serde_json::json!({ "project": "app", "type": mode, "token": "s3cret", "user": "bot", "registry": "ghcr.io" })
+        } else {
+            serde_json::json!({ "project": "app", "type": mode })
+        })
+        .unwrap();
+        let mut job = DeployJob::from_entry("app", &ProjectEntry::Path("/srv/app".to_string()), mode);
+        job.payload = Some(payload);
+        job
+    }
+
+    async fn run(job: &DeployJob, runner: &Arc<MockRunner>, timeout: Option<Duration>) -> (DeployOutcome, Vec<String>) {
+        let exec = Arc::new(Exec::new(runner.clone()));
+        let result = run_job(job, Arc::new(Metrics::default()), exec.clone(), timeout).await;
+        (result, exec.commands())
+    }
+
+    #[tokio::test]
+    async fn plan_fetches_without_resetting_and_lists_the_deploy_commands() {
+        let runner = Arc::new(MockRunner {
+            stdout: vec![
+                ("rev-parse HEAD", "1111111111111111\n"),
+                ("rev-parse FETCH_HEAD", "2222222222222222\n"),
+                ("rev-list --count", "3\n"),
+            ],
+            ..Default::default()
+        });
+        let plan = plan_job(&job("repo", true), runner.clone()).await;
+
+        let calls = runner.calls.lock().unwrap().clone();
+        assert_eq!(calls.len(), 4);
+        assert!(calls[0].contains("fetch origin main") && !calls[0].contains("reset"));
+        assert_eq!(plan.current_commit.as_deref(), Some("111111111111"));
+        assert_eq!(plan.target_commit.as_deref(), Some("222222222222"));
+        assert_eq!(plan.new_commits, Some(3));
+        assert_eq!(plan.error, None);
+        assert!(plan.commands[0].contains("reset --hard origin/main"));
+        assert!(plan.commands.iter().any(|c| c.contains("up -d --build")));
+        assert!(!serde_json::to_string(&plan).unwrap().contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn pull_request_previews_build_in_their_own_worktree_and_compose_project() {
+        let mut job = job("repo", true);
+        job.path = "/srv/app/deploy".to_string();
+        let job = job.for_pull_request(42);
+        assert_eq!(job.project_id, "app-pr-42");
+        assert_eq!(job.path, "/srv/app.previews/pr-42/deploy");
+        assert_eq!(job.rollback_limit, 0);
+
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(commands[0].starts_with("cd /srv/app && git"));
+        assert!(commands[0].contains("fetch origin pull/42/head && git worktree add --force --detach '/srv/app.previews/pr-42'"));
+        assert_eq!(commands[1], "git -C /srv/app.previews/pr-42 rev-parse HEAD");
+        assert!(commands[2].contains("cd /srv/app.previews/pr-42/deploy && docker compose --ansi never -p app-pr-42 up -d --build"));
+    }
+
+    #[tokio::test]
+    async fn teardown_removes_the_preview_containers_and_volumes() {
+        let job = job("teardown", false).for_pull_request(42);
+        let (result, commands) = run(&job, &Arc::new(MockRunner::default()), None).await;
+
+        assert_eq!(result.unwrap().message, "Success: Preview Torn Down");
+        assert_eq!(commands, ["cd /srv/app && docker compose --ansi never -p app-pr-42 down --volumes --remove-orphans"]);
+
+        let (result, _) = run(&self::job("teardown", false), &Arc::new(MockRunner::default()), None).await;
+        assert!(matches!(result, Err(DeployError::InvalidMode(_))));
+    }
+
+    #[tokio::test]
+    async fn output_patterns_fail_a_deploy_that_exited_zero() {
+        let runner = Arc::new(MockRunner {
+            stdout: vec![("up -d", "web  | FATAL: could not bind port\n")],
+            ..Default::default()
+        });
+        let mut job = job("image", true);
+        job.compose.output_patterns = Some(OutputPatterns {
+            failure: Some(regex::Regex::new("FATAL|panic").unwrap()),
+            success: None,
+        });
+        let (result, _) = run(&job, &runner, None).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.to_string(), "Compose exited 0, but its output matched failure_pattern `FATAL|panic`");
+        assert_eq!(err.stderr_tail(), Some("web  | FATAL: could not bind port"));
+
+        job.compose.output_patterns = Some(OutputPatterns {
+            failure: None,
+            success: Some(regex::Regex::new("Started").unwrap()),
+        });
+        let (result, _) = run(&job, &runner, None).await;
+        assert!(matches!(result, Err(DeployError::OutputRejected(..))));
+    }
+
+    #[tokio::test]
+    async fn unsigned_image_aborts_before_up() {
+        let runner = Arc::new(MockRunner {
+            rules: vec![("cosign verify --key /etc/cosign.pub redis:7", 1, "no matching signatures")],
+            stdout: vec![("config --images", "ghcr.io/org/app:1\nredis:7\n")],
+            ..Default::default()
+        });
+        let mut job = job("image", true);
+        job.compose.verify_images = Some(ImagePolicy::Key("/etc/cosign.pub".to_string()));
+        let (result, commands) = run(&job, &runner, None).await;
+        match result {
+            Err(DeployError::SignatureInvalid(image, output)) => {
+                assert_eq!(image, "redis:7");
+                assert_eq!(output, "no matching signatures");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(commands.iter().any(|c| c == "cosign verify --key /etc/cosign.pub ghcr.io/org/app:1"));
+        assert!(!commands.iter().any(|c| c.contains("up -d")));
+    }
+
+    #[tokio::test]
+    async fn inline_override_is_layered_over_the_default_file_and_validated() {
+        let dir = env::temp_dir().join(format!("graft-hook-override-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("compose.yaml"), "services: {}\n").unwrap();
+        std::fs::write(dir.join("compose.override.yaml"), "services: {}\n").unwrap();
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("image", true);
+        job.path = dir.display().to_string();
+        job.compose.inline_override = Some("services:\n  web:\n    image: app:9\n".to_string());
+        let exec = Arc::new(Exec::new(runner.clone()));
+        let result = run_job(&job, Arc::new(Metrics::default()), exec.clone(), None).await;
+        let commands = exec.commands();
+
+        assert!(result.is_ok());
+        let up = commands.iter().find(|c| c.contains("up -d --pull always")).unwrap();
+        let written = up.split(" -f '").nth(3).and_then(|rest| rest.split('\'').next()).map(std::path::PathBuf::from).unwrap();
+        assert!(written.ends_with("app.override.yml"));
+        assert_eq!(std::fs::read_to_string(&written).unwrap(), "services:\n  web:\n    image: app:9\n");
+        let dir_mode = std::fs::metadata(written.parent().unwrap()).unwrap().permissions();
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&dir_mode) & 0o777, 0o700);
+        let files = format!("-f 'compose.yaml' -f 'compose.override.yaml' -f '{}'", written.display());
+        assert!(commands.iter().any(|c| c.contains(&files) && c.ends_with("config -q")));
+        drop(exec);
+        assert!(!written.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn image_tag_is_exported_to_compose() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("image", true);
+        job.compose.image_tag = Some("v2.3.1".to_string());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        let up = commands.iter().position(|c| c.contains("up -d --pull always")).unwrap();
+        let env = &runner.envs.lock().unwrap()[up];
+        assert!(env.iter().any(|(name, value)| name == "TAG" && value == "v2.3.1"));
+    }
+
+    #[tokio::test]
+    async fn ssh_host_points_compose_at_the_remote_engine_with_the_configured_key() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("image", true);
+        job.project_id = "ssh-remote".to_string();
+        job.compose.ssh_host = Some("deploy@web-2".to_string());
+        job.compose.ssh_key = Some("/etc/graft/id_ed25519".to_string());
+        let exec = Arc::new(Exec::new(runner.clone()));
+        let result = run_job(&job, Arc::new(Metrics::default()), exec.clone(), None).await;
+        let commands = exec.commands();
+
+        assert!(result.is_ok());
+        let up = commands.iter().position(|c| c.contains("up -d --pull always")).unwrap();
+        let env = runner.envs.lock().unwrap()[up].clone();
+        assert!(env.iter().any(|(name, value)| name == "DOCKER_HOST" && value == "ssh://deploy@web-2"));
+        let (_, path) = env.iter().find(|(name, _)| name == "PATH").unwrap();
+        let dir = std::path::PathBuf::from(path.split(':').next().unwrap());
+        assert!(dir.file_name().unwrap().to_str().unwrap().starts_with("graft-hook-ssh-"));
+        let dir_mode = std::fs::metadata(&dir).unwrap().permissions();
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&dir_mode) & 0o777, 0o700);
+        let wrapper = std::fs::read_to_string(dir.join("ssh")).unwrap();
+        assert!(wrapper.contains("exec ssh -i '/etc/graft/id_ed25519' -o IdentitiesOnly=yes"));
+        drop(exec);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn readiness_reports_missing_credentials_and_directories() {
+        let checks = readiness_checks(&job("image", false));
+        assert_eq!(checks.len(), 2);
+        assert_eq!((checks[0].check, checks[0].ok), ("credentials", false));
+        assert_eq!((checks[1].check, checks[1].ok), ("compose_path", false));
+        assert_eq!(checks[1].detail, "/srv/app does not exist");
+
+        let checks = readiness_checks(&job("repo", true));
+        assert_eq!((checks[0].ok, checks[0].detail.as_str()), (true, "git as bot"));
+        assert_eq!((checks[1].check, checks[1].ok), ("repo_path", false));
+    }
+
+    #[tokio::test]
+    async fn build_parallelism_limits_compose_builds() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("repo", true);
+        job.compose.build_parallelism = Some(1);
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(commands[2].contains("up -d --build"));
+        let env = &runner.envs.lock().unwrap()[2];
+        assert!(env.iter().any(|(name, value)| name == "COMPOSE_PARALLEL_LIMIT" && value == "1"));
+    }
+
+    #[tokio::test]
+    async fn credential_provider_token_is_used_and_redacted() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("repo", false);
+        job.credentials = Some(serde_json::from_value(serde_json::json!({
+            "provider": "command", "command": ["echo", "vault-token"], "user": "deploy"
+        })).unwrap());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        let fetch = &runner.calls.lock().unwrap()[0];
+        assert!(fetch.contains("echo username=deploy; echo password=vault-token;"));
+        assert!(commands[0].contains("echo password=***;") && !commands[0].contains("vault-token"));
+    }
+
+    #[tokio::test]
+    async fn rewound_branch_is_refused_and_the_checkout_restored() {
+        let runner = Arc::new(MockRunner {
+            rules: vec![("merge-base --is-ancestor", 1, "")],
+            stdout: vec![("rev-parse HEAD", "1111111111111111\n")],
+            ..Default::default()
+        });
+        let mut job = job("repo", true);
+        job.git.require_fast_forward = true;
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::NotFastForward(..))));
+        assert!(commands.iter().any(|c| c == "git -C /srv/app merge-base --is-ancestor 1111111111111111 1111111111111111"));
+        assert_eq!(commands.last().unwrap(), "git -C /srv/app reset --hard 1111111111111111");
+        assert!(!commands.iter().any(|c| c.contains("up -d")));
+    }
+
+    #[tokio::test]
+    async fn known_commit_is_reset_to_without_fetching() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("repo", true);
+        job.git.commit = Some("3f2a9c1e0b7d".to_string());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert_eq!(commands[0], "cd /srv/app && git reset --hard 3f2a9c1e0b7d");
+        assert!(!commands.iter().any(|c| c.contains("fetch")));
+    }
+
+    #[tokio::test]
+    async fn repo_deploy_pulls_then_rebuilds() {
+        let runner = Arc::new(MockRunner {
+            stdout: vec![("log -1", "3f2a9c1\x1fAda Lovelace\x1fFix: handle empty payloads\x1f1700000000\n")],
+            ..Default::default()
+        });
+        let (result, commands) = run(&job("repo", true), &runner, None).await;
+
+        let report = result.unwrap();
+        assert_eq!(report.message, "Success: Repo Pulled and Containers Rebuilt");
+        assert_eq!(
+            report.commit,
+            Some(CommitInfo {
+                sha: "3f2a9c1".to_string(),
+                author: "Ada Lovelace".to_string(),
+                subject: "Fix: handle empty payloads".to_string(),
+                timestamp: 1700000000,
+            })
+        );
+        assert_eq!(commands.len(), 5);
+        assert!(commands[0].contains("fetch origin main") && commands[0].contains("reset --hard origin/main"));
+        assert_eq!(commands[1], "git -C /srv/app rev-parse HEAD");
+        assert!(commands[2].contains("docker compose --ansi never up -d --build"));
+        assert!(commands[3].contains("docker compose --ansi never ps --format json"));
+        assert!(commands[4].starts_with("git -C /srv/app log -1"));
+    }
+
+    #[tokio::test]
+    async fn progress_reports_each_phase_as_it_finishes() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let exec = Arc::new(Exec::new(Arc::new(MockRunner::default())).with_progress(Some(tx)));
+        let result = run_job(&job("repo", true), Arc::new(Metrics::default()), exec, None).await;
+        assert!(result.is_ok());
+
+        let mut phases = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            phases.push(format!("{} {}", event["phase"].as_str().unwrap(), event["status"].as_str().unwrap()));
+        }
+        assert_eq!(phases, ["git started", "git done", "build started", "build done"]);
+    }
+
+    #[test]
+    fn compose_ps_output_is_parsed_in_both_formats() {
+        let lines = concat!(
+            r#"{"Service":"web","State":"running","Health":"healthy"}"#,
+            "\n",
+            r#"{"Service":"worker","State":"exited","Health":""}"#,
+        );
+        let array = r#"[{"Service":"web","State":"running","Health":"healthy"},{"Service":"worker","State":"exited"}]"#;
+        for stdout in [lines, array] {
+            let services = parse_compose_ps(stdout).unwrap();
+            assert_eq!(services.len(), 2);
+            assert_eq!(services[0].health.as_deref(), Some("healthy"));
+            assert_eq!((services[1].state.as_str(), services[1].health.as_ref()), ("exited", None));
+        }
+        assert_eq!(parse_compose_ps("NAME  SERVICE  STATUS"), None);
+    }
+
+    #[test]
+    fn container_sock_becomes_a_unix_docker_host() {
+        let sock = "/run/user/1000/podman/podman.sock";
+        assert_eq!(engine_host(None, Some(sock)).as_deref(), Some("unix:///run/user/1000/podman/podman.sock"));
+        assert_eq!(engine_host(Some(" "), Some("tcp://10.0.0.5:2375")).as_deref(), Some("tcp://10.0.0.5:2375"));
+        assert_eq!(engine_host(Some("ssh://deploy@box"), Some(sock)).as_deref(), Some("ssh://deploy@box"));
+        assert_eq!(engine_host(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn pull_deploy_skips_docker() {
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job("pull", true), &runner, None).await;
+
+        assert_eq!(result.unwrap().message, "Success: Repo Pulled");
+        assert!(commands[0].contains("reset --hard origin/main"));
+        assert!(commands.iter().all(|c| !c.contains("docker")));
+    }
+
+    #[tokio::test]
+    async fn recorded_commands_redact_the_token() {
+        let runner = Arc::new(MockRunner::default());
+        let (_, commands) = run(&job("repo", true), &runner, None).await;
+
+        assert!(commands.iter().all(|c| !c.contains("s3cret")));
+        assert!(commands[0].contains("password=***"));
+        // The real command still carries the credential
+        assert!(runner.calls.lock().unwrap()[0].contains("password=s3cret"));
+    }
+
+    #[tokio::test]
+    async fn tag_deploy_resets_to_the_tag() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("repo", true);
+        job.git.tag = Some("v1.4.0".to_string());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(commands[0].contains("fetch --force origin tag v1.4.0"));
+        assert!(commands[0].contains("reset --hard refs/tags/v1.4.0"));
+        assert!(!commands[0].contains("origin main"));
+    }
+
+    #[tokio::test]
+    async fn missing_primary_branch_falls_back_but_auth_failures_do_not() {
+        let mut job = job("pull", true);
+        job.git.fallback_branch = Some("trunk".to_string());
+        let runner = Arc::new(MockRunner::failing("origin main", 128, "fatal: couldn't find remote ref main"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(commands.iter().any(|c| c.contains("fetch origin trunk") && c.ends_with("reset --hard origin/trunk")));
+
+        let runner = Arc::new(MockRunner::failing("origin main", 128, "fatal: Authentication failed for 'https://example.com/'"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(matches!(result, Err(DeployError::GitPull(_))));
+        assert!(!commands.iter().any(|c| c.contains("trunk")));
+    }
+
+    #[tokio::test]
+    async fn git_failure_stops_before_compose() {
+        let runner = Arc::new(MockRunner::failing("fetch", 128, "fatal: repository not found"));
+        let (result, commands) = run(&job("repo", true), &runner, None).await;
+
+        let err = result.unwrap_err();
+        assert_eq!(err.to_string(), "Git Pull Failed");
+        assert_eq!(err.stderr_tail(), Some("fatal: repository not found"));
+        assert_eq!(commands.len(), 1);
+    }
+
+    #[test]
+    fn payload_toggles_map_to_up_flags() {
+        let mut compose = ComposeOptions::default();
+        for name in crate::config::COMPOSE_TOGGLES {
+            compose.set_toggle(name, true);
+        }
+        assert!(compose.no_build && compose.pull);
+        assert_eq!(up_flags(&compose), " --remove-orphans --force-recreate");
+    }
+
+    #[tokio::test]
+    async fn up_waits_for_health_and_drops_wait_on_an_old_compose() {
+        let mut job = job("image", true);
+        job.compose.wait = Some(Some(90));
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        assert!(commands.iter().any(|c| c.ends_with("up -d --pull always --wait --wait-timeout 90")));
+
+        let runner = Arc::new(MockRunner::failing("--wait", 1, "unknown flag: --wait"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        let ups: Vec<_> = commands.iter().filter(|c| c.contains("up -d")).collect();
+        assert_eq!(ups.len(), 2);
+        assert!(ups[1].ends_with("up -d --pull always"));
+    }
+
+    #[tokio::test]
+    async fn stop_timeout_is_passed_to_up() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("repo", true);
+        job.compose.stop_timeout = Some(45);
+        let (_, commands) = run(&job, &runner, None).await;
+
+        assert!(commands[2].contains("up -d --build --timeout 45"));
+    }
+
+    #[tokio::test]
+    async fn configured_command_runs_without_a_shell() {
+        let runner = Arc::new(MockRunner {
+            stdout: vec![("rev-parse HEAD", "3f2a9c1e0b7d\n")],
+            ..Default::default()
+        });
+        let mut job = job("repo", true);
+        job.compose.command = Some(vec!["make".to_string(), "deploy; rm -rf /".to_string()]);
+        let (result, _) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        let calls = runner.calls.lock().unwrap();
+        assert_eq!(calls[2], "make deploy; rm -rf /");
+        assert!(calls[0].starts_with("sh -c"));
+
+        // The hook sees the deploy context, including the commit the sync checked out
+        let env = &runner.envs.lock().unwrap()[2];
+        let var = |name: &str| env.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        assert_eq!(var("GRAFT_PROJECT"), Some("app"));
+        assert_eq!(var("GRAFT_BRANCH"), Some("main"));
+        assert_eq!(var("GRAFT_COMMIT"), Some("3f2a9c1e0b7d"));
+        assert_eq!(var("GRAFT_USER"), Some("bot"));
+    }
+
+    #[tokio::test]
+    async fn repo_deploy_waits_for_the_checkout_lock() {
+        let dir = env::temp_dir().join(format!("graft-hook-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let held = std::fs::File::create(dir.join(CHECKOUT_LOCK_FILE)).unwrap();
+        flock(&held, libc::LOCK_EX).unwrap();
+
+        let mut job = job("repo", true);
+        job.repo_path = dir.display().to_string();
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, Some(Duration::from_millis(200))).await;
+        assert!(matches!(result, Err(DeployError::TimedOut(_))));
+        assert!(commands.is_empty());
+
+        drop(held);
+        let (result, _) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restart_needs_no_credentials_and_skips_git() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("restart", false);
+        let (result, commands) = run(&job, &runner, None).await;
+        assert_eq!(result.unwrap().message, "Success: Containers Restarted");
+        assert!(commands[0].ends_with("docker compose --ansi never restart"));
+        assert!(!commands.iter().any(|c| c.contains("git")));
+
+        job.compose.recreate_on_restart = true;
+        job.compose.stop_timeout = Some(20);
+        let (_, commands) = run(&job, &runner, None).await;
+        assert!(commands[0].ends_with("docker compose --ansi never down --timeout 20"));
+        assert!(commands[1].ends_with("docker compose --ansi never up -d --timeout 20"));
+    }
+
+    #[tokio::test]
+    async fn wake_starts_existing_containers_and_creates_missing_ones() {
+        let runner = Arc::new(MockRunner {
+            stdout: vec![("ps -a -q", "3f2a9c1e0b7d\n")],
+            ..Default::default()
+        });
+        let (result, commands) = run(&job("wake", false), &runner, None).await;
+        assert_eq!(result.unwrap().message, "Success: Containers Started");
+        assert!(commands[1].ends_with("docker compose --ansi never start"));
+
+        let (result, commands) = run(&job("wake", false), &Arc::new(MockRunner::default()), None).await;
+        assert_eq!(result.unwrap().message, "Success: Containers Created");
+        assert!(commands[1].ends_with("docker compose --ansi never up -d"));
+    }
+
+    #[tokio::test]
+    async fn down_removes_volumes_only_when_asked() {
+        let runner = Arc::new(MockRunner::default());
+        let mut down = job("down", false);
+        let (result, commands) = run(&down, &runner, None).await;
+        assert_eq!(result.unwrap().message, "Success: Stack Brought Down");
+        assert!(commands[0].ends_with("docker compose --ansi never down"));
+
+        down.compose.remove_volumes = true;
+        let (_, commands) = run(&down, &runner, None).await;
+        assert!(commands[0].ends_with("docker compose --ansi never down --volumes"));
+
+        let (result, commands) = run(&job("stop", false), &runner, None).await;
+        assert_eq!(result.unwrap().message, "Success: Containers Stopped");
+        assert!(commands[0].ends_with("docker compose --ansi never stop"));
+    }
+
+    #[test]
+    fn registry_credentials_come_from_the_matching_entry() {
+        let registries: BTreeMap<String, RegistryCredentials> = serde_json::from_value(serde_json::json!({
+            "ghcr.io": { "user": "gh-bot", "token": "gh-token" },
+            "registry.example.com": { "user": "ex-bot", "token": "ex-token" },
+        }))
+        .unwrap();
+        let payload = |registry: &str| -> WebhookPayload {
+            serde_json::from_value(serde_json::json!({ "project": "app", "type": "image", "registry": registry })).unwrap()
+        };
+
+        let project_default = RegistryCredentials {
+            user: "app-bot".to_string(),
+            token: "app-token".to_string(),
+        };
+        let (_, creds) = docker_credentials(Some(&payload("Registry.Example.com")), &registries, Some(&project_default)).unwrap();
+        assert_eq!((creds.user.as_str(), creds.token.as_str()), ("ex-bot", "ex-token"));
+
+        let mut explicit = payload("ghcr.io");
+        explicit.token = Some("payload-token".to_string());
+        let (_, creds) = docker_credentials(Some(&explicit), &registries, None).unwrap();
+        assert_eq!((creds.user.as_str(), creds.token.as_str()), ("gh-bot", "payload-token"));
+
+        // Registries without an entry fall back to the project's docker_credentials
+        let (_, creds) = docker_credentials(Some(&payload("quay.io")), &registries, Some(&project_default)).unwrap();
+        assert_eq!((creds.user.as_str(), creds.token.as_str()), ("app-bot", "app-token"));
+    }
+
+    #[tokio::test]
+    async fn project_git_credentials_stand_in_for_a_payload_without_them() {
+        let runner = Arc::new(MockRunner::default());
+        let mut job = job("pull", false);
+        job.git_credentials = Some(RegistryCredentials {
+            user: "app-deployer".to_string(),
+            token: "project-pat".to_string(),
+        });
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        assert!(commands.iter().any(|c| c.contains("username=app-deployer")));
+        assert!(!commands.iter().any(|c| c.contains("project-pat")));
+    }
+
+    #[tokio::test]
+    async fn rejected_docker_login_is_not_retried() {
+        let runner = Arc::new(MockRunner::failing("login", 1, "Error response from daemon: unauthorized"));
+        let (result, commands) = run(&job("image", true), &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::DockerLogin(_))));
+        assert_eq!(commands.len(), 1);
+        assert!(is_transient_login_error("Get \"https://ghcr.io/v2/\": net/http: TLS handshake timeout"));
+        assert!(is_transient_login_error("toomanyrequests: rate limit exceeded"));
+    }
+
+    #[tokio::test]
+    async fn build_login_logs_in_before_the_repo_build() {
+        let runner = Arc::new(MockRunner::default());
+        let mut with_login = job("repo", true);
+        with_login.compose.build_login = true;
+        let (result, commands) = run(&with_login, &runner, None).await;
+
+        assert_eq!(result.unwrap().registry_login.as_deref(), Some("ghcr.io"));
+        assert!(commands[0].contains("docker login ghcr.io -u bot"), "{:?}", commands);
+        assert!(commands.iter().any(|c| c.contains("up -d --build")));
+
+        // Nothing to log in with: the build goes ahead without one
+        let mut without = job("repo", false);
+        without.compose.build_login = true;
+        without.git_credentials = Some(RegistryCredentials { user: "bot".to_string(), token: "t".to_string() });
+        let (result, commands) = run(&without, &Arc::new(MockRunner::default()), None).await;
+        assert_eq!(result.unwrap().registry_login, None);
+        assert!(!commands.iter().any(|c| c.contains("login")));
+    }
+
+    #[tokio::test]
+    async fn parallel_login_overlaps_the_git_fetch() {
+        let mut job = job("repo", true);
+        job.compose.build_login = true;
+        job.compose.parallel_login = true;
+        let runner = Arc::new(MockRunner {
+            delay: Some(Duration::from_millis(30)),
+            ..Default::default()
+        });
+        let (result, commands) = run(&job, &runner, None).await;
+        assert_eq!(result.unwrap().registry_login.as_deref(), Some("ghcr.io"));
+        // The login starts while the fetch is still running, so it is recorded right after it
+        let fetch = commands.iter().position(|c| c.contains(" fetch ")).unwrap();
+        assert!(commands[fetch + 1].contains("docker login ghcr.io"), "{:?}", commands);
+
+        // Each failure keeps its own error
+        let runner = Arc::new(MockRunner::failing("login", 1, "unauthorized"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(matches!(result, Err(DeployError::DockerLogin(_))));
+        assert!(!commands.iter().any(|c| c.contains("up -d")));
+    }
+
+    #[tokio::test]
+    async fn failed_smoke_test_is_reported_without_failing_the_deploy() {
+        let mut job = job("image", true);
+        job.compose.smoke_test = Some(SmokeTest {
+            command: vec!["./smoke.sh".to_string()],
+            timeout: Duration::from_secs(5),
+        });
+        let runner = Arc::new(MockRunner::failing("smoke.sh", 1, "GET /health: 502"));
+        let (result, commands) = run(&job, &runner, None).await;
+        let smoke = result.unwrap().smoke_test.unwrap();
+        assert!(!smoke.passed);
+        assert_eq!(smoke.output, "GET /health: 502");
+        assert!(commands.contains(&"cd /srv/app && ./smoke.sh".to_string()));
+    }
+
+    #[tokio::test]
+    async fn login_mode_only_logs_in() {
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job("login", true), &runner, None).await;
+        let report = result.unwrap();
+        assert_eq!(report.registry_login.as_deref(), Some("ghcr.io"));
+        assert_eq!(report.login_user.as_deref(), Some("bot"));
+        assert_eq!(commands, ["echo *** | docker login ghcr.io -u bot --password-stdin"]);
+    }
+
+    #[tokio::test]
+    async fn transcript_keeps_full_output_with_secrets_redacted() {
+        let runner = Arc::new(MockRunner {
+            rules: vec![("login", 0, "WARNING! Your password will be stored unencrypted")],
+            stdout: vec![("up -d", "Container app-web-1  Started\n")],
+            ..Default::default()
+        });
+        let exec = Arc::new(Exec::new(runner.clone()));
+        run_job(&job("image", true), Arc::new(Metrics::default()), exec.clone(), None).await.unwrap();
+
+        let log = exec.transcript();
+        assert!(log.contains("WARNING! Your password will be stored unencrypted"));
+        assert!(log.contains("Container app-web-1  Started"));
+        assert!(log.contains("$ echo *** | docker login"));
+        assert!(!log.contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn mask_patterns_scrub_output_before_it_is_reported() {
+        let runner = Arc::new(MockRunner::failing("up -d", 1, "entrypoint: exporting API_KEY=live-9f8e7d\nError: service web exited"));
+        let mut job = job("image", true);
+        job.mask_patterns = vec![regex::Regex::new(r"API_KEY=\S+").unwrap()];
+        let exec = Arc::new(Exec::new(runner.clone()));
+        let err = run_job(&job, Arc::new(Metrics::default()), exec.clone(), None).await.unwrap_err();
+
+        let tail = err.stderr_tail().unwrap();
+        assert!(tail.contains("exporting ***"), "{}", tail);
+        assert!(!tail.contains("live-9f8e7d"));
+        assert!(!exec.transcript().contains("live-9f8e7d"));
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_the_cap_with_jitter() {
+        for _ in 0..20 {
+            let first = retry_delay(0);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+            let capped = retry_delay(12);
+            assert!(capped >= Duration::from_secs(30) && capped <= Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn post_up_commands_run_after_up_and_report_failures() {
+        let mut job = job("repo", true);
+        job.compose.post_up = vec![
+            vec!["run".to_string(), "--rm".to_string(), "app".to_string(), "migrate".to_string()],
+            vec!["exec".to_string(), "app".to_string(), "warm-cache".to_string()],
+        ];
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        assert!(commands[3].ends_with("docker compose --ansi never 'run' '--rm' 'app' 'migrate'"));
+        assert!(commands[4].ends_with("docker compose --ansi never 'exec' 'app' 'warm-cache'"));
+
+        let runner = Arc::new(MockRunner::failing("migrate", 1, "relation already exists"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(matches!(result, Err(DeployError::PostUp(1, _))));
+        assert!(!commands.iter().any(|c| c.contains("warm-cache")));
+    }
+
+    #[tokio::test]
+    async fn failing_health_command_is_retried_then_rolls_back() {
+        let mut job = job("repo", true);
+        job.compose.post_up_rollback = true;
+        job.compose.health = Some(HealthCheck {
+            command: vec!["./check-queue".to_string()],
+            timeout: Duration::from_secs(2),
+            interval: Duration::from_secs(1),
+        });
+        let runner = Arc::new(MockRunner {
+            rules: vec![("check-queue", 1, "queue still has 40 jobs")],
+            stdout: vec![("rev-parse", "abc123\n")],
+            ..Default::default()
+        });
+        let (result, commands) = run(&job, &runner, None).await;
+        match result {
+            Err(DeployError::Unhealthy(2, output)) => assert_eq!(output, "queue still has 40 jobs"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(commands.iter().filter(|c| c.contains("check-queue")).count(), 2);
+        assert!(commands.iter().any(|c| c.ends_with("reset --hard abc123")));
+    }
+
+    #[tokio::test]
+    async fn failed_up_restores_the_previous_commit() {
+        let mut job = job("repo", true);
+        job.compose.restore_on_failure = true;
+        let runner = Arc::new(MockRunner {
+            rules: vec![("up -d", 1, "Error response from daemon: port is already allocated")],
+            stdout: vec![("rev-parse", "abc123\n")],
+            ..Default::default()
+        });
+        let (result, commands) = run(&job, &runner, None).await;
+        let err = result.unwrap_err();
+        assert!(matches!(&err, DeployError::Restored(inner, false) if matches!(**inner, DeployError::ComposeBuild(..))));
+        assert_eq!(err.code(), "COMPOSE_FAILED");
+        assert_eq!(err.restored(), Some(false));
+        assert!(err.to_string().ends_with("the previous version could not be restored"));
+        assert!(commands.iter().any(|c| c.ends_with("reset --hard abc123")));
+        assert_eq!(commands.iter().filter(|c| c.contains("up -d")).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn proxy_variables_reach_spawned_commands() {
+        let set = |var: &str| match var {
+            "HTTPS_PROXY" => Some("http://proxy.internal:3128".to_string()),
+            "no_proxy" => Some("localhost,.internal".to_string()),
+            _ => None,
+        };
+        let proxies = proxy_env(set);
+        assert_eq!(proxies.iter().map(|(var, _)| *var).collect::<Vec<_>>(), ["HTTPS_PROXY", "no_proxy"]);
+
+        // Even a command started with an empty environment gets them
+        let env = CommandEnv {
+            vars: proxies.into_iter().map(|(var, value)| (var.to_string(), value)).collect(),
+            clean: true,
+        };
+        let out = SystemRunner
+            .run("/bin/sh", &["-c", "echo \"$HTTPS_PROXY|$no_proxy\""], None, &env)
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "http://proxy.internal:3128|localhost,.internal");
+    }
+
+    #[tokio::test]
+    async fn clean_env_keeps_only_the_allowed_variables() {
+        let mut exec = Exec::new(Arc::new(SystemRunner));
+        exec.host_env = |name| match name {
+            "HTTPS_PROXY" => Some("http://proxy.internal:3128".to_string()),
+            _ => env::var(name).ok(),
+        };
+        exec.set_env("GRAFT_PROJECT", "app");
+        exec.set_clean_env(&["CARGO_PKG_NAME".to_string()]);
+
+        let out = exec
+            .sh("echo \"$CARGO_MANIFEST_DIR|$CARGO_PKG_NAME|$GRAFT_PROJECT|${PATH:+path}|$HTTPS_PROXY\"")
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "|graft-hook|app|path|http://proxy.internal:3128");
+    }
+
+    #[tokio::test]
+    async fn atomic_worktree_switches_the_symlink_to_the_new_release() {
+        let dir = env::temp_dir().join(format!("graft-hook-worktree-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("repo")).unwrap();
+        let current = dir.join("current");
+        std::os::unix::fs::symlink(dir.join("repo.releases/old"), &current).unwrap();
+
+        let mut job = job("repo", true);
+        job.repo_path = dir.join("repo").display().to_string();
+        job.path = current.display().to_string();
+        job.git.atomic_worktree = true;
+        let runner = Arc::new(MockRunner {
+            stdout: vec![("FETCH_HEAD", "abc123def456\n")],
+            ..Default::default()
+        });
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(!commands[0].contains("reset --hard"));
+        assert!(commands[1].contains("worktree add --force --detach") && commands[1].contains("abc123def456"));
+        assert_eq!(std::fs::read_link(&current).unwrap(), dir.join("repo.releases/abc123def456"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rollback_walks_back_through_retained_releases_and_pruning_drops_old_ones() {
+        let dir = env::temp_dir().join(format!("graft-hook-rollback-{}", std::process::id()));
+        let releases = dir.join("repo.releases");
+        for commit in ["aaa", "bbb", "ccc"] {
+            std::fs::create_dir_all(releases.join(commit)).unwrap();
+            std::fs::write(releases.join(commit).join("docker-compose.yml"), "services: {}\n").unwrap();
+        }
+        std::fs::write(releases.join(".history"), "aaa\nbbb\nccc\n").unwrap();
+        let current = dir.join("current");
+        std::os::unix::fs::symlink(releases.join("ccc"), &current).unwrap();
+
+        let mut job = job("rollback", false);
+        job.repo_path = dir.join("repo").display().to_string();
+        job.path = current.display().to_string();
+        job.git.atomic_worktree = true;
+        job.git.rollback_steps = 1;
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        assert_eq!(std::fs::read_link(&current).unwrap(), releases.join("bbb"));
+        assert!(commands.iter().any(|c| c.contains("up -d --build")));
+
+        job.git.rollback_steps = 2;
+        let (result, _) = run(&job, &runner, None).await;
+        assert_eq!(result.unwrap_err().to_string(), "Cannot roll back: only 1 older release(s) are retained");
+
+        let exec = Exec::new(runner.clone());
+        prune_releases(&exec, &job.repo_path, 2).await;
+        assert_eq!(release_history(&job.repo_path), ["bbb", "ccc"]);
+        let removed = format!("worktree remove --force '{}'", releases.join("aaa").display());
+        assert!(exec.commands()[0].ends_with(&removed));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_repo_dir_is_initialized_when_cloning_is_allowed() {
+        let dir = env::temp_dir().join(format!("graft-hook-clone-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut job = job("repo", true);
+        job.repo_path = dir.display().to_string();
+        job.git.clone_url = Some("https://example.com/app.git".to_string());
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(commands[0].contains("git init -q && git remote add origin 'https://example.com/app.git'"));
+        assert!(commands[1].contains("fetch origin main"));
+
+        // Anything besides our lock file means this isn't a fresh directory
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("docker-compose.yml"), "services: {}").unwrap();
+        let (result, commands) = run(&job, &Arc::new(MockRunner::default()), None).await;
+        assert!(matches!(result, Err(DeployError::GitClone(_))));
+        assert!(commands.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_compose_file_fails_before_compose_runs() {
+        let dir = env::temp_dir().join(format!("graft-hook-nocompose-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut job = job("restart", false);
+        job.path = dir.display().to_string();
+        let runner = Arc::new(MockRunner::default());
+
+        let (result, commands) = run(&job, &runner, None).await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, DeployError::ComposeFileMissing(_)));
+        assert_eq!(err.to_string(), format!("No compose file found at {}", dir.display()));
+        assert!(commands.is_empty());
+
+        std::fs::write(dir.join("compose.yaml"), "services: {}").unwrap();
+        let (result, _) = run(&job, &runner, None).await;
+        assert!(result.is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn downloaded_compose_file_is_checked_and_passed_with_f() {
+        let sum = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+        assert!(verify_sha256(b"foo", sum).is_ok());
+        assert!(verify_sha256(b"bar", sum).unwrap_err().starts_with("checksum mismatch"));
+
+        let compose = ComposeOptions {
+            files: vec!["/tmp/graft-hook-app.compose.yml".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            compose_cmd(&compose),
+            "docker compose --ansi never --project-directory . -f '/tmp/graft-hook-app.compose.yml'"
+        );
+
+        let compose = ComposeOptions {
+            project_directory: Some("/srv/app".to_string()),
+            ..compose
+        };
+        assert_eq!(
+            compose_cmd(&compose),
+            "docker compose --ansi never --project-directory '/srv/app' -f '/tmp/graft-hook-app.compose.yml'"
+        );
+    }
+
+    #[test]
+    fn payload_schema_minimums_match_the_validators() {
+        let schema = crate::config::payload_schema();
+        let properties = &schema["properties"];
+        let min_scale = properties["scale"]["additionalProperties"]["minimum"].as_u64().unwrap() as u32;
+        let scaled = |count| ComposeOptions {
+            scale: BTreeMap::from([("web".to_string(), count)]),
+            ..Default::default()
+        };
+        assert!(validate_compose_options(&scaled(min_scale)).is_ok());
+        assert_eq!(validate_compose_options(&scaled(min_scale - 1)), Err("Invalid scale count"));
+        let min_pr = properties["pr_number"]["minimum"].as_u64().unwrap() as u32;
+        assert!(crate::config::is_valid_pr_number(min_pr));
+        assert!(!crate::config::is_valid_pr_number(min_pr - 1));
+    }
+
+    #[test]
+    fn priority_prefix_skips_missing_tools() {
+        let compose = ComposeOptions {
+            nice: Some(10),
+            ionice_class: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(priority_prefix(&compose, |_| true), "nice -n 10 ionice -c 3 ");
+        assert_eq!(priority_prefix(&compose, |tool| tool == "nice"), "nice -n 10 ");
+        assert_eq!(priority_prefix(&ComposeOptions::default(), |_| true), "");
+    }
+
+    #[tokio::test]
+    async fn up_retries_rerun_only_up_and_never_a_failed_build() {
+        let mut job = job("repo", true);
+        job.compose.up_retries = 1;
+        let runner = Arc::new(MockRunner::failing("up -d", 1, "failed to create volume: already exists"));
+        let (result, commands) = run(&job, &runner, None).await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, DeployError::ComposeUp(2, ..)), "{:?}", err);
+        assert_eq!(err.code(), "COMPOSE_UP_FAILED");
+        assert_eq!(commands.iter().filter(|c| c.ends_with("never build")).count(), 1);
+        assert_eq!(commands.iter().filter(|c| c.contains("up -d")).count(), 2);
+        assert!(!commands.iter().any(|c| c.contains("--build")));
+
+        let runner = Arc::new(MockRunner::failing("never build", 1, "failed to solve: process did not complete"));
+        let (result, commands) = run(&job, &runner, None).await;
+        assert!(matches!(result, Err(DeployError::ComposeBuild(..))));
+        assert!(!commands.iter().any(|c| c.contains("up -d")));
+    }
+
+    #[test]
+    fn write_probe_names_the_filesystem_problem() {
+        let dir = env::temp_dir().join(format!("graft-hook-probe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(probe_writable(dir.to_str().unwrap()).is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let full = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        assert_eq!(unwritable_reason(&full), Some("no space left on the filesystem"));
+        assert_eq!(unwritable_reason(&std::io::Error::from_raw_os_error(libc::EROFS)), Some("read-only filesystem"));
+        assert_eq!(unwritable_reason(&std::io::Error::from_raw_os_error(libc::ENOENT)), None);
+        let err = DeployError::NotWritable("/srv/app".to_string(), "read-only filesystem".to_string());
+        assert_eq!(err.to_string(), "Project directory is not writable (read-only filesystem): /srv/app");
+    }
+
+    #[tokio::test]
+    async fn compose_failure_is_reported() {
+        let runner = Arc::new(MockRunner::failing("compose", 1, "yaml: line 3: mapping values are not allowed"));
+        let (result, _) = run(&job("repo", true), &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::ComposeBuild(..))));
+    }
+
+    #[tokio::test]
+    async fn failing_service_is_named_in_the_error() {
+        let runner = Arc::new(MockRunner::failing(
+            "up -d",
+            1,
+            "Error response from daemon: driver failed programming external connectivity on endpoint for service \"web\"",
+        ));
+        let (result, _) = run(&job("image", true), &runner, None).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.failed_services(), ["web"]);
+        assert_eq!(err.to_string(), "Docker Compose pull/up failed (service web)");
+
+        // Nothing in the output: fall back to the containers compose left behind
+        let runner = Arc::new(MockRunner {
+            rules: vec![("up -d", 1, "dependency failed to start")],
+            stdout: vec![(
+                "ps --all",
+                "{\"Service\":\"db\",\"State\":\"running\",\"Health\":\"unhealthy\"}\n{\"Service\":\"web\",\"State\":\"created\"}\n{\"Service\":\"cache\",\"State\":\"running\"}",
+            )],
+            ..Default::default()
+        });
+        let (result, _) = run(&job("image", true), &runner, None).await;
+        assert_eq!(result.unwrap_err().failed_services(), ["db", "web"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_compose_file_fails_before_up() {
+        let runner = Arc::new(MockRunner::failing("config -q", 15, "invalid interpolation format"));
+        let mut job = job("image", true);
+        job.compose.validate = true;
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::ComposeInvalid(_))));
+        assert!(commands.iter().all(|c| !c.contains(" up ")));
+    }
+
+    #[tokio::test]
+    async fn unchanged_compose_file_is_validated_once() {
+        let dir = env::temp_dir().join(format!("graft-hook-validate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("compose.yaml"), "services: {}\n").unwrap();
+        let mut job = job("image", true);
+        job.path = dir.display().to_string();
+        job.compose.validate = true;
+
+        let cache = Arc::new(ValidationCache::default());
+        let runner = Arc::new(MockRunner::default());
+        let mut validations = 0;
+        for edit in [None, None, Some("services: { web: {} }\n")] {
+            if let Some(content) = edit {
+                std::fs::write(dir.join("compose.yaml"), content).unwrap();
+            }
+            let exec = Arc::new(Exec::new(runner.clone()).with_validation_cache("app", cache.clone()));
+            run_job(&job, Arc::new(Metrics::default()), exec.clone(), None).await.unwrap();
+            validations += exec.commands().iter().filter(|c| c.contains("config -q")).count();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(validations, 2);
+    }
+
+    #[tokio::test]
+    async fn failed_pull_before_build_is_not_fatal() {
+        let runner = Arc::new(MockRunner::failing(" pull ", 1, "pull access denied for app-local"));
+        let mut job = job("repo", true);
+        job.compose.pull = true;
+        let (result, commands) = run(&job, &runner, None).await;
+
+        assert!(result.is_ok());
+        assert!(commands[2].contains("compose --ansi never pull --ignore-buildable"));
+        assert!(commands[3].contains("up -d --build"));
+    }
+
+    #[tokio::test]
+    async fn missing_docker_binary_is_detected() {
+        let runner = Arc::new(MockRunner::failing("docker login", 127, "sh: docker: not found"));
+        let (result, _) = run(&job("image", true), &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::BinaryNotFound("docker"))));
+    }
+
+    #[tokio::test]
+    async fn missing_credentials_run_nothing() {
+        let runner = Arc::new(MockRunner::default());
+        let (result, commands) = run(&job("repo", false), &runner, None).await;
+
+        assert!(matches!(result, Err(DeployError::MissingCredentials("Git"))));
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropping_a_command_kills_its_grandchildren() {
+        let pid_file = env::temp_dir().join(format!("graft-hook-pgid-{}", std::process::id()));
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let args = ["-c", script.as_str()];
+        let env = CommandEnv::default();
+        let run = SystemRunner.run("sh", &args, None, &env);
+        assert!(tokio::time::timeout(Duration::from_millis(300), run).await.is_err());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Gone, or a zombie waiting for init to reap it
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        let alive = stat.rsplit(')').next().is_some_and(|rest| !rest.trim_start().starts_with('Z') && !rest.is_empty());
+        assert!(!alive, "background sleep survived the timeout");
+    }
+
+    #[tokio::test]
+    async fn slow_deploy_times_out() {
+        let runner = Arc::new(MockRunner {
+            delay: Some(Duration::from_secs(5)),
+            ..Default::default()
+        });
+        let (result, _) = run(&job("image", true), &runner, Some(Duration::from_millis(50))).await;
+
+        assert!(matches!(result, Err(DeployError::TimedOut(_))));
+    }
+}
@@ -0,0 +1,178 @@
+// Per-project deploy history, HISTORY_MAX entries each, optionally kept in HISTORY_FILE so it
+// survives restarts (including the hook redeploying itself)
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::deploy::CommitInfo;
+
+const DEFAULT_HISTORY_MAX: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub status: String,
+    pub mode: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<CommitInfo>,
+    // Full sha checked out (GRAFT_COMMIT), for redeploy-last-good
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+    // DeployJob::source; missing from entries written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub finished_at: u64,
+}
+
+pub struct History {
+    max: usize,
+    file: Option<PathBuf>,
+    // Newest entry first
+    projects: Mutex<BTreeMap<String, VecDeque<HistoryEntry>>>,
+    // Serializes file writes so an older snapshot never lands after a newer one
+    writes: tokio::sync::Mutex<()>,
+}
+
+impl History {
+    pub fn new(max: usize, file: Option<PathBuf>) -> Self {
+        History {
+            max,
+            file,
+            projects: Mutex::new(BTreeMap::new()),
+            writes: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // HISTORY_MAX (0 keeps none) and HISTORY_FILE, loading whatever the file already holds
+    pub fn from_env() -> Self {
+        let max = env::var("HISTORY_MAX")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_HISTORY_MAX);
+        let file = env::var("HISTORY_FILE").ok().filter(|p| !p.trim().is_empty()).map(PathBuf::from);
+        let history = History::new(max, file);
+        history.load();
+        history
+    }
+
+    // A missing file is a first start; an unreadable one is logged and replaced on the next write
+    fn load(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let raw = match std::fs::read_to_string(file) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("⚠️ Could not read HISTORY_FILE {}: {}", file.display(), e);
+                return;
+            }
+        };
+        match serde_json::from_str::<BTreeMap<String, VecDeque<HistoryEntry>>>(&raw) {
+            Ok(mut projects) => {
+                for entries in projects.values_mut() {
+                    entries.truncate(self.max);
+                }
+                let count: usize = projects.values().map(VecDeque::len).sum();
+                info!("📜 Loaded {} deploy history entries from {}", count, file.display());
+                *self.projects.lock().unwrap() = projects;
+            }
+            Err(e) => warn!("⚠️ Ignoring unparseable HISTORY_FILE {}: {}", file.display(), e),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // Records a finished deploy; the file is rewritten in the background so deploys never wait on it
+    pub fn record(self: &Arc<Self>, project: &str, entry: HistoryEntry) {
+        if self.max == 0 {
+            return;
+        }
+        {
+            let mut projects = self.projects.lock().unwrap();
+            let entries = projects.entry(project.to_string()).or_default();
+            entries.push_front(entry);
+            entries.truncate(self.max);
+        }
+        if self.file.is_some() {
+            let history = self.clone();
+            tokio::spawn(async move { history.persist().await });
+        }
+    }
+
+    // Newest first
+    pub fn entries(&self, project: &str) -> Vec<HistoryEntry> {
+        self.projects.lock().unwrap().get(project).map_or_else(Vec::new, |e| e.iter().cloned().collect())
+    }
+
+    // Sha of the newest successful deploy that recorded one
+    pub fn last_good(&self, project: &str) -> Option<String> {
+        let projects = self.projects.lock().unwrap();
+        projects.get(project)?.iter().find(|e| e.status == "success").and_then(|e| e.sha.clone())
+    }
+
+    // Written to a temp file and renamed, so a crash mid-write keeps the previous file
+    async fn persist(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let _write = self.writes.lock().await;
+        let body = match serde_json::to_vec(&*self.projects.lock().unwrap()) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("⚠️ Could not serialize deploy history: {}", e);
+                return;
+            }
+        };
+        let tmp = file.with_extension("tmp");
+        let written = match tokio::fs::write(&tmp, body).await {
+            Ok(()) => tokio::fs::rename(&tmp, file).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("⚠️ Could not write HISTORY_FILE {}: {}", file.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: &str, sha: &str, at: u64) -> HistoryEntry {
+        HistoryEntry {
+            status: status.to_string(),
+            mode: "repo".to_string(),
+            message: format!("deploy at {}", at),
+            commit: None,
+            sha: Some(sha.to_string()),
+            source: None,
+            finished_at: at,
+        }
+    }
+
+    #[tokio::test]
+    async fn history_is_capped_and_survives_a_restart() {
+        let file = env::temp_dir().join(format!("graft-hook-history-{}.json", std::process::id()));
+        let history = Arc::new(History::new(2, Some(file.clone())));
+        history.record("app", entry("success", "aaa", 1));
+        history.record("app", entry("success", "bbb", 2));
+        history.record("app", entry("error", "ccc", 3));
+        history.persist().await;
+
+        let restarted = History::new(2, Some(file.clone()));
+        restarted.load();
+        let entries = restarted.entries("app");
+        assert_eq!(entries.iter().map(|e| e.finished_at).collect::<Vec<_>>(), [3, 2]);
+        assert_eq!(restarted.last_good("app").as_deref(), Some("bbb"));
+        assert_eq!(restarted.last_good("other"), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+// Deploy webhook for docker compose projects; `main.rs` only wires up the server
+pub mod audit;
+pub mod backup;
+pub mod config;
+pub mod credentials;
+pub mod deploy;
+pub mod history;
+pub mod metrics;
+pub mod notify;
+pub mod prune;
+pub mod queue;
+pub mod self_test;
+pub mod server;
+pub mod signature;
+pub mod statsd;
+pub mod window;
+
+pub use config::{ConfigFile, ProjectConfig, ProjectEntry, WebhookPayload};
+pub use deploy::{deploy_docker, deploy_git, CommandEnv, CommandRunner, DeployError, DeployJob, DeployOutcome, Exec, SystemRunner};
+pub use server::{internal_router, public_router, router, AppState};
@@ -0,0 +1,63 @@
+// Minimal Prometheus histograms, rendered in the text exposition format on /metrics
+use std::{collections::BTreeMap, sync::Mutex};
+
+#[derive(Default)]
+pub struct Metrics {
+    histograms: Mutex<BTreeMap<(&'static str, String), Histogram>>,
+}
+
+const HISTOGRAM_BUCKETS: [f64; 9] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0];
+
+const METRIC_HELP: &[(&str, &str)] = &[
+    ("graft_git_duration_seconds", "Time spent fetching and resetting the git checkout"),
+    ("graft_build_duration_seconds", "Time spent in docker compose build and up"),
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn observe(&self, name: &'static str, project: &str, seconds: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry((name, project.to_string())).or_default();
+        for (i, bound) in HISTOGRAM_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let histograms = self.histograms.lock().unwrap();
+        let mut out = String::new();
+        let mut current = "";
+
+        for ((name, project), histogram) in histograms.iter() {
+            let project = project.replace('\\', "\\\\").replace('"', "\\\"");
+            if *name != current {
+                current = name;
+                let help = METRIC_HELP.iter().find(|(n, _)| n == name).map_or("", |(_, h)| h);
+                out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+            }
+            for (i, bound) in HISTOGRAM_BUCKETS.iter().enumerate() {
+                out.push_str(&format!(
+                    "{}_bucket{{project=\"{}\",le=\"{}\"}} {}\n",
+                    name, project, bound, histogram.buckets[i]
+                ));
+            }
+            out.push_str(&format!(
+                "{}_bucket{{project=\"{}\",le=\"+Inf\"}} {}\n",
+                name, project, histogram.count
+            ));
+            out.push_str(&format!("{}_sum{{project=\"{}\"}} {}\n", name, project, histogram.sum));
+            out.push_str(&format!("{}_count{{project=\"{}\"}} {}\n", name, project, histogram.count));
+        }
+        out
+    }
+}
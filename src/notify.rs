@@ -0,0 +1,446 @@
+// Outgoing deploy notifications
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::{env, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    process::Command,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::deploy::{DeployJob, DeployOutcome};
+use crate::server::AppState;
+
+// Where deploy notifications go. `send` only hands the body off: delivery runs on its own
+// task, and failures are logged there, never surfaced to the deploy.
+pub(crate) trait NotifySink: Send + Sync {
+    fn send(&self, body: serde_json::Value);
+}
+
+// Generic outgoing webhook (Slack/Discord compatible) fired after each deploy (and optionally at start)
+pub struct Notifier {
+    url: String,
+    client: reqwest::Client,
+}
+
+const DEFAULT_NOTIFY_TIMEOUT_SECS: u64 = 10;
+
+// NOTIFY_HEADERS="Authorization=Bearer abc,X-Team=ops"; values are marked sensitive so they never hit logs
+fn parse_headers(spec: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parsed = pair.split_once('=').and_then(|(name, value)| {
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+            let mut value = HeaderValue::from_str(value.trim()).ok()?;
+            value.set_sensitive(true);
+            Some((name, value))
+        });
+        match parsed {
+            Some((name, value)) => {
+                headers.insert(name, value);
+            }
+            // Only the name part: the value may be a secret
+            None => warn!(
+                "⚠️ Ignoring malformed NOTIFY_HEADERS entry '{}'",
+                pair.split('=').next().unwrap_or("").trim()
+            ),
+        }
+    }
+    headers
+}
+
+impl Notifier {
+    // Enabled by NOTIFY_URL; NOTIFY_TIMEOUT_SECS bounds each delivery so a hung endpoint can't pile up tasks
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("NOTIFY_URL").ok().filter(|u| !u.is_empty())?;
+        let timeout = env::var("NOTIFY_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_NOTIFY_TIMEOUT_SECS);
+
+        let headers = parse_headers(&env::var("NOTIFY_HEADERS").unwrap_or_default());
+        if !headers.is_empty() {
+            let names: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+            info!("Sending custom notification headers: {}", names.join(", "));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .default_headers(headers)
+            .user_agent(concat!("graft-hook/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("CRITICAL: Failed to build notification HTTP client");
+
+        info!("🔔 Deploy notifications enabled ({}s timeout)", timeout);
+        Some(Notifier { url, client })
+    }
+}
+
+const DEFAULT_SUCCESS_TEMPLATE: &str = "✅ [{project}] {result} (commit {commit}, by {user})";
+const DEFAULT_FAILURE_TEMPLATE: &str = "❌ [{project}] Deploy failed: {result} (commit {commit}, by {user})";
+const DEFAULT_START_TEMPLATE: &str = "🚀 [{project}] Deploying {ref} (currently at {commit}, by {user})";
+
+// Replaces {name} placeholders in a single pass; unknown placeholders are left as-is
+fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                let key = &after[..end];
+                match vars.iter().find(|(k, _)| *k == key) {
+                    Some((_, value)) => out.push_str(value),
+                    None => {
+                        out.push('{');
+                        out.push_str(key);
+                        out.push('}');
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub(crate) async fn current_commit(path: &str) -> Option<String> {
+    let out = Command::new("git")
+        .args(["-C", path, "rev-parse", "--short", "HEAD"])
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+// What was deployed, as far as we know before the fetch: the tag, or the branch being pulled
+fn deploy_ref(job: &DeployJob) -> String {
+    match (&job.git.tag, job.git.steps.first()) {
+        (Some(tag), _) => tag.clone(),
+        (None, Some(step)) => format!("{}/{}", step.remote, step.branch),
+        (None, None) => "origin/main".to_string(),
+    }
+}
+
+impl NotifySink for Notifier {
+    fn send(&self, body: serde_json::Value) {
+        let request = self.client.post(&self.url).json(&body);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(resp) if resp.status().is_success() => debug!("Notification delivered"),
+                Ok(resp) => warn!("Notification endpoint returned {}", resp.status()),
+                Err(e) => warn!("Failed to send notification: {}", e),
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventBackend {
+    Nats,
+    Redis,
+}
+
+// Structured deploy events published to a NATS subject or Redis channel, one connection per event
+#[derive(Debug, Clone)]
+pub(crate) struct EventPublisher {
+    backend: EventBackend,
+    addr: String,
+    channel: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl EventPublisher {
+    // EVENTS_URL=nats://[user:pass@]host[:4222]/subject or redis://[:pass@]host[:6379]/channel
+    pub(crate) fn from_env() -> Option<Self> {
+        let raw = env::var("EVENTS_URL").ok().filter(|u| !u.is_empty())?;
+        match Self::parse(&raw) {
+            Ok(publisher) => {
+                info!("📣 Publishing deploy events to {:?} `{}` at {}", publisher.backend, publisher.channel, publisher.addr);
+                Some(publisher)
+            }
+            Err(e) => {
+                error!("❌ Ignoring EVENTS_URL: {}", e);
+                None
+            }
+        }
+    }
+
+    fn parse(raw: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(raw).map_err(|e| e.to_string())?;
+        let (backend, default_port) = match url.scheme() {
+            "nats" => (EventBackend::Nats, 4222),
+            "redis" => (EventBackend::Redis, 6379),
+            other => return Err(format!("unsupported scheme '{}'; use nats:// or redis://", other)),
+        };
+        let host = url.host_str().ok_or("missing host")?;
+        let channel = url.path().trim_start_matches('/');
+        if channel.is_empty() || channel.chars().any(char::is_whitespace) {
+            return Err("missing or invalid subject/channel in the path".to_string());
+        }
+        Ok(EventPublisher {
+            backend,
+            addr: format!("{}:{}", host, url.port().unwrap_or(default_port)),
+            channel: channel.to_string(),
+            user: Some(url.username().to_string()).filter(|u| !u.is_empty()),
+            password: url.password().map(str::to_string),
+        })
+    }
+
+    // Everything written on the connection for one event, and how many replies to wait for (Redis)
+    fn frames(&self, payload: &str) -> (Vec<u8>, usize) {
+        match self.backend {
+            EventBackend::Nats => {
+                let mut connect = serde_json::json!({ "verbose": false, "name": "graft-hook" });
+                if let (Some(user), Some(password)) = (&self.user, &self.password) {
+                    connect["user"] = user.as_str().into();
+                    connect["pass"] = password.as_str().into();
+                }
+                let frames = format!(
+                    "CONNECT {}\r\nPUB {} {}\r\n{}\r\nPING\r\n",
+                    connect,
+                    self.channel,
+                    payload.len(),
+                    payload
+                );
+                (frames.into_bytes(), 0)
+            }
+            EventBackend::Redis => {
+                let mut commands: Vec<Vec<&str>> = Vec::new();
+                if let Some(password) = &self.password {
+                    match &self.user {
+                        Some(user) => commands.push(vec!["AUTH", user, password]),
+                        None => commands.push(vec!["AUTH", password]),
+                    }
+                }
+                commands.push(vec!["PUBLISH", &self.channel, payload]);
+                let mut frames = Vec::new();
+                for command in &commands {
+                    frames.extend(format!("*{}\r\n", command.len()).into_bytes());
+                    for arg in command {
+                        frames.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+                    }
+                }
+                (frames, commands.len())
+            }
+        }
+    }
+
+    // Done once the server acknowledged: NATS answers our PING, Redis replies to each command
+    async fn deliver(&self, payload: &str) -> Result<(), String> {
+        let (frames, replies) = self.frames(payload);
+        let stream = TcpStream::connect(&self.addr).await.map_err(|e| e.to_string())?;
+        let (read, mut write) = stream.into_split();
+        write.write_all(&frames).await.map_err(|e| e.to_string())?;
+
+        let mut lines = BufReader::new(read).lines();
+        let mut answered = 0;
+        while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+            // Both protocols report errors as "-ERR ..." lines
+            if line.starts_with('-') {
+                return Err(line);
+            }
+            match self.backend {
+                EventBackend::Nats if line == "PONG" => return Ok(()),
+                EventBackend::Nats => {}
+                EventBackend::Redis => {
+                    answered += 1;
+                    if answered == replies {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Err("connection closed before the event was acknowledged".to_string())
+    }
+}
+
+impl NotifySink for EventPublisher {
+    fn send(&self, body: serde_json::Value) {
+        let publisher = self.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(EVENT_TIMEOUT, publisher.deliver(&body.to_string())).await {
+                Ok(Ok(())) => debug!("Deploy event published"),
+                Ok(Err(e)) => warn!("Failed to publish deploy event to {}: {}", publisher.addr, e),
+                Err(_) => warn!("Publishing deploy event to {} timed out", publisher.addr),
+            }
+        });
+    }
+}
+
+// "started", "succeeded" or "failed", with the deploy result fields once it has finished
+pub(crate) fn publish_event(state: &AppState, job: &DeployJob, outcome: Option<&DeployOutcome>) {
+    let events = match &state.events {
+        Some(events) => events,
+        None => return,
+    };
+    let mut event = serde_json::json!({
+        "event": "started",
+        "project": job.project_id,
+        "mode": job.mode,
+        "user": job.payload.as_ref().and_then(|p| p.user.as_deref()),
+        "source": job.source,
+        "at": crate::server::unix_now(),
+    });
+    match outcome {
+        None => {}
+        Some(Ok(report)) => {
+            event["event"] = "succeeded".into();
+            event["status"] = "success".into();
+            event["message"] = report.message.into();
+            event["timings"] = serde_json::json!(report.timings);
+            event["services"] = serde_json::json!(report.services);
+            event["commit"] = serde_json::json!(report.commit);
+        }
+        Some(Err(e)) => {
+            event["event"] = "failed".into();
+            event["status"] = "error".into();
+            event["message"] = e.to_string().into();
+            event["code"] = e.code().into();
+            event["output"] = serde_json::json!(e.stderr_tail());
+            event["failed_services"] = serde_json::json!(e.failed_services());
+        }
+    }
+    events.send(event);
+}
+
+// The project's labels and tags, so receivers can route notifications by team or environment
+fn add_metadata(state: &AppState, job: &DeployJob, mut body: serde_json::Value) -> serde_json::Value {
+    let config = state.config();
+    if let Some(entry) = job.config_key.as_ref().and_then(|key| config.get(key)) {
+        body["labels"] = serde_json::json!(entry.labels());
+        body["tags"] = serde_json::json!(entry.tags());
+    }
+    body
+}
+
+pub(crate) async fn notify_deploy_started(state: &AppState, job: &DeployJob) {
+    let notifier = match &state.notifier {
+        Some(n) => n,
+        None => return,
+    };
+
+    let user = job.payload.as_ref().and_then(|p| p.user.as_deref()).unwrap_or("unknown");
+    let commit = current_commit(&job.repo_path).await.unwrap_or_else(|| "unknown".to_string());
+    let target = deploy_ref(job);
+    let text = render_template(
+        DEFAULT_START_TEMPLATE,
+        &[
+            ("project", &job.project_id),
+            ("ref", &target),
+            ("user", user),
+            ("commit", &commit),
+            ("source", &job.source),
+        ],
+    );
+
+    notifier.send(add_metadata(
+        state,
+        job,
+        serde_json::json!({
+            "text": text,
+            "content": text,
+            "project": job.project_id,
+            "status": "started",
+            "ref": target,
+            "commit": commit,
+            "source": job.source,
+        }),
+    ));
+}
+
+pub(crate) async fn notify_deploy(state: &AppState, job: &DeployJob, success: bool, result: &str) {
+    let notifier = match &state.notifier {
+        Some(n) => n,
+        None => return,
+    };
+
+    let config = state.config();
+    let entry = job.config_key.as_ref().and_then(|key| config.get(key));
+    let user = job.payload.as_ref().and_then(|p| p.user.as_deref());
+    let default = if success { DEFAULT_SUCCESS_TEMPLATE } else { DEFAULT_FAILURE_TEMPLATE };
+    let template = entry.and_then(|e| e.notify_template(success)).unwrap_or(default);
+
+    let commit = current_commit(&job.repo_path).await.unwrap_or_else(|| "unknown".to_string());
+    let text = render_template(
+        template,
+        &[
+            ("project", &job.project_id),
+            ("result", result),
+            ("user", user.unwrap_or("unknown")),
+            ("commit", &commit),
+            ("source", &job.source),
+        ],
+    );
+
+    notifier.send(add_metadata(
+        state,
+        job,
+        serde_json::json!({
+            "text": text,
+            "content": text,
+            "project": job.project_id,
+            "status": if success { "success" } else { "error" },
+            "result": result,
+            "commit": commit,
+            "source": job.source,
+        }),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_headers_are_parsed_and_hidden_from_debug() {
+        let headers = parse_headers("Authorization=Bearer s3cret, X-Team=ops,broken,bad name=x");
+
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["authorization"], "Bearer s3cret");
+        assert_eq!(headers["x-team"], "ops");
+        assert!(!format!("{:?}", headers).contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn redis_events_are_published_with_resp_framing() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://:hunter2@{}/deploys", listener.local_addr().unwrap());
+        let publisher = EventPublisher::parse(&url).unwrap();
+        assert_eq!(publisher.backend, EventBackend::Redis);
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = vec![0; 256];
+            let mut len = 0;
+            while !String::from_utf8_lossy(&received[..len]).contains("{}\r\n") {
+                len += tokio::io::AsyncReadExt::read(&mut socket, &mut received[len..]).await.unwrap();
+            }
+            socket.write_all(b"+OK\r\n:1\r\n").await.unwrap();
+            String::from_utf8_lossy(&received[..len]).to_string()
+        });
+
+        publisher.deliver("{}").await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            "*2\r\n$4\r\nAUTH\r\n$7\r\nhunter2\r\n*3\r\n$7\r\nPUBLISH\r\n$7\r\ndeploys\r\n$2\r\n{}\r\n"
+        );
+
+        assert!(EventPublisher::parse("kafka://broker/deploys").is_err());
+        let (frames, _) = EventPublisher::parse("nats://ci:pw@nats.internal/deploys.app").unwrap().frames("{}");
+        assert!(String::from_utf8(frames).unwrap().ends_with("PUB deploys.app 2\r\n{}\r\nPING\r\n"));
+    }
+}
@@ -0,0 +1,292 @@
+// Opt-in periodic `docker system prune` (PRUNE_SCHEDULE), for hosts that deploy too rarely for
+// the image prune after each deploy to keep disk use in check
+use std::{env, time::Duration};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::env_flag;
+use crate::deploy::docker_bin;
+use crate::server::unix_now;
+use crate::window::civil_from_days;
+
+// While deploys run, a due prune waits this long between checks so it can't remove an image
+// or build cache a deploy is about to use
+const BUSY_RECHECK: Duration = Duration::from_secs(30);
+
+pub struct Pruner {
+    // PRUNE_SCHEDULE as given, for logs
+    spec: String,
+    schedule: Schedule,
+    // PRUNE_UNTIL: only what is older than this, e.g. "24h" (docker's `until` filter)
+    until: Option<String>,
+    // PRUNE_ALL: every unused image (`-a`), not just dangling ones
+    all: bool,
+    // PRUNE_VOLUMES: unused volumes too; never without the flag
+    volumes: bool,
+    use_sudo: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Schedule {
+    Every(Duration),
+    Cron(Cron),
+}
+
+// Five cron fields (minute hour day-of-month month day-of-week) as bitmasks, matched against
+// the server clock in UTC. Fields take `*`, numbers, `a-b` ranges, `/n` steps and comma lists;
+// names like `mon` are not understood.
+#[derive(Debug, PartialEq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in cron, when both day fields are restricted a day matching either one runs
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Pruner {
+    // None unless PRUNE_SCHEDULE is set; a schedule or filter that doesn't parse disables
+    // pruning with a warning rather than pruning in a way nobody asked for
+    pub fn from_env() -> Option<Self> {
+        let schedule = env::var("PRUNE_SCHEDULE").ok().filter(|s| !s.trim().is_empty())?;
+        let until = env::var("PRUNE_UNTIL").ok().filter(|s| !s.trim().is_empty());
+        match Pruner::new(&schedule, until.as_deref(), env_flag("PRUNE_ALL"), env_flag("PRUNE_VOLUMES")) {
+            Ok(pruner) => {
+                info!("🧹 Pruning docker {}", pruner.describe());
+                Some(pruner)
+            }
+            Err(e) => {
+                warn!("⚠️ Periodic prune disabled: {}", e);
+                None
+            }
+        }
+    }
+
+    fn new(spec: &str, until: Option<&str>, all: bool, volumes: bool) -> Result<Self, String> {
+        let schedule = Schedule::parse(spec.trim()).map_err(|e| format!("PRUNE_SCHEDULE={}: {}", spec, e))?;
+        let until = until.map(str::trim);
+        if let Some(until) = until {
+            if !until.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '.' | '-')) {
+                return Err(format!("PRUNE_UNTIL={} is not a duration or timestamp", until));
+            }
+            // The daemon rejects the combination, so every run would fail
+            if volumes {
+                return Err("PRUNE_UNTIL can't be combined with PRUNE_VOLUMES".to_string());
+            }
+        }
+        Ok(Pruner {
+            spec: spec.trim().to_string(),
+            schedule,
+            until: until.map(str::to_string),
+            all,
+            volumes,
+            use_sudo: env_flag("USE_SUDO"),
+        })
+    }
+
+    // For the boot log and the effective config
+    pub fn describe(&self) -> String {
+        let when = match &self.schedule {
+            Schedule::Every(interval) => format!("every {}s", interval.as_secs()),
+            Schedule::Cron(_) => format!("on cron '{}' (UTC)", self.spec),
+        };
+        format!("{}: {}", when, self.command())
+    }
+
+    fn command(&self) -> String {
+        let mut cmd = format!("{} system prune -f", docker_bin(self.use_sudo));
+        if self.all {
+            cmd.push_str(" --all");
+        }
+        if self.volumes {
+            cmd.push_str(" --volumes");
+        }
+        if let Some(until) = &self.until {
+            cmd.push_str(&format!(" --filter until={}", until));
+        }
+        cmd
+    }
+
+    // Runs until the process exits; `busy` reports whether a deploy is in progress
+    pub async fn run(self, busy: impl Fn() -> bool) {
+        loop {
+            tokio::time::sleep(self.schedule.wait(unix_now())).await;
+            while busy() {
+                tokio::time::sleep(BUSY_RECHECK).await;
+            }
+            self.prune().await;
+        }
+    }
+
+    async fn prune(&self) {
+        let cmd = self.command();
+        match Command::new("sh").arg("-c").arg(&cmd).output().await {
+            Ok(out) if out.status.success() => {
+                let stdout = String::from_utf8_lossy(&out.stdout);
+                info!("🧹 {}: reclaimed {}", cmd, reclaimed(&stdout).unwrap_or("nothing reported"));
+            }
+            Ok(out) => warn!("⚠️ {} failed: {}", cmd, String::from_utf8_lossy(&out.stderr).trim()),
+            Err(e) => warn!("⚠️ Failed to run {}: {}", cmd, e),
+        }
+    }
+}
+
+// "Total reclaimed space: 1.2GB" closes docker's output
+fn reclaimed(stdout: &str) -> Option<&str> {
+    stdout
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix("Total reclaimed space:"))
+        .map(str::trim)
+}
+
+impl Schedule {
+    // An interval ("6h", "30m", "1d", or plain seconds) of at least a minute, or a cron expression
+    fn parse(value: &str) -> Result<Self, String> {
+        if value.contains(char::is_whitespace) {
+            return Cron::parse(value).map(Schedule::Cron);
+        }
+        let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+        let scale = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            _ => return Err(format!("unknown unit '{}'; use s, m, h or d", unit)),
+        };
+        let secs = number.parse::<u64>().map_err(|_| "expected an interval like 6h or a cron expression".to_string())?;
+        match secs.checked_mul(scale) {
+            Some(secs) if secs >= 60 => Ok(Schedule::Every(Duration::from_secs(secs))),
+            _ => Err("the interval must be at least a minute".to_string()),
+        }
+    }
+
+    fn wait(&self, now: u64) -> Duration {
+        match self {
+            Schedule::Every(interval) => *interval,
+            Schedule::Cron(cron) => Duration::from_secs(cron.next_after(now).map_or(86400, |at| at - now)),
+        }
+    }
+}
+
+impl Cron {
+    fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("expected 5 cron fields, got {}", fields.len()));
+        };
+        // 7 is Sunday too
+        let weekdays = cron_field(weekday, 0, 7, "day of week")?;
+        Ok(Cron {
+            minutes: cron_field(minute, 0, 59, "minute")?,
+            hours: cron_field(hour, 0, 23, "hour")?,
+            days: cron_field(day, 1, 31, "day of month")?,
+            months: cron_field(month, 1, 12, "month")?,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    // The next matching minute after `unix`, looking up to a year ahead (so "0 0 30 2 *" never runs)
+    fn next_after(&self, unix: u64) -> Option<u64> {
+        let limit = unix + 366 * 86400;
+        let mut at = (unix / 60 + 1) * 60;
+        while at <= limit {
+            let days = (at / 86400) as i64;
+            let (_, month, day) = civil_from_days(days);
+            // The epoch was a Thursday
+            let weekday = (days + 4) % 7;
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (false, true) => bit(self.days, day),
+                (true, false) => bit(self.weekdays, weekday as u32),
+                (false, false) => bit(self.days, day) || bit(self.weekdays, weekday as u32),
+            };
+            if !bit(self.months, month) || !day_matches {
+                at = (days as u64 + 1) * 86400;
+                continue;
+            }
+            let (hour, minute) = ((at % 86400 / 3600) as u32, (at % 3600 / 60) as u32);
+            if !bit(self.hours, hour) {
+                at = (at / 3600 + 1) * 3600;
+                continue;
+            }
+            if bit(self.minutes, minute) {
+                return Some(at);
+            }
+            at += 60;
+        }
+        None
+    }
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+fn cron_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid {} field '{}'", name, field);
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?),
+            None => {
+                let n = range.parse().map_err(|_| invalid())?;
+                (n, if step > 1 { max } else { n })
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedules_parse_as_intervals_or_cron() {
+        assert_eq!(Schedule::parse("6h"), Ok(Schedule::Every(Duration::from_secs(6 * 3600))));
+        assert_eq!(Schedule::parse("300"), Ok(Schedule::Every(Duration::from_secs(300))));
+        assert!(Schedule::parse("30s").is_err());
+        assert!(Schedule::parse("2w").is_err());
+        assert!(Schedule::parse("0 25 * * *").is_err());
+
+        // 03:30 every Sunday; 2026-10-14 12:00 UTC is a Wednesday
+        let Schedule::Cron(cron) = Schedule::parse("30 3 * * 0").unwrap() else {
+            panic!("expected a cron schedule");
+        };
+        let wednesday_noon = 1_791_979_200;
+        assert_eq!(cron.next_after(wednesday_noon), Some(wednesday_noon + 3 * 86400 + 15 * 3600 + 30 * 60));
+        let Schedule::Cron(every_quarter) = Schedule::parse("*/15 * * * 7").unwrap() else {
+            panic!("expected a cron schedule");
+        };
+        assert_eq!(every_quarter.weekdays, 1);
+    }
+
+    #[test]
+    fn prune_command_only_removes_volumes_when_asked() {
+        let pruner = Pruner::new("1d", Some("24h"), true, false).unwrap();
+        assert_eq!(pruner.command(), "docker system prune -f --all --filter until=24h");
+        assert!(!Pruner::new("1d", None, false, false).unwrap().command().contains("--volumes"));
+        assert!(Pruner::new("1d", Some("24h"), false, true).is_err());
+        assert!(Pruner::new("1d", Some("24h; rm -rf /"), false, false).is_err());
+
+        let stdout = "Deleted Images:\nuntagged: app:old\n\nTotal reclaimed space: 1.2GB\n";
+        assert_eq!(reclaimed(stdout), Some("1.2GB"));
+    }
+}
@@ -0,0 +1,176 @@
+// Deploy worker slots (WORKER_COUNT) shared round-robin between projects, so one project
+// queueing many deploys can't starve the others
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+pub struct WorkerPool {
+    size: usize,
+    inner: Arc<Mutex<Pool>>,
+}
+
+#[derive(Default)]
+struct Pool {
+    free: usize,
+    // One FIFO per project; a slot goes to the next project after `last` that has a waiter
+    waiting: BTreeMap<String, VecDeque<oneshot::Sender<()>>>,
+    last: Option<String>,
+}
+
+// A held worker slot, handed to the next waiter when dropped
+pub struct Worker {
+    pool: Arc<Mutex<Pool>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        release(&self.pool);
+    }
+}
+
+// Queued for a slot; a waiter dropped after being handed one passes it on
+struct Waiter {
+    rx: oneshot::Receiver<()>,
+    pool: Arc<Mutex<Pool>>,
+    done: bool,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if !self.done {
+            self.rx.close();
+            if self.rx.try_recv().is_ok() {
+                release(&self.pool);
+            }
+        }
+    }
+}
+
+fn release(pool: &Mutex<Pool>) {
+    let mut pool = pool.lock().unwrap();
+    loop {
+        let next = match &pool.last {
+            Some(last) => pool
+                .waiting
+                .range::<String, _>((Bound::Excluded(last), Bound::Unbounded))
+                .next()
+                .or_else(|| pool.waiting.iter().next()),
+            None => pool.waiting.iter().next(),
+        }
+        .map(|(project, _)| project.clone());
+        let Some(project) = next else {
+            pool.free += 1;
+            return;
+        };
+        let queue = pool.waiting.get_mut(&project).expect("picked from the map");
+        let sender = queue.pop_front().expect("empty queues are removed");
+        if queue.is_empty() {
+            pool.waiting.remove(&project);
+        }
+        pool.last = Some(project);
+        // Fails only when the waiter gave up; try the next one
+        if sender.send(()).is_ok() {
+            return;
+        }
+    }
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        WorkerPool {
+            size,
+            inner: Arc::new(Mutex::new(Pool {
+                free: size,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn busy(&self) -> usize {
+        self.size - self.inner.lock().unwrap().free
+    }
+
+    // Deploys waiting for a slot, per project
+    pub fn queue_depths(&self) -> BTreeMap<String, usize> {
+        let pool = self.inner.lock().unwrap();
+        pool.waiting
+            .iter()
+            .map(|(project, queue)| (project.clone(), queue.iter().filter(|tx| !tx.is_closed()).count()))
+            .filter(|(_, depth)| *depth > 0)
+            .collect()
+    }
+
+    pub async fn acquire(&self, project: &str) -> Worker {
+        let rx = {
+            let mut pool = self.inner.lock().unwrap();
+            if pool.free > 0 && pool.waiting.is_empty() {
+                pool.free -= 1;
+                return Worker { pool: self.inner.clone() };
+            }
+            let (tx, rx) = oneshot::channel();
+            pool.waiting.entry(project.to_string()).or_default().push_back(tx);
+            rx
+        };
+        let mut waiter = Waiter {
+            rx,
+            pool: self.inner.clone(),
+            done: false,
+        };
+        // The sender is only dropped unsent by a waiter's own cancellation
+        let _ = (&mut waiter.rx).await;
+        waiter.done = true;
+        Worker { pool: self.inner.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn slots_alternate_between_projects() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let held = pool.acquire("busy").await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (i, project) in ["busy", "busy", "busy", "quiet"].into_iter().enumerate() {
+            let (shared, order) = (pool.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _worker = shared.acquire(project).await;
+                order.lock().unwrap().push(format!("{}{}", project, i));
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }));
+            while pool.queue_depths().values().sum::<usize>() < i + 1 {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert_eq!(pool.queue_depths(), BTreeMap::from([("busy".to_string(), 3), ("quiet".to_string(), 1)]));
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["busy0", "quiet3", "busy1", "busy2"]);
+        assert_eq!(pool.busy(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_waiter_does_not_lose_the_slot() {
+        let pool = WorkerPool::new(1);
+        let held = pool.acquire("a").await;
+        let waiting = tokio::time::timeout(Duration::from_millis(10), pool.acquire("b")).await;
+        assert!(waiting.is_err());
+        assert!(pool.queue_depths().is_empty());
+        drop(held);
+        let _worker = pool.acquire("c").await;
+        assert_eq!(pool.busy(), 1);
+    }
+}
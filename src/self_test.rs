@@ -0,0 +1,75 @@
+// Startup self-test (--self-test / SELF_TEST=1): checks the host can actually deploy, then exits
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::config::ConfigSource;
+
+// Ok carries a short detail for the report, Err the reason the check failed
+type Check = Result<String, String>;
+
+async fn run_check(program: &str, args: &[&str]) -> Check {
+    match Command::new(program).args(args).output().await {
+        Ok(out) if out.status.success() => {
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            Ok(stdout.lines().next().unwrap_or("").trim().to_string())
+        }
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            Err(format!("exited with {}: {}", out.status, stderr.lines().last().unwrap_or("").trim()))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn report(name: &str, check: &Check) {
+    match check {
+        Ok(detail) if detail.is_empty() => println!("✅ {}", name),
+        Ok(detail) => println!("✅ {}: {}", name, detail),
+        Err(reason) => println!("❌ {}: {}", name, reason),
+    }
+}
+
+// Prints one line per check; true when every check passed
+pub async fn self_test(source: &ConfigSource) -> bool {
+    let mut passed = true;
+
+    // `docker version` (not --version) also needs the daemon to answer
+    for (name, check) in [
+        ("docker", run_check("docker", &["version", "--format", "{{.Server.Version}}"]).await),
+        ("git", run_check("git", &["--version"]).await),
+    ] {
+        report(name, &check);
+        passed &= check.is_ok();
+    }
+
+    let config = match source.load() {
+        Ok(config) => {
+            report("config", &Ok(format!("{} project(s)", config.len())));
+            config
+        }
+        Err(e) => {
+            report("config", &Err(e));
+            return false;
+        }
+    };
+
+    let mut names: Vec<&String> = config.keys().collect();
+    names.sort();
+    for name in names {
+        let entry = &config[name];
+        let mut missing: Vec<&str> = [entry.path(), entry.repo_path(), entry.compose_path()]
+            .into_iter()
+            .filter(|p| !Path::new(p).is_dir())
+            .collect();
+        missing.dedup();
+        let check = if missing.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(format!("missing {}", missing.join(", ")))
+        };
+        report(&format!("project {}", name), &check);
+        passed &= check.is_ok();
+    }
+
+    passed
+}
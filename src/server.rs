@@ -0,0 +1,2693 @@
+// HTTP handlers and shared server state
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{process::Command, sync::Semaphore};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::audit::AuditLog;
+use crate::statsd::Statsd;
+use crate::backup::{create_backup, prune_backups};
+use crate::config::{
+    adhoc_path_allowed, env_flag, find_project, is_valid_git_ref, is_valid_pr_number, is_valid_image_tag, repository_matches, ConfigFile, ConfigSource, ErrorPayload, FieldLimits, GitOptions,
+    CredentialKind, ProjectEntry, RegistryCredentials, WebhookPayload, COMPOSE_TOGGLES, DEPLOY_TYPES, DOCKER_TOKEN_VAR, GIT_TOKEN_VAR,
+};
+use crate::deploy::{
+    docker_bin, plan_job, readiness_checks, run_job, validate_compose_options, CommandRunner, CommitInfo, ComposeOptions, DeployError, DeployJob,
+    DeployOutcome, Exec, PhaseTimings, Progress, ServiceState, SmokeTestResult, SystemRunner, ValidationCache,
+};
+use crate::history::{History, HistoryEntry};
+use crate::metrics::Metrics;
+use crate::notify::{current_commit, notify_deploy, notify_deploy_started, publish_event, EventPublisher, Notifier, NotifySink};
+use crate::queue::WorkerPool;
+use crate::signature::{check_sources, constant_time_eq, verify_signature, BasicAuth, SignatureAlgorithm, SignatureSource};
+
+#[derive(Serialize, Debug, Clone)]
+struct DeployResponse {
+    status: &'static str,
+    message: String,
+    // Stable identifier for errors, so clients needn't match on `message`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<BTreeMap<String, u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<PhaseTimings>,
+    // Container states after `up`, when compose can report them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<ServiceState>>,
+    // Repo mode: the commit now checked out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<CommitInfo>,
+    // Services compose failed to start, when a compose failure could be narrowed down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_services: Option<Vec<String>>,
+    // Only with ?verbose=true; credentials are already redacted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<String>>,
+    // Tail of the failing command's stderr (see OUTPUT_TAIL_LINES)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    // Image mode: the tag pulled, from the payload's image_tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_tag: Option<String>,
+    // ssh_host projects: the remote host deployed to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    // build_login projects and login deploys: the registry logged into
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry_login: Option<String>,
+    // Login deploys: the user the credentials belong to (never the token)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login_user: Option<String>,
+    // smoke_test_command projects; a failed smoke test makes the status "success_with_warnings"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smoke_test: Option<SmokeTestResult>,
+    // Where the deploy request came from (see DeployJob::source)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    // restore_on_failure deploys that failed: whether the previous version is running again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restored: Option<bool>,
+    // Why a "skipped" request had nothing to deploy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+// Codes for request-level rejections; deploy failures use DeployError::code()
+fn error_code(status: StatusCode, message: &str) -> &'static str {
+    match message {
+        "Project not found in config" => "PROJECT_NOT_FOUND",
+        "Missing signature" => "SIGNATURE_MISSING",
+        "Invalid signature" | "Invalid signature format" => "SIGNATURE_INVALID",
+        "Missing API key" | "Invalid API key" | "Invalid credentials" => "UNAUTHORIZED",
+        "Repository does not match project configuration" => "REPOSITORY_MISMATCH",
+        "Invalid Type" | "Invalid mode" | "Missing mode parameter" => "INVALID_MODE",
+        "Invalid tag" | "Invalid image tag" => "INVALID_TAG",
+        "Deploy type not allowed for this project" => "TYPE_NOT_ALLOWED",
+        "Removing volumes requires confirmation" | "Deploy type requires confirmation for this project" => {
+            "CONFIRMATION_REQUIRED"
+        }
+        "Invalid compose profile" | "Invalid scale service name" | "Invalid scale count" | "Invalid compose override" => {
+            "INVALID_COMPOSE_OPTIONS"
+        }
+        "Server configuration error" => "SERVER_MISCONFIGURED",
+        "Request timed out" => "REQUEST_TIMED_OUT",
+        _ => match status {
+            StatusCode::UNAUTHORIZED => "UNAUTHORIZED",
+            StatusCode::FORBIDDEN => "FORBIDDEN",
+            StatusCode::NOT_FOUND => "NOT_FOUND",
+            StatusCode::UNPROCESSABLE_ENTITY => "INVALID_PAYLOAD",
+            s if s.is_client_error() => "BAD_REQUEST",
+            _ => "INTERNAL_ERROR",
+        },
+    }
+}
+
+// Body for the JSON-only endpoints' errors, matching DeployResponse's status/message/code
+fn json_error(status: StatusCode, message: &str) -> (StatusCode, Json<serde_json::Value>) {
+    let code = error_code(status, message);
+    (status, Json(serde_json::json!({ "status": "error", "message": message, "code": code })))
+}
+
+fn reply(code: StatusCode, message: &str) -> (StatusCode, Json<DeployResponse>) {
+    let status = match code {
+        StatusCode::ACCEPTED => "queued",
+        c if c.is_success() => "success",
+        _ => "error",
+    };
+    let body = DeployResponse {
+        status,
+        message: message.to_string(),
+        code: (status == "error").then(|| error_code(code, message)),
+        scale: None,
+        timings: None,
+        services: None,
+        commit: None,
+        failed_services: None,
+        commands: None,
+        output: None,
+        image_tag: None,
+        host: None,
+        registry_login: None,
+        login_user: None,
+        smoke_test: None,
+        source: None,
+        restored: None,
+        reason: None,
+    };
+    (code, Json(body))
+}
+
+// A webhook that was accepted but had nothing to deploy: status "skipped" with a machine-readable
+// reason, answered with SKIPPED_STATUS (200, or 202)
+fn skipped(state: &AppState, reason: &'static str, message: &str) -> (StatusCode, Json<DeployResponse>) {
+    let (code, Json(mut body)) = reply(state.skipped_status, message);
+    body.status = "skipped";
+    body.reason = Some(reason);
+    (code, Json(body))
+}
+
+// 404 by default; 200 "ignored" when this host is one of several receiving the same webhook
+fn unknown_project(state: &AppState, name: &str) -> (StatusCode, Json<DeployResponse>) {
+    if state.ignore_unknown_projects {
+        info!("Ignoring deploy for unknown project '{}'", name);
+        let (code, Json(mut body)) = reply(StatusCode::OK, "Ignored: project not configured on this host");
+        body.status = "ignored";
+        return (code, Json(body));
+    }
+    error!("Project '{}' not found in config", name);
+    reply(StatusCode::NOT_FOUND, "Project not found in config")
+}
+
+// Final response for a finished deploy, reporting the applied scale on success
+fn deploy_reply(result: &DeployOutcome, job: &DeployJob, commands: Vec<String>) -> (StatusCode, Json<DeployResponse>) {
+    let (code, Json(mut body)) = match result {
+        Ok(report) => {
+            let (code, Json(mut body)) = reply(StatusCode::OK, report.message);
+            body.timings = report.timings.clone();
+            body.services = report.services.clone();
+            body.commit = report.commit.clone();
+            body.registry_login = report.registry_login.clone();
+            body.login_user = report.login_user.clone();
+            if let Some(smoke) = &report.smoke_test {
+                if !smoke.passed {
+                    body.status = "success_with_warnings";
+                }
+                body.smoke_test = Some(smoke.clone());
+            }
+            body.image_tag = job.compose.image_tag.clone();
+            if !job.compose.scale.is_empty() {
+                body.scale = Some(job.compose.scale.clone());
+            }
+            (code, Json(body))
+        }
+        Err(e) => {
+            // Misconfiguration the caller can act on, not a server fault
+            let status = match e {
+                DeployError::ComposeFileMissing(_) | DeployError::NotWritable(..) => StatusCode::UNPROCESSABLE_ENTITY,
+                DeployError::ProjectBusy(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let (code, Json(mut body)) = reply(status, &e.to_string());
+            body.code = Some(e.code());
+            body.output = e.stderr_tail().map(str::to_string);
+            body.restored = e.restored();
+            if !e.failed_services().is_empty() {
+                body.failed_services = Some(e.failed_services().to_vec());
+            }
+            (code, Json(body))
+        }
+    };
+    body.host = job.compose.ssh_host.clone();
+    body.source = Some(job.source.clone());
+    if job.verbose {
+        body.commands = Some(commands);
+    }
+    (code, Json(body))
+}
+
+const MAX_SOURCE_LEN: usize = 64;
+
+// X-Deploy-Source names the sender for deploys without a payload `source`; `default` is the kind of endpoint.
+// Printable and short, since it lands in logs, history and notification text.
+fn request_source(headers: &HeaderMap, default: &str) -> String {
+    let source: String = headers
+        .get("x-deploy-source")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_SOURCE_LEN)
+        .collect();
+    if source.is_empty() {
+        default.to_string()
+    } else {
+        source
+    }
+}
+
+// Rejects a webhook deploy type the project's allowed_types leaves out
+fn check_type_allowed(name: &str, entry: &ProjectEntry, mode: &str) -> Result<(), (StatusCode, &'static str)> {
+    if entry.allows_type(mode) {
+        return Ok(());
+    }
+    warn!("🚫 Refusing {} of '{}': not in its allowed_types", mode, name);
+    Err((StatusCode::FORBIDDEN, "Deploy type not allowed for this project"))
+}
+
+// Rejects an unconfirmed deploy of a type in the project's confirm_types. Only JSON payloads can
+// confirm, so signed query, manual and git-host deploys of those types are always refused.
+fn check_confirmed(
+    name: &str,
+    entry: &ProjectEntry,
+    mode: &str,
+    payload: Option<&WebhookPayload>,
+) -> Result<(), (StatusCode, &'static str)> {
+    if !entry.requires_confirm(mode) || payload.is_some_and(WebhookPayload::confirms) {
+        return Ok(());
+    }
+    warn!("🚫 Refusing {} of '{}' without confirmation (confirm_types)", mode, name);
+    Err((StatusCode::BAD_REQUEST, "Deploy type requires confirmation for this project"))
+}
+
+// Rejects a deploy whose repository doesn't match the project's expected_repository
+fn check_repository(
+    name: &str,
+    entry: &ProjectEntry,
+    actual: Option<&str>,
+) -> Result<(), (StatusCode, &'static str)> {
+    match entry.expected_repository() {
+        Some(expected) if !repository_matches(expected, actual) => {
+            warn!(
+                "🚫 Repository mismatch for '{}': expected {}, got {}",
+                name,
+                expected,
+                actual.unwrap_or("<none>")
+            );
+            Err((StatusCode::FORBIDDEN, "Repository does not match project configuration"))
+        }
+        _ => Ok(()),
+    }
+}
+
+const DEFAULT_WORKER_COUNT: usize = 2;
+
+pub struct AppState {
+    // Swapped wholesale on reload; handlers work on the snapshot they started with
+    config: RwLock<Arc<ConfigFile>>,
+    config_source: Option<ConfigSource>,
+    pub(crate) notifier: Option<Notifier>,
+    // EVENTS_URL: structured started/succeeded/failed events for an event pipeline
+    pub(crate) events: Option<Box<dyn NotifySink>>,
+    metrics: Arc<Metrics>,
+    runtime: Mutex<HashMap<String, ProjectRuntime>>,
+    // HISTORY_MAX / HISTORY_FILE: recent deploys per project, served by GET /history/{project}
+    history: Arc<History>,
+    // NORMALIZE_PROJECT_NAMES: see config::find_project
+    normalize_project_names: bool,
+    // REJECT_BLANK_FIELDS: a blank optional payload field is a 422 instead of unset
+    reject_blank_fields: bool,
+    // MAX_FIELD_LENGTH / MAX_TOKEN_LENGTH for JSON payload fields
+    field_limits: FieldLimits,
+    // IGNORE_UNKNOWN_PROJECTS: answer 200 "ignored" instead of 404 so fan-out senders don't retry
+    ignore_unknown_projects: bool,
+    runner: Arc<dyn CommandRunner>,
+    // DEPLOY_TIMEOUT_SECS; unset or 0 means no limit
+    deploy_timeout: Option<Duration>,
+    // WEBHOOK_SIGNATURES: body signatures required on JSON webhooks, one entry per git host
+    signature_sources: Vec<SignatureSource>,
+    // API_KEY: bearer token for the read-only API endpoints
+    api_key: Option<String>,
+    // WEBHOOK_BASIC_USER / WEBHOOK_BASIC_PASSWORD
+    basic_auth: Option<BasicAuth>,
+    audit: Option<AuditLog>,
+    // STATSD_ADDR: push deploy counters and timers over UDP
+    statsd: Option<Statsd>,
+    // One lock per lock_group, created on first use
+    group_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    // LOCK_WAIT_TIMEOUT_SECS: how long a deploy waits for its project's (or lock group's) running
+    // deploy before giving up with 409 PROJECT_BUSY; None waits indefinitely
+    lock_wait: Option<Duration>,
+    // WORKER_COUNT: deploys allowed to run at once across all projects, handed out round-robin
+    workers: WorkerPool,
+    // Server-wide pause set by POST /pause; in memory only, a restart resumes deploys
+    pause: Mutex<Option<Pause>>,
+    // WRITE_STATUS_FILE: after each deploy, write the result to <path>/.graft-status.json
+    write_status_file: bool,
+    // LENIENT_CONTENT_TYPE: parse webhook bodies as JSON whatever Content-Type the client sent
+    lenient_content_type: bool,
+    // ALLOW_ADHOC_PATHS: JSON payloads may name a `path` under one of ADHOC_PATH_PREFIXES (comma-separated)
+    adhoc_path_prefixes: Option<Vec<String>>,
+    // Compose files that passed `config -q`, so unchanged ones aren't re-validated every deploy
+    compose_validations: Arc<ValidationCache>,
+    // Idempotency-Key responses by (project, key), kept for IDEMPOTENCY_TTL_SECS
+    idempotency: Mutex<HashMap<(String, String), Idempotent>>,
+    idempotency_ttl: Duration,
+    // DEDUPE_WINDOW_SECS: JSON payloads without an Idempotency-Key are keyed by their body's
+    // sha256 for this long, so a sender retrying the same body doesn't deploy twice; 0 disables
+    dedupe_window: Duration,
+    // WAIT_FOR_DOCKER_SECS: false until `docker info` answers (or the wait runs out); deploys wait for it
+    docker_ready: Arc<tokio::sync::watch::Sender<bool>>,
+    // SKIPPED_STATUS: HTTP status for skipped webhooks, 200 (default) or 202
+    skipped_status: StatusCode,
+    // BATCH_FAILED_STATUS: status of a waited-for /deploy/all batch in which every deploy failed
+    batch_failed_status: StatusCode,
+    // The last RECENT_DEPLOYS_SIZE finished deploys across all projects, written to
+    // LAST_DEPLOYS_DUMP on shutdown for post-mortems
+    recent_deploys: Mutex<VecDeque<serde_json::Value>>,
+    recent_deploys_size: usize,
+    last_deploys_dump: Option<String>,
+}
+
+enum Idempotent {
+    InFlight,
+    Done {
+        at: Instant,
+        // IDEMPOTENCY_TTL_SECS, or DEDUPE_WINDOW_SECS for body hashes
+        ttl: Duration,
+        code: StatusCode,
+        body: Box<DeployResponse>,
+    },
+}
+
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 3600;
+const DEFAULT_RECENT_DEPLOYS_SIZE: usize = 100;
+const MAX_COMPOSE_OVERRIDE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+struct Pause {
+    reason: String,
+    since: u64,
+}
+
+fn skipped_status_from_env() -> StatusCode {
+    match env::var("SKIPPED_STATUS").ok().as_deref().map(str::trim) {
+        None | Some("") | Some("200") => StatusCode::OK,
+        Some("202") => StatusCode::ACCEPTED,
+        Some(other) => {
+            warn!("Ignoring SKIPPED_STATUS={}: expected 200 or 202", other);
+            StatusCode::OK
+        }
+    }
+}
+
+const DEFAULT_LOCK_WAIT_TIMEOUT_SECS: u64 = 300;
+
+// Seconds, where 0 means don't wait at all; waiting indefinitely has to be asked for with "none"
+fn lock_wait_from_env() -> Option<Duration> {
+    let default = Some(Duration::from_secs(DEFAULT_LOCK_WAIT_TIMEOUT_SECS));
+    match env::var("LOCK_WAIT_TIMEOUT_SECS").ok().as_deref().map(str::trim) {
+        None | Some("") => default,
+        Some("none") => None,
+        Some(raw) => match raw.parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                warn!(
+                    "Ignoring LOCK_WAIT_TIMEOUT_SECS={}: expected seconds or \"none\"; using {}s",
+                    raw, DEFAULT_LOCK_WAIT_TIMEOUT_SECS
+                );
+                default
+            }
+        },
+    }
+}
+
+// Waits for a project or lock group lock, up to LOCK_WAIT_TIMEOUT_SECS
+async fn wait_for_lock<T>(state: &AppState, lock: impl std::future::Future<Output = T>) -> Result<T, DeployError> {
+    match state.lock_wait {
+        None => Ok(lock.await),
+        Some(wait) => tokio::time::timeout(wait, lock).await.map_err(|_| DeployError::ProjectBusy(wait.as_secs())),
+    }
+}
+
+// Defaults to 500, what a single failed deploy answers; any 4xx or 5xx is accepted
+fn batch_failed_status_from_env() -> StatusCode {
+    match env::var("BATCH_FAILED_STATUS").ok().as_deref().map(str::trim) {
+        None | Some("") => StatusCode::INTERNAL_SERVER_ERROR,
+        Some(raw) => match raw.parse::<u16>().ok().and_then(|n| StatusCode::from_u16(n).ok()) {
+            Some(status) if status.is_client_error() || status.is_server_error() => status,
+            _ => {
+                warn!("Ignoring BATCH_FAILED_STATUS={}: expected a 4xx or 5xx status", raw);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    }
+}
+
+impl AppState {
+    // Reads the optional features (notifications, timeout, unknown-project handling) from the environment
+    pub fn from_env(config: ConfigFile) -> Self {
+        let ignore_unknown_projects = env_flag("IGNORE_UNKNOWN_PROJECTS");
+        if ignore_unknown_projects {
+            info!("Unknown projects will be answered with 200 \"ignored\"");
+        }
+
+        let notifier = Notifier::from_env();
+
+        let signature_sources: Vec<SignatureSource> = match env::var("WEBHOOK_SIGNATURES") {
+            Ok(json) if !json.trim().is_empty() => {
+                serde_json::from_str(&json).expect("CRITICAL: WEBHOOK_SIGNATURES is not a valid source list")
+            }
+            _ => Vec::new(),
+        };
+        if !signature_sources.is_empty() {
+            info!("🔏 Requiring webhook signatures from {} source(s)", signature_sources.len());
+        }
+
+        let worker_count = env::var("WORKER_COUNT")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_WORKER_COUNT);
+        info!("Running up to {} deploy(s) at once", worker_count);
+
+        let adhoc_path_prefixes = env_flag("ALLOW_ADHOC_PATHS").then(|| {
+            let prefixes: Vec<String> = env::var("ADHOC_PATH_PREFIXES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|p| p.trim().trim_end_matches('/').to_string())
+                .filter(|p| !p.is_empty())
+                .collect();
+            if prefixes.is_empty() {
+                warn!("⚠️ ALLOW_ADHOC_PATHS is set but ADHOC_PATH_PREFIXES is empty; every ad-hoc path will be refused");
+            } else {
+                info!("Ad-hoc deploy paths allowed under {}", prefixes.join(", "));
+            }
+            prefixes
+        });
+
+        let docker_wait = env::var("WAIT_FOR_DOCKER_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs);
+        let docker_ready = Arc::new(tokio::sync::watch::Sender::new(docker_wait.is_none()));
+        if let Some(wait) = docker_wait {
+            tokio::spawn(wait_for_docker(docker_ready.clone(), wait));
+        }
+
+        AppState {
+            config: RwLock::new(Arc::new(config)),
+            config_source: None,
+            notifier,
+            events: EventPublisher::from_env().map(|p| Box::new(p) as Box<dyn NotifySink>),
+            metrics: Arc::new(Metrics::default()),
+            runtime: Mutex::new(HashMap::new()),
+            history: Arc::new(History::from_env()),
+            ignore_unknown_projects,
+            normalize_project_names: env_flag("NORMALIZE_PROJECT_NAMES"),
+            reject_blank_fields: env_flag("REJECT_BLANK_FIELDS"),
+            field_limits: {
+                let limit = |name: &str, default: usize| {
+                    env::var(name)
+                        .ok()
+                        .and_then(|v| v.trim().parse().ok())
+                        .filter(|&n| n > 0)
+                        .unwrap_or(default)
+                };
+                let defaults = FieldLimits::default();
+                FieldLimits {
+                    max_len: limit("MAX_FIELD_LENGTH", defaults.max_len),
+                    max_token_len: limit("MAX_TOKEN_LENGTH", defaults.max_token_len),
+                }
+            },
+            runner: Arc::new(SystemRunner),
+            deploy_timeout: env::var("DEPLOY_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            signature_sources,
+            api_key: env::var("API_KEY").ok().filter(|k| !k.is_empty()),
+            basic_auth: match (env::var("WEBHOOK_BASIC_USER"), env::var("WEBHOOK_BASIC_PASSWORD")) {
+                (Ok(user), Ok(password)) if !password.is_empty() => Some(BasicAuth { user, password }),
+                _ => None,
+            },
+            audit: AuditLog::from_env(),
+            statsd: Statsd::from_env(),
+            workers: WorkerPool::new(worker_count),
+            pause: Mutex::new(None),
+            lenient_content_type: env_flag("LENIENT_CONTENT_TYPE"),
+            write_status_file: env_flag("WRITE_STATUS_FILE"),
+            adhoc_path_prefixes,
+            compose_validations: Arc::new(ValidationCache::default()),
+            idempotency: Mutex::new(HashMap::new()),
+            idempotency_ttl: Duration::from_secs(
+                env::var("IDEMPOTENCY_TTL_SECS")
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECS),
+            ),
+            dedupe_window: Duration::from_secs(
+                env::var("DEDUPE_WINDOW_SECS")
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(0),
+            ),
+            docker_ready,
+            skipped_status: skipped_status_from_env(),
+            batch_failed_status: batch_failed_status_from_env(),
+            recent_deploys: Mutex::new(VecDeque::new()),
+            group_locks: Mutex::new(HashMap::new()),
+            lock_wait: lock_wait_from_env(),
+            recent_deploys_size: env::var("RECENT_DEPLOYS_SIZE")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(DEFAULT_RECENT_DEPLOYS_SIZE),
+            last_deploys_dump: env::var("LAST_DEPLOYS_DUMP").ok().filter(|p| !p.trim().is_empty()),
+        }
+    }
+
+    // Settings read from the environment, for the startup banner; secrets only show as set or not
+    pub fn effective_config(&self) -> serde_json::Value {
+        serde_json::json!({
+            "config": self.config_source.as_ref().map(ConfigSource::describe),
+            "projects": self.config().len(),
+            "deploy_timeout_secs": self.deploy_timeout.map(|t| t.as_secs()),
+            "lock_wait_timeout_secs": self.lock_wait.map(|t| t.as_secs()),
+            "workers": self.workers.size(),
+            "notifications": self.notifier.is_some(),
+            "events": self.events.is_some(),
+            "audit_log": self.audit.is_some(),
+            "statsd": self.statsd.is_some(),
+            "signature_sources": self.signature_sources.len(),
+            "api_key": self.api_key.is_some(),
+            "basic_auth": self.basic_auth.is_some(),
+            "ignore_unknown_projects": self.ignore_unknown_projects,
+            "normalize_project_names": self.normalize_project_names,
+            "reject_blank_fields": self.reject_blank_fields,
+            "lenient_content_type": self.lenient_content_type,
+            "write_status_file": self.write_status_file,
+            "adhoc_path_prefixes": self.adhoc_path_prefixes,
+            "idempotency_ttl_secs": self.idempotency_ttl.as_secs(),
+            "dedupe_window_secs": self.dedupe_window.as_secs(),
+            "docker_ready": *self.docker_ready.borrow(),
+            "skipped_status": self.skipped_status.as_u16(),
+            "batch_failed_status": self.batch_failed_status.as_u16(),
+            "recent_deploys_size": self.recent_deploys_size,
+            "history_max": self.history.max(),
+            "last_deploys_dump": self.last_deploys_dump,
+        })
+    }
+
+    // Where reloads (SIGHUP, POST /reload) read the config from
+    pub fn with_config_source(mut self, source: ConfigSource) -> Self {
+        self.config_source = Some(source);
+        self
+    }
+
+    // Projects with a deploy executing right now, sorted; used to report abandoned deploys on shutdown
+    pub fn deploying_projects(&self) -> Vec<String> {
+        let runtime = self.runtime.lock().unwrap();
+        let mut projects: Vec<String> = runtime
+            .iter()
+            .filter(|(_, slot)| slot.in_flight > 0)
+            .map(|(name, _)| name.clone())
+            .collect();
+        projects.sort();
+        projects
+    }
+
+    fn record_recent_deploy(&self, event: serde_json::Value) {
+        if self.recent_deploys_size == 0 {
+            return;
+        }
+        let mut recent = self.recent_deploys.lock().unwrap();
+        while recent.len() >= self.recent_deploys_size {
+            recent.pop_front();
+        }
+        recent.push_back(event);
+    }
+
+    // Writes the recent-deploys ring (oldest first) to LAST_DEPLOYS_DUMP; called once on shutdown
+    pub fn dump_recent_deploys(&self) {
+        let Some(path) = &self.last_deploys_dump else {
+            return;
+        };
+        let recent: Vec<serde_json::Value> = self.recent_deploys.lock().unwrap().iter().cloned().collect();
+        let body = serde_json::json!({ "dumped_at": unix_now(), "deploys": recent });
+        let tmp = format!("{}.tmp", path);
+        let written = serde_json::to_vec_pretty(&body)
+            .map_err(std::io::Error::other)
+            .and_then(|bytes| std::fs::write(&tmp, bytes))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        match written {
+            Ok(()) => info!("📝 Wrote the last {} deploy(s) to {}", recent.len(), path),
+            Err(e) => warn!("⚠️ Failed to write LAST_DEPLOYS_DUMP {}: {}", path, e),
+        }
+    }
+
+    pub(crate) fn config(&self) -> Arc<ConfigFile> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Re-reads the config source and swaps it in; the old config stays on any error
+    pub fn reload_config(&self) -> Result<usize, String> {
+        let source = self.config_source.as_ref().ok_or("No config source to reload from")?;
+        match source.load() {
+            Ok(config) => {
+                let count = config.len();
+                *self.config.write().unwrap() = Arc::new(config);
+                info!("🔄 Reloaded config: {} project(s)", count);
+                Ok(count)
+            }
+            Err(e) => {
+                error!("❌ Config reload failed, keeping the current config: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+// One access-log line per request, whatever route (or 404) it hit
+pub async fn access_log(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let started = std::time::Instant::now();
+    let response = next.run(request).await;
+    info!(
+        "{} {} -> {} in {:.1}ms",
+        method,
+        path,
+        response.status().as_u16(),
+        started.elapsed().as_secs_f64() * 1000.0
+    );
+    response
+}
+
+// REQUEST_TIMEOUT_SECS: answers 504 when a request takes longer, e.g. a credential command or
+// upstream that hangs before the deploy starts. The handler runs on its own task and is left to
+// finish, so a deploy that did start still completes and records its result.
+pub async fn request_timeout(
+    State(timeout): State<Duration>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let mut handler = tokio::spawn(next.run(request));
+    match tokio::time::timeout(timeout, &mut handler).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            error!("Handler for {} failed: {}", path, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response()
+        }
+        Err(_) => {
+            warn!("⏱️ {} took longer than {}s; answering 504 and letting it finish", path, timeout.as_secs());
+            json_error(StatusCode::GATEWAY_TIMEOUT, "Request timed out").into_response()
+        }
+    }
+}
+
+// Every endpoint on one listener (the default)
+pub fn router(state: Arc<AppState>) -> Router {
+    public_router(state.clone()).merge(internal_router(state))
+}
+
+// What senders need to reach: webhooks, manual deploys and build error reports
+pub fn public_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/webhook", post(handle_deploy))
+        .route("/webhook/bitbucket", post(handle_bitbucket))
+        .route("/webhook/github", post(handle_github))
+        .route("/plan", post(handle_plan))
+        .route("/webhook/test", post(handle_test))
+        .route("/deploy/{project}", post(handle_manual_deploy))
+        .route("/builderror", post(handle_error))
+        .route("/schema", get(handle_schema))
+        .with_state(state)
+}
+
+// Operational endpoints, served separately when INTERNAL_LISTEN_ADDR is set
+pub fn internal_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/health", get(handle_health))
+        .route("/status/{project}", get(handle_status))
+        .route("/logs/{project}", get(handle_logs))
+        .route("/history/{project}", get(handle_history))
+        .route("/metrics", get(handle_metrics))
+        .route("/stats", get(handle_stats))
+        .route("/projects", get(handle_projects))
+        .route("/deploy/all", post(handle_deploy_all))
+        .route("/projects/{project}/effective", get(handle_project_effective))
+        .route("/projects/{project}/reset", post(handle_reset))
+        .route("/projects/{project}/rollback", post(handle_rollback))
+        .route("/projects/{project}/redeploy-last-good", post(handle_redeploy_last_good))
+        .route("/reload", post(handle_reload))
+        .route("/pause", post(handle_pause))
+        .route("/resume", post(handle_resume))
+        .route("/", get(handle_dashboard))
+        .with_state(state)
+}
+
+// Live per-project deploy state
+#[derive(Default)]
+struct ProjectRuntime {
+    // Deploys currently executing, across all dispatch paths
+    in_flight: u32,
+    // Coalescing slot: set while a coalesced deploy (or its follow-up) runs
+    running: bool,
+    pending: Option<DeployJob>,
+    // Kept after later successes so recurring problems stay visible
+    last_error: Option<LastError>,
+    last_deploy: Option<LastDeploy>,
+    // Full command output of the latest deploy, served by GET /logs/{project}
+    last_log: Option<String>,
+    // Circuit breaker: failures since the last success, and when a tripped breaker reopens
+    consecutive_failures: u32,
+    paused_until: Option<u64>,
+    // Sized from max_concurrency on first use
+    permits: Option<Arc<Semaphore>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct LastError {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr_tail: Option<String>,
+    occurred_at: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct LastDeploy {
+    status: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<CommitInfo>,
+    finished_at: u64,
+}
+
+// Verifies X-Hub-Signature-256 over the sorted query string, keyed by the token for the requested mode
+fn check_query_signature(
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    mode_key: &str,
+) -> Result<String, (StatusCode, &'static str)> {
+    // Get signature from header
+    let signature = match headers.get("x-hub-signature-256") {
+        Some(sig) => match sig.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                error!("Invalid signature header format");
+                return Err((StatusCode::UNAUTHORIZED, "Invalid signature format"));
+            }
+        },
+        None => {
+            error!("Missing X-Hub-Signature-256 header");
+            return Err((StatusCode::UNAUTHORIZED, "Missing signature"));
+        }
+    };
+
+    // Parse mode first to determine which token to use for signature verification
+    let mode = match params.get(mode_key) {
+        Some(m) => m.as_str(),
+        None => {
+            error!("Missing '{}' parameter", mode_key);
+            return Err((StatusCode::BAD_REQUEST, "Missing mode parameter"));
+        }
+    };
+
+    // Get secret from environment based on mode
+    let secret = match mode {
+        "repo" => {
+            match env::var("GIT_PAT_TOKEN") {
+                Ok(s) => s,
+                Err(_) => {
+                    error!("No GIT_PAT_TOKEN found in environment for repo mode");
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "Server configuration error"));
+                }
+            }
+        }
+        "image" => {
+            match env::var("DOCKER_ACCESS_TOKEN") {
+                Ok(s) => s,
+                Err(_) => {
+                    error!("No DOCKER_ACCESS_TOKEN found in environment for image mode");
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "Server configuration error"));
+                }
+            }
+        }
+        _ => {
+            error!("Invalid mode for signature verification: {}", mode);
+            return Err((StatusCode::BAD_REQUEST, "Invalid mode"));
+        }
+    };
+
+    // Reconstruct query string for verification
+    let mut query_pairs: Vec<_> = params.iter().collect();
+    query_pairs.sort_by_key(|(k, _)| *k);
+    let query_string: String = query_pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    // Verify signature
+    if !verify_signature(query_string.as_bytes(), signature, &secret) {
+        error!("❌ Signature verification failed");
+        return Err((StatusCode::UNAUTHORIZED, "Invalid signature"));
+    }
+
+    info!("✅ Signature verified successfully");
+    Ok(mode.to_string())
+}
+
+// Adapts the progress channel to an NDJSON body; ends after the result line even if a queued
+// follow-up deploy still holds a sender
+struct NdjsonStream {
+    events: tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+    done: bool,
+}
+
+impl futures_core::Stream for NdjsonStream {
+    type Item = Result<String, std::convert::Infallible>;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        if self.done {
+            return std::task::Poll::Ready(None);
+        }
+        self.events.poll_recv(cx).map(|event| {
+            event.map(|event| {
+                self.done = event["phase"] == "result";
+                Ok(format!("{}\n", event))
+            })
+        })
+    }
+}
+
+// `Accept: application/x-ndjson` streams a line per deploy phase, then the usual response body
+// with "phase": "result" and its HTTP status as "http_status"; the response itself is always 200
+async fn handle_deploy(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    let streaming = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/x-ndjson"));
+    if !streaming {
+        return deploy_request(state, params, headers, body, None).await.into_response();
+    }
+
+    let (events, rx) = tokio::sync::mpsc::unbounded_channel();
+    let progress = events.clone();
+    tokio::spawn(async move {
+        let (code, Json(response)) = deploy_request(state, params, headers, body, Some(progress)).await;
+        let mut result = serde_json::json!(response);
+        result["phase"] = "result".into();
+        result["http_status"] = code.as_u16().into();
+        let _ = events.send(result);
+    });
+    (
+        [(axum::http::header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::from_stream(NdjsonStream { events: rx, done: false }),
+    )
+        .into_response()
+}
+
+async fn deploy_request(
+    state: Arc<AppState>,
+    params: HashMap<String, String>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+    progress: Option<Progress>,
+) -> (StatusCode, Json<DeployResponse>) {
+    info!("📥 Webhook request received");
+    let verbose = params.get("verbose").is_some_and(|v| v == "true");
+
+    // Priority 1: Check for query param + signature approach ("verbose" alone doesn't count)
+    if params.keys().any(|k| k != "verbose") {
+        info!("🔍 Query params detected, attempting signature verification");
+        
+        let mode = match check_query_signature(&params, &headers, "mode") {
+            Ok(mode) => mode,
+            Err((code, message)) => return reply(code, message),
+        };
+
+        // Parse remaining query params
+        let project_name = params.get("project");
+        let path = params.get("path");
+        let versions_to_keep = params.get("versionstokeep")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        // Determine deployment context
+        let config = state.config();
+        let mut job = if let Some(proj_name) = project_name {
+            // Mode 1: Project exists in config
+            match config.get(proj_name) {
+                Some(entry) => {
+                    info!("📁 Using project from config: {}", proj_name);
+                    let repository = params.get("repository").map(String::as_str);
+                    if let Err((code, message)) = check_repository(proj_name, entry, repository) {
+                        return reply(code, message);
+                    }
+                    let mut job = DeployJob::from_entry(proj_name, entry, &mode);
+                    job.source = request_source(&headers, "webhook");
+                    job
+                }
+                None => return unknown_project(&state, proj_name),
+            }
+        } else if let Some(custom_path) = path {
+            // Mode 2: External user with custom path
+            info!("📁 Using custom path: {}", custom_path);
+            // Use sanitized path as project_id for backups
+            let sanitized_id = custom_path.replace(['/', '\\', '.'], "_");
+            DeployJob {
+                project_id: sanitized_id,
+                config_key: None,
+                path: custom_path.clone(),
+                repo_path: custom_path.clone(),
+                mode,
+                rollback_limit: versions_to_keep,
+                compose: ComposeOptions {
+                    use_sudo: env_flag("USE_SUDO"),
+                    ..Default::default()
+                },
+                git: GitOptions::default(),
+                registries: BTreeMap::new(),
+                credentials: None,
+                git_credentials: None,
+                docker_credentials: None,
+                payload: None,
+                verbose: false,
+                source: request_source(&headers, "webhook"),
+                clean_env: None,
+                mask_patterns: Vec::new(),
+                progress: None,
+            }
+        } else {
+            error!("Either 'project' or 'path' parameter must be provided");
+            return reply(StatusCode::BAD_REQUEST, "Missing project or path parameter");
+        };
+
+        if let Err(msg) = validate_compose_options(&job.compose) {
+            return reply(StatusCode::BAD_REQUEST, msg);
+        }
+        job.verbose = verbose;
+        job.progress = progress;
+
+        // Execute deployment
+        return dispatch_idempotent(&state, &headers, job, None).await;
+    }
+
+    // Priority 2: Fall back to JSON payload approach
+    info!("📦 No query params, attempting JSON payload parsing");
+    let mut job = match payload_job(&state, &headers, &body) {
+        Ok(job) => job,
+        Err(rejected) => return *rejected,
+    };
+    job.verbose = verbose;
+    job.progress = progress;
+    dispatch_idempotent(&state, &headers, job, Some(&body)).await
+}
+
+// Same payload and auth as /webhook; reports what the deploy would do and runs nothing but a fetch
+async fn handle_plan(State(state): State<Arc<AppState>>, headers: HeaderMap, body: axum::body::Bytes) -> Response {
+    info!("📥 Plan request received");
+    let job = match payload_job(&state, &headers, &body) {
+        Ok(job) => job,
+        Err(rejected) => return rejected.into_response(),
+    };
+    Json(plan_job(&job, state.runner.clone()).await).into_response()
+}
+
+// Runs every check a /webhook deploy would (auth, payload, project, credentials, paths) and
+// reports readiness: 200 when ready, 422 with the failing checks otherwise. Runs no commands.
+async fn handle_test(State(state): State<Arc<AppState>>, headers: HeaderMap, body: axum::body::Bytes) -> Response {
+    info!("📥 Webhook test request received");
+    let job = match payload_job(&state, &headers, &body) {
+        Ok(job) => job,
+        Err(rejected) => return rejected.into_response(),
+    };
+    let checks = readiness_checks(&job);
+    let ready = checks.iter().all(|c| c.ok);
+    let code = if ready { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    let body = serde_json::json!({
+        "ready": ready,
+        "project": job.project_id,
+        "mode": job.mode,
+        "checks": checks,
+    });
+    (code, Json(body)).into_response()
+}
+
+// Authenticates and validates a JSON deploy payload into the job it asks for; shared by /webhook, /plan and /webhook/test
+fn payload_job(state: &AppState, headers: &HeaderMap, body: &[u8]) -> Result<DeployJob, Box<(StatusCode, Json<DeployResponse>)>> {
+    if let Err((code, message)) = check_webhook_auth(state, headers, body) {
+        return Err(Box::new(reply(code, message)));
+    }
+    if let Err((code, message)) = check_content_type(state, headers) {
+        return Err(Box::new(reply(code, &message)));
+    }
+
+    let mut payload: WebhookPayload = match parse_payload(body) {
+        Ok(p) => p,
+        Err((code, message)) => return Err(Box::new(reply(code, &message))),
+    };
+    if let Err(field) = payload.normalize_blank_fields(state.reject_blank_fields) {
+        warn!("🚫 Rejected payload: field '{}' is empty", field);
+        return Err(Box::new(reply(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Invalid payload field '{}': must not be empty", field),
+        )));
+    }
+
+    // Before anything logs the payload or builds a command from it
+    if let Some(field) = payload.oversized_field(state.field_limits) {
+        warn!("🚫 Rejected payload: field '{}' exceeds the configured length limit", field);
+        return Err(Box::new(reply(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Invalid payload field '{}': value too long", field),
+        )));
+    }
+
+    debug!("Payload received: {:?}", payload);
+    
+    // Lookup Project Path; an ad-hoc path stands in for a config entry with all defaults
+    let config = state.config();
+    let adhoc_entry;
+    let project_entry = match (&payload.path, &state.adhoc_path_prefixes) {
+        (Some(_), None) => {
+            warn!("🚫 Refused ad-hoc path for '{}': ALLOW_ADHOC_PATHS is not set", payload.project);
+            return Err(Box::new(reply(StatusCode::FORBIDDEN, "Ad-hoc paths are disabled")));
+        }
+        (Some(path), Some(prefixes)) => {
+            if !adhoc_path_allowed(path, prefixes) {
+                warn!("🚫 Refused ad-hoc path {}: not under an allowed prefix", path);
+                return Err(Box::new(reply(StatusCode::FORBIDDEN, "Path is not allowed")));
+            }
+            info!("📁 Using ad-hoc path: {}", path);
+            adhoc_entry = ProjectEntry::Path(path.clone());
+            &adhoc_entry
+        }
+        (None, _) => match find_project(&config, &payload.project, state.normalize_project_names) {
+            Some((key, entry)) => {
+                if key != payload.project {
+                    info!("Project '{}' resolved to config key '{}'", payload.project, key);
+                    payload.project = key.to_string();
+                }
+                entry
+            }
+            None => return Err(Box::new(unknown_project(state, &payload.project))),
+        },
+    };
+
+    if let Err((code, message)) = check_repository(&payload.project, project_entry, payload.repository.as_deref()) {
+        return Err(Box::new(reply(code, message)));
+    }
+
+    // Select Deployment Mode
+    if !DEPLOY_TYPES.contains(&payload.r#type.as_str()) {
+        warn!("Invalid deployment type received: {}", payload.r#type);
+        return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Invalid Type")));
+    }
+    if let Err((code, message)) = check_type_allowed(&payload.project, project_entry, &payload.r#type) {
+        return Err(Box::new(reply(code, message)));
+    }
+    // Repo and image deploys carry credentials; lifecycle actions don't, so the webhook itself must be authenticated
+    let lifecycle = matches!(payload.r#type.as_str(), "restart" | "wake" | "stop" | "down" | "teardown");
+    if lifecycle && state.signature_sources.is_empty() && state.basic_auth.is_none() {
+        warn!("🚫 Refusing {} of '{}': no webhook authentication is configured", payload.r#type, payload.project);
+        return Err(Box::new(reply(StatusCode::FORBIDDEN, "Lifecycle actions require webhook authentication")));
+    }
+
+    let mut job = DeployJob::from_entry(&payload.project, project_entry, &payload.r#type);
+    job.source = match payload.source.as_deref().map(str::trim) {
+        Some(source) if !source.is_empty() => source.to_string(),
+        _ => request_source(headers, "webhook"),
+    };
+    if let Some(path) = &payload.path {
+        // Like signed query deploys of a custom path: keyed by the sanitized path, no config entry
+        job.project_id = path.replace(['/', '\\', '.'], "_");
+        job.config_key = None;
+    }
+
+    // Payload profiles take precedence over the project's configured ones
+    if let Some(profiles) = &payload.compose_profiles {
+        job.compose.profiles = profiles.clone();
+    }
+    job.compose.scale = payload.scale.clone().unwrap_or_default();
+    job.compose.no_build = payload.build == Some(false);
+
+    for (name, &enabled) in payload.options.iter().flatten() {
+        if !COMPOSE_TOGGLES.contains(&name.as_str()) {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, &format!("Unknown option '{}'", name))));
+        }
+        if !project_entry.allowed_options().contains(name) {
+            warn!("🚫 Option '{}' requested for '{}' but not allowed by its config", name, payload.project);
+            return Err(Box::new(reply(StatusCode::FORBIDDEN, &format!("Option '{}' is not allowed for this project", name))));
+        }
+        job.compose.set_toggle(name, enabled);
+    }
+
+    if payload.volumes == Some(true) {
+        if payload.r#type != "down" {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "volumes is only valid for down")));
+        }
+        // Destroys data, so the sender has to say it means it
+        if !payload.confirms() {
+            warn!("🚫 Refusing down --volumes of '{}' without confirmation", payload.project);
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Removing volumes requires confirmation")));
+        }
+        job.compose.remove_volumes = true;
+    }
+
+    if let Err(msg) = validate_compose_options(&job.compose) {
+        return Err(Box::new(reply(StatusCode::BAD_REQUEST, msg)));
+    }
+
+    if let Some(tag) = &payload.tag {
+        if !is_valid_git_ref(tag) {
+            error!("Invalid tag name: {}", tag);
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Invalid tag")));
+        }
+        job.git.tag = Some(tag.clone());
+    }
+
+    if let Some(tag) = &payload.image_tag {
+        if payload.r#type != "image" {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "image_tag is only valid for image")));
+        }
+        if !is_valid_image_tag(tag) {
+            error!("Invalid image tag: {}", tag);
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Invalid image tag")));
+        }
+        job.compose.image_tag = Some(tag.clone());
+    }
+
+    if let Some(yaml) = &payload.compose_override {
+        if !matches!(payload.r#type.as_str(), "repo" | "image") {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "compose_override is only valid for repo and image")));
+        }
+        if yaml.trim().is_empty() || yaml.len() > MAX_COMPOSE_OVERRIDE_BYTES || yaml.contains('\0') {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Invalid compose override")));
+        }
+        job.compose.inline_override = Some(yaml.clone());
+    }
+
+    if payload.allow_non_fast_forward == Some(true) {
+        if !matches!(payload.r#type.as_str(), "repo" | "pull") {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "allow_non_fast_forward is only valid for repo and pull")));
+        }
+        if job.git.require_fast_forward {
+            warn!("⚠️ {} deploy of '{}' allows a non-fast-forward commit", payload.r#type, payload.project);
+        }
+        job.git.require_fast_forward = false;
+    }
+
+    if payload.r#type == "teardown" && payload.pr_number.is_none() {
+        return Err(Box::new(reply(StatusCode::BAD_REQUEST, "teardown requires pr_number")));
+    }
+    if let Some(pr) = payload.pr_number {
+        if !is_valid_pr_number(pr) {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "Invalid pr_number")));
+        }
+        if !matches!(payload.r#type.as_str(), "repo" | "teardown") {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "pr_number is only valid for repo and teardown")));
+        }
+        if payload.tag.is_some() {
+            return Err(Box::new(reply(StatusCode::BAD_REQUEST, "pr_number and tag are mutually exclusive")));
+        }
+        info!("🔀 {} of the preview of pull request #{} for {}", payload.r#type, pr, payload.project);
+        job = job.for_pull_request(pr);
+    }
+
+    job.payload = Some(payload);
+    Ok(job)
+}
+
+// Manual trigger with config defaults: POST /deploy/{project}?type=repo|image
+// Signed like /webhook query deploys, over the sorted query string including project=<name>
+async fn handle_manual_deploy(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    Query(mut params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DeployResponse>) {
+    info!("📥 Manual deploy request for {}", project);
+    let verbose = params.get("verbose").is_some_and(|v| v == "true");
+    params.insert("project".to_string(), project.clone());
+
+    let mode = match check_query_signature(&params, &headers, "type") {
+        Ok(mode) => mode,
+        Err((code, message)) => return reply(code, message),
+    };
+
+    let config = state.config();
+    let entry = match config.get(&project) {
+        Some(entry) => entry,
+        None => return unknown_project(&state, &project),
+    };
+
+    let mut job = DeployJob::from_entry(&project, entry, &mode);
+    if let Err(msg) = validate_compose_options(&job.compose) {
+        return reply(StatusCode::BAD_REQUEST, msg);
+    }
+    job.verbose = verbose;
+    job.source = request_source(&headers, "manual");
+    dispatch_idempotent(&state, &headers, job, None).await
+}
+
+// The parts of a Bitbucket Cloud `repo:push` event we need
+#[derive(Deserialize, Debug)]
+struct BitbucketPush {
+    repository: EventRepository,
+    push: BitbucketChanges,
+}
+
+// Bitbucket and GitHub events describe the repository the same way
+#[derive(Deserialize, Debug)]
+struct EventRepository {
+    full_name: String,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BitbucketChanges {
+    changes: Vec<BitbucketChange>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BitbucketChange {
+    // Null when the branch was deleted
+    new: Option<BitbucketRef>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BitbucketRef {
+    r#type: String,
+    name: String,
+}
+
+// Prefer a project whose expected_repository matches, else one named after the repository
+fn find_event_project<'a>(config: &'a ConfigFile, repo: &EventRepository) -> Option<(&'a str, &'a ProjectEntry)> {
+    config
+        .iter()
+        .find(|(_, entry)| {
+            entry
+                .expected_repository()
+                .is_some_and(|expected| repository_matches(expected, Some(&repo.full_name)))
+        })
+        .or_else(|| config.get_key_value(&repo.name))
+        .map(|(name, entry)| (name.as_str(), entry))
+}
+
+// Bitbucket Cloud push webhook; deploys in repo mode with credentials from the environment
+async fn handle_bitbucket(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> (StatusCode, Json<DeployResponse>) {
+    info!("📥 Bitbucket webhook received");
+
+    // Bitbucket signs the raw body with the webhook secret, sent as X-Hub-Signature
+    let mut sources = state.signature_sources.clone();
+    if let Ok(secret) = env::var("BITBUCKET_WEBHOOK_SECRET") {
+        sources.push(SignatureSource {
+            header: "x-hub-signature".to_string(),
+            algorithm: SignatureAlgorithm::HmacSha256,
+            secret,
+        });
+    }
+    if let Err((code, message)) = check_sources(&sources, &headers, &body) {
+        return reply(code, message);
+    }
+
+    if let Err((code, message)) = check_content_type(&state, &headers) {
+        return reply(code, &message);
+    }
+    let event: BitbucketPush = match parse_payload(&body) {
+        Ok(e) => e,
+        Err((code, message)) => return reply(code, &message),
+    };
+
+    let config = state.config();
+    let (name, entry) = match find_event_project(&config, &event.repository) {
+        Some(found) => found,
+        None => return unknown_project(&state, &event.repository.full_name),
+    };
+
+    // Only deploy pushes to a branch this project actually pulls
+    let git = entry.git_options();
+    let deployed = |branch: &str| {
+        if git.steps.is_empty() {
+            branch == "main"
+        } else {
+            git.steps.iter().any(|step| step.branch == branch)
+        }
+    };
+    let pushed: Vec<&str> = event
+        .push
+        .changes
+        .iter()
+        .filter_map(|change| change.new.as_ref())
+        .filter(|r| r.r#type == "branch")
+        .map(|r| r.name.as_str())
+        .collect();
+    if !pushed.iter().any(|branch| deployed(branch)) {
+        info!("Ignoring Bitbucket push to {:?} for {}", pushed, name);
+        return skipped(&state, "BRANCH_FILTERED", "Skipped: push is not to a deployed branch");
+    }
+
+    info!("📁 Bitbucket push for {} maps to project {}", event.repository.full_name, name);
+    let mut job = DeployJob::from_entry(name, entry, "repo");
+    job.source = request_source(&headers, "bitbucket");
+    dispatch_deploy(&state, job).await
+}
+
+// The parts of a GitHub `pull_request` event we need
+#[derive(Deserialize, Debug)]
+struct GithubPullRequest {
+    action: String,
+    number: u32,
+    repository: EventRepository,
+}
+
+// Sent when a hook is created; organization hooks have no repository
+#[derive(Deserialize, Debug)]
+struct GithubPing {
+    repository: Option<EventRepository>,
+}
+
+// Answers GitHub's ping with the readiness of the project the repository maps to, so a newly
+// configured hook shows whether deploys would work
+fn github_ping(state: &AppState, headers: &HeaderMap, body: &[u8]) -> (StatusCode, Json<DeployResponse>) {
+    if let Err((code, message)) = check_content_type(state, headers) {
+        return reply(code, &message);
+    }
+    let ping: GithubPing = match parse_payload(body) {
+        Ok(p) => p,
+        Err((code, message)) => return reply(code, &message),
+    };
+    let Some(repository) = ping.repository else {
+        return reply(StatusCode::OK, "Pong");
+    };
+    let config = state.config();
+    let (name, entry) = match find_event_project(&config, &repository) {
+        Some(found) => found,
+        None => return unknown_project(state, &repository.full_name),
+    };
+    let failed: Vec<String> = readiness_checks(&DeployJob::from_entry(name, entry, "repo"))
+        .into_iter()
+        .filter(|c| !c.ok)
+        .map(|c| format!("{}: {}", c.check, c.detail))
+        .collect();
+    if failed.is_empty() {
+        info!("🏓 GitHub ping for {}: ready", name);
+        reply(StatusCode::OK, &format!("Pong: {} is ready", name))
+    } else {
+        warn!("🏓 GitHub ping for {}: not ready ({})", name, failed.join("; "));
+        reply(StatusCode::UNPROCESSABLE_ENTITY, &format!("Pong: {} is not ready: {}", name, failed.join("; ")))
+    }
+}
+
+// GitHub pull_request webhook: a closed PR tears down its preview (see DeployJob::for_pull_request)
+async fn handle_github(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> (StatusCode, Json<DeployResponse>) {
+    info!("📥 GitHub webhook received");
+
+    // GitHub signs the raw body with the webhook secret, sent as X-Hub-Signature-256
+    let mut sources = state.signature_sources.clone();
+    if let Ok(secret) = env::var("GITHUB_WEBHOOK_SECRET") {
+        sources.push(SignatureSource {
+            header: "x-hub-signature-256".to_string(),
+            algorithm: SignatureAlgorithm::HmacSha256,
+            secret,
+        });
+    }
+    // Teardowns destroy volumes, so unlike Bitbucket pushes an unsigned event is never accepted
+    if sources.is_empty() {
+        warn!("🚫 Refusing GitHub webhook: neither GITHUB_WEBHOOK_SECRET nor WEBHOOK_SIGNATURES is set");
+        return reply(StatusCode::FORBIDDEN, "GitHub webhooks require a signature secret");
+    }
+    if let Err((code, message)) = check_sources(&sources, &headers, &body) {
+        return reply(code, message);
+    }
+
+    let event_type = headers.get("x-github-event").and_then(|v| v.to_str().ok()).unwrap_or("");
+    if event_type == "ping" {
+        return github_ping(&state, &headers, &body);
+    }
+    if event_type != "pull_request" {
+        info!("Ignoring GitHub {} event", event_type);
+        return skipped(&state, "EVENT_IGNORED", "Skipped: not a pull_request event");
+    }
+    if let Err((code, message)) = check_content_type(&state, &headers) {
+        return reply(code, &message);
+    }
+    let event: GithubPullRequest = match parse_payload(&body) {
+        Ok(e) => e,
+        Err((code, message)) => return reply(code, &message),
+    };
+    if event.action != "closed" {
+        info!("Ignoring GitHub pull_request {} for #{}", event.action, event.number);
+        return skipped(&state, "PULL_REQUEST_OPEN", "Skipped: pull request is not closed");
+    }
+
+    let config = state.config();
+    let (name, entry) = match find_event_project(&config, &event.repository) {
+        Some(found) => found,
+        None => return unknown_project(&state, &event.repository.full_name),
+    };
+
+    info!("📁 PR #{} of {} closed; tearing down its preview of {}", event.number, event.repository.full_name, name);
+    let mut job = DeployJob::from_entry(name, entry, "teardown").for_pull_request(event.number);
+    job.source = request_source(&headers, "github");
+    dispatch_deploy(&state, job).await
+}
+
+// Seconds left on a tripped circuit breaker, if deploys of this project are paused
+fn paused_for(state: &AppState, project_id: &str) -> Option<u64> {
+    let runtime = state.runtime.lock().unwrap();
+    let until = runtime.get(project_id)?.paused_until?;
+    until.checked_sub(unix_now()).filter(|&left| left > 0)
+}
+
+// Drops an Idempotency-Key's in-flight marker if the request is abandoned before it finishes
+struct PendingKey<'a> {
+    cache: &'a Mutex<HashMap<(String, String), Idempotent>>,
+    slot: Option<(String, String)>,
+}
+
+impl Drop for PendingKey<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.cache.lock().unwrap().remove(&slot);
+        }
+    }
+}
+
+// Rejections of a deploy that never started: outside its windows, circuit breaker open, server
+// paused, or gave up waiting for its lock. Kept out of the idempotency cache, since each is gone
+// by the time a retry comes
+const NOT_STARTED_CODES: [&str; 4] = ["OUTSIDE_DEPLOY_WINDOW", "CIRCUIT_OPEN", "DEPLOYS_PAUSED", "PROJECT_BUSY"];
+
+// With an Idempotency-Key header, a retry for the same project within IDEMPOTENCY_TTL_SECS gets
+// the first request's response back instead of deploying again; checked after authentication.
+// `body` is the JSON payload, deduplicated by hash within DEDUPE_WINDOW_SECS when it has no key.
+async fn dispatch_idempotent(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    job: DeployJob,
+    body: Option<&[u8]>,
+) -> (StatusCode, Json<DeployResponse>) {
+    let header_key = headers
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|k| !k.is_empty());
+    let (key, ttl) = match (header_key, body) {
+        (Some(key), _) if key.len() > state.field_limits.max_len => {
+            return reply(StatusCode::BAD_REQUEST, "Idempotency-Key is too long");
+        }
+        (Some(key), _) => (key.to_string(), state.idempotency_ttl),
+        (None, Some(body)) if !state.dedupe_window.is_zero() => {
+            use sha2::{Digest, Sha256};
+            (format!("sha256:{}", hex::encode(Sha256::digest(body))), state.dedupe_window)
+        }
+        _ => return dispatch_deploy(state, job).await,
+    };
+    let by_hash = header_key.is_none();
+
+    let slot = (job.project_id.clone(), key.clone());
+    {
+        let mut cache = state.idempotency.lock().unwrap();
+        cache.retain(|_, entry| match entry {
+            Idempotent::InFlight => true,
+            Idempotent::Done { at, ttl, .. } => at.elapsed() < *ttl,
+        });
+        match cache.get(&slot) {
+            Some(Idempotent::Done { code, body, .. }) => {
+                if by_hash {
+                    info!("♻️ Replaying the response for a repeated payload of {}", job.project_id);
+                } else {
+                    info!("♻️ Replaying the response for {}'s Idempotency-Key {}", job.project_id, key);
+                }
+                return (*code, Json(body.as_ref().clone()));
+            }
+            Some(Idempotent::InFlight) if by_hash => {
+                warn!("Rejecting a repeated payload of {} while the first still runs", job.project_id);
+                return reply(StatusCode::CONFLICT, "An identical payload is still being deployed");
+            }
+            Some(Idempotent::InFlight) => {
+                warn!("Rejecting a repeat of {}'s Idempotency-Key {} while it still runs", job.project_id, key);
+                return reply(StatusCode::CONFLICT, "A deploy with this Idempotency-Key is still running");
+            }
+            None => {
+                cache.insert(slot.clone(), Idempotent::InFlight);
+            }
+        }
+    }
+
+    let mut pending = PendingKey {
+        cache: &state.idempotency,
+        slot: Some(slot),
+    };
+    let (code, Json(body)) = dispatch_deploy(state, job).await;
+    // Nothing ran for these (see NOT_STARTED_CODES), so a retry should try again
+    let not_started = body.code.is_some_and(|c| NOT_STARTED_CODES.contains(&c));
+    if code != StatusCode::SERVICE_UNAVAILABLE && !not_started {
+        if let Some(slot) = pending.slot.take() {
+            let done = Idempotent::Done {
+                at: Instant::now(),
+                ttl,
+                code,
+                body: Box::new(body.clone()),
+            };
+            state.idempotency.lock().unwrap().insert(slot, done);
+        }
+    }
+    (code, Json(body))
+}
+
+// Runs a deploy directly, or coalesces it behind the running one for projects with `coalesce`
+async fn dispatch_deploy(state: &Arc<AppState>, job: DeployJob) -> (StatusCode, Json<DeployResponse>) {
+    // Covers the signed-query, manual and git-host paths; JSON payloads were checked in payload_job.
+    // Rollbacks come from the API, not a webhook, so allowed_types doesn't apply to them.
+    if job.mode != "rollback" {
+        let config = state.config();
+        if let Some(entry) = job.config_key.as_ref().and_then(|key| config.get(key)) {
+            if let Err((code, message)) = check_type_allowed(&job.project_id, entry, &job.mode) {
+                return reply(code, message);
+            }
+            if let Err((code, message)) = check_confirmed(&job.project_id, entry, &job.mode, job.payload.as_ref()) {
+                return reply(code, message);
+            }
+        }
+    }
+
+    // Rollbacks and redeploys of the last good commit undo a bad deploy, so windows don't hold them back
+    if job.mode != "rollback" && job.git.commit.is_none() {
+        let windows = job.config_key.as_ref().and_then(|key| state.config().get(key).and_then(|entry| entry.deploy_windows()));
+        if let Some(windows) = windows {
+            let now = unix_now();
+            if !windows.allows(now) {
+                if job.payload.as_ref().and_then(|p| p.override_window) == Some(true) {
+                    warn!("⚠️ Deploying {} outside its deploy windows (override_window)", job.project_id);
+                } else {
+                    let next = match windows.next_open(now) {
+                        Some(at) => format!("; next window opens {}", windows.describe(at)),
+                        None => String::new(),
+                    };
+                    warn!("🛑 Rejecting deploy of {}: outside its deploy windows", job.project_id);
+                    let (code, Json(mut body)) =
+                        reply(StatusCode::FORBIDDEN, &format!("Outside the project's deploy windows{}", next));
+                    body.code = Some("OUTSIDE_DEPLOY_WINDOW");
+                    return (code, Json(body));
+                }
+            }
+        }
+    }
+
+    if let Some(pause) = state.pause.lock().unwrap().clone() {
+        warn!("🛑 Rejecting deploy of {}: server paused ({})", job.project_id, pause.reason);
+        let (code, Json(mut body)) = reply(StatusCode::SERVICE_UNAVAILABLE, &format!("paused: {}", pause.reason));
+        body.code = Some("DEPLOYS_PAUSED");
+        return (code, Json(body));
+    }
+
+    if let Some(left) = paused_for(state, &job.project_id) {
+        warn!("🛑 Rejecting deploy of {}: paused after repeated failures ({}s left)", job.project_id, left);
+        let (code, Json(mut body)) = reply(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &format!("Deploys paused after repeated failures; retry in {}s", left),
+        );
+        body.code = Some("CIRCUIT_OPEN");
+        return (code, Json(body));
+    }
+
+    let coalesce = job
+        .config_key
+        .as_ref()
+        .is_some_and(|key| state.config().get(key).is_some_and(|entry| entry.coalesce()));
+
+    if !coalesce {
+        let (result, commands) = execute_deploy(state, &job).await;
+        return deploy_reply(&result, &job, commands);
+    }
+
+    {
+        let mut runtime = state.runtime.lock().unwrap();
+        let slot = runtime.entry(job.project_id.clone()).or_default();
+        if slot.running {
+            let project_id = job.project_id.clone();
+            let replaced = slot.pending.replace(job).is_some();
+            info!(
+                "⏳ Deploy already running for {}; {} pending follow-up",
+                project_id,
+                if replaced { "replaced" } else { "queued" }
+            );
+            return reply(
+                StatusCode::ACCEPTED,
+                "Deploy already running; this request is queued as the pending follow-up",
+            );
+        }
+        slot.running = true;
+    }
+
+    let (result, commands) = execute_deploy(state, &job).await;
+    tokio::spawn(drain_pending(state.clone(), job.project_id.clone()));
+    deploy_reply(&result, &job, commands)
+}
+
+// Runs the latest pending deploy (if any) once the current one finishes, then releases the slot
+async fn drain_pending(state: Arc<AppState>, project_id: String) {
+    loop {
+        let next = {
+            let mut runtime = state.runtime.lock().unwrap();
+            let slot = runtime.entry(project_id.clone()).or_default();
+            match slot.pending.take() {
+                Some(job) => job,
+                None => {
+                    slot.running = false;
+                    return;
+                }
+            }
+        };
+
+        if paused_for(&state, &project_id).is_some() || state.pause.lock().unwrap().is_some() {
+            warn!("🛑 Dropping coalesced follow-up for {}: deploys are paused", project_id);
+            continue;
+        }
+        info!("▶️ Running coalesced follow-up deploy for {}", project_id);
+        match execute_deploy(&state, &next).await.0 {
+            Ok(report) => info!("Coalesced deploy for {} finished: {}", project_id, report.message),
+            Err(e) => warn!("Coalesced deploy for {} failed: {}", project_id, e),
+        }
+    }
+}
+
+// Polls `docker info` after boot until the daemon answers, then lets waiting deploys through.
+// When `wait` runs out they go ahead anyway and fail (or not) on their own.
+async fn wait_for_docker(ready: Arc<tokio::sync::watch::Sender<bool>>, wait: Duration) {
+    let deadline = Instant::now() + wait;
+    info!("⏳ Holding deploys for up to {}s until docker responds", wait.as_secs());
+    loop {
+        let answered = Command::new("docker")
+            .args(["info", "--format", "{{.ServerVersion}}"])
+            .output()
+            .await
+            .is_ok_and(|out| out.status.success());
+        if answered {
+            info!("✅ Docker is responding; deploys can run");
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!("⚠️ Docker still not responding after {}s; letting deploys run anyway", wait.as_secs());
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(2).min(deadline.saturating_duration_since(Instant::now()))).await;
+    }
+    ready.send_replace(true);
+}
+
+// Returns the outcome along with the (redacted) commands that were run
+async fn execute_deploy(state: &AppState, job: &DeployJob) -> (DeployOutcome, Vec<String>) {
+    let mut docker_ready = state.docker_ready.subscribe();
+    if !*docker_ready.borrow() {
+        info!("⏳ {} waits for docker to come up", job.project_id);
+        let _ = docker_ready.wait_for(|ready| *ready).await;
+    }
+    let permits = {
+        let limit = job
+            .config_key
+            .as_ref()
+            .and_then(|key| state.config().get(key).map(|entry| entry.max_concurrency()))
+            .unwrap_or(1);
+        let mut runtime = state.runtime.lock().unwrap();
+        let slot = runtime.entry(job.project_id.clone()).or_default();
+        slot.permits
+            .get_or_insert_with(|| Arc::new(Semaphore::new(limit as usize)))
+            .clone()
+    };
+    if permits.available_permits() == 0 {
+        info!("⏳ {} is at its concurrency limit; waiting for a running deploy to finish", job.project_id);
+    }
+    // Held through cleanup and backups so those don't overlap either
+    let _permit = match wait_for_lock(state, permits.acquire_owned()).await {
+        Ok(permit) => permit.expect("deploy semaphore is never closed"),
+        Err(e) => {
+            warn!("🛑 Giving up on {}: {}", job.project_id, e);
+            return (Err(e), Vec::new());
+        }
+    };
+    // Then the project's lock_group, if any, so group members deploy one at a time
+    let group = job
+        .config_key
+        .as_ref()
+        .and_then(|key| state.config().get(key).and_then(|entry| entry.lock_group().map(str::to_string)));
+    let _group_guard = match group {
+        Some(group) => {
+            let lock = state.group_locks.lock().unwrap().entry(group.clone()).or_default().clone();
+            let guard = match lock.clone().try_lock_owned() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    info!("⏳ {} waits for another deploy in lock group {}", job.project_id, group);
+                    match wait_for_lock(state, lock.lock_owned()).await {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            warn!("🛑 Giving up on {}: {} (lock group {})", job.project_id, e, group);
+                            return (Err(e), Vec::new());
+                        }
+                    }
+                }
+            };
+            Some(guard)
+        }
+        None => None,
+    };
+    // Taken after the project permit so a queued same-project deploy doesn't hold a worker slot
+    if state.workers.busy() == state.workers.size() {
+        info!("⏳ All {} deploy workers are busy; {} waits for a free one", state.workers.size(), job.project_id);
+    }
+    let _worker = state.workers.acquire(&job.project_id).await;
+
+    let config = state.config();
+    let entry = job.config_key.as_ref().and_then(|key| config.get(key));
+    if entry.is_some_and(|e| e.notify_on_start()) {
+        notify_deploy_started(state, job).await;
+    }
+
+    publish_event(state, job, None);
+
+    state.runtime.lock().unwrap().entry(job.project_id.clone()).or_default().in_flight += 1;
+    let exec = Arc::new(
+        Exec::new(state.runner.clone())
+            .with_validation_cache(&job.project_id, state.compose_validations.clone())
+            .with_progress(job.progress.clone()),
+    );
+    let timeout = entry.and_then(|e| e.deploy_timeout()).unwrap_or(state.deploy_timeout);
+    // Parent of the git_pull / compose_build / compose_up phase spans
+    let span = info_span!("deploy", project = %job.project_id, mode = %job.mode, source = %job.source);
+    let started = Instant::now();
+    let result = run_job(job, state.metrics.clone(), exec.clone(), timeout).instrument(span).await;
+    if let Some(statsd) = &state.statsd {
+        statsd.deploy(&job.project_id, &job.mode, result.is_ok(), started.elapsed());
+    }
+    if let Some(slot) = state.runtime.lock().unwrap().get_mut(&job.project_id) {
+        slot.in_flight = slot.in_flight.saturating_sub(1);
+    }
+
+    // Post-deploy cleanup and backup; pull deploys leave docker alone, login deploys everything
+    if result.is_ok() && job.rollback_limit > 0 && job.mode != "login" {
+        create_backup(&job.project_id, &job.path, job.compose.use_sudo).await;
+        prune_backups(&job.project_id, job.rollback_limit).await;
+    }
+    if result.is_ok() && !matches!(job.mode.as_str(), "pull" | "login") {
+        debug!("Deployment success, performing safe image cleanup");
+        let _ = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} image prune -f", docker_bin(job.compose.use_sudo)))
+            .status()
+            .await;
+    }
+
+    let breaker = entry.and_then(|e| e.circuit_breaker());
+    {
+        let mut runtime = state.runtime.lock().unwrap();
+        let slot = runtime.entry(job.project_id.clone()).or_default();
+        match &result {
+            Ok(_) => {
+                slot.consecutive_failures = 0;
+                slot.paused_until = None;
+            }
+            Err(e) => {
+                slot.last_error = Some(LastError {
+                    message: e.to_string(),
+                    cause: e.cause().map(str::to_string),
+                    stderr_tail: e.stderr_tail().map(str::to_string),
+                    occurred_at: unix_now(),
+                });
+                slot.consecutive_failures += 1;
+                if let Some((threshold, cooldown)) = breaker {
+                    if slot.consecutive_failures >= threshold {
+                        warn!(
+                            "🛑 {} failed {} times in a row; pausing deploys for {}s",
+                            job.project_id, slot.consecutive_failures, cooldown
+                        );
+                        slot.paused_until = Some(unix_now() + cooldown);
+                    }
+                }
+            }
+        }
+    }
+
+    let summary = match &result {
+        Ok(report) => report.message.to_string(),
+        Err(e) => e.to_string(),
+    };
+    {
+        let mut runtime = state.runtime.lock().unwrap();
+        let slot = runtime.entry(job.project_id.clone()).or_default();
+        slot.last_deploy = Some(LastDeploy {
+            status: if result.is_ok() { "success" } else { "error" },
+            message: summary.clone(),
+            commit: result.as_ref().ok().and_then(|report| report.commit.clone()),
+            finished_at: unix_now(),
+        });
+        slot.last_log = Some(exec.transcript());
+    }
+    state.history.record(
+        &job.project_id,
+        HistoryEntry {
+            status: if result.is_ok() { "success" } else { "error" }.to_string(),
+            mode: job.mode.clone(),
+            message: summary.clone(),
+            commit: result.as_ref().ok().and_then(|report| report.commit.clone()),
+            sha: exec.env_var("GRAFT_COMMIT"),
+            source: Some(job.source.clone()),
+            finished_at: unix_now(),
+        },
+    );
+    if state.write_status_file {
+        write_status_file(job, &result);
+    }
+    let event = serde_json::json!({
+        "at": unix_now(),
+        "project": job.project_id,
+        "mode": job.mode,
+        "user": job.payload.as_ref().and_then(|p| p.user.as_deref()),
+        "source": job.source,
+        "status": if result.is_ok() { "success" } else { "error" },
+        "message": summary,
+    });
+    if let Some(audit) = &state.audit {
+        audit.record(&event);
+    }
+    state.record_recent_deploy(event);
+    notify_deploy(state, job, result.is_ok(), &summary).await;
+    publish_event(state, job, Some(&result));
+
+    (result, exec.commands())
+}
+
+const STATUS_FILE: &str = ".graft-status.json";
+
+// For sidecars that read files rather than the API. Written to a unique temp file and renamed
+// over the old one, so readers never see a partial file even with concurrent deploys.
+fn write_status_file(job: &DeployJob, result: &DeployOutcome) {
+    static WRITES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let dir = std::path::Path::new(&job.path);
+    if !dir.is_dir() {
+        return;
+    }
+    let body = serde_json::json!({
+        "project": job.project_id,
+        "mode": job.mode,
+        "status": if result.is_ok() { "success" } else { "error" },
+        "message": match result {
+            Ok(report) => report.message.to_string(),
+            Err(e) => e.to_string(),
+        },
+        "code": result.as_ref().err().map(|e| e.code()),
+        "commit": result.as_ref().ok().and_then(|report| report.commit.clone()),
+        "finished_at": unix_now(),
+    });
+    let n = WRITES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let temp = dir.join(format!("{}.{}.{}.tmp", STATUS_FILE, std::process::id(), n));
+    let written = serde_json::to_vec_pretty(&body)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| std::fs::write(&temp, bytes))
+        .and_then(|()| std::fs::rename(&temp, dir.join(STATUS_FILE)));
+    if let Err(e) = written {
+        warn!("⚠️ Failed to write {} in {}: {}", STATUS_FILE, job.path, e);
+        let _ = std::fs::remove_file(&temp);
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Webhook bodies are JSON; anything else (including no Content-Type) is a 415 saying so
+fn check_content_type(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    if state.lenient_content_type {
+        return Ok(());
+    }
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let media = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if media == "application/json" || (media.starts_with("application/") && media.ends_with("+json")) {
+        return Ok(());
+    }
+    let got = if content_type.is_empty() { "none".to_string() } else { format!("'{}'", content_type) };
+    warn!("🚫 Rejected body with Content-Type {}", got);
+    Err((
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        format!("Content-Type must be application/json (got {})", got),
+    ))
+}
+
+// Malformed JSON is a 400; well-formed JSON with a missing or mistyped field is a 422 naming the field
+fn parse_payload<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, (StatusCode, String)> {
+    let mut de = serde_json::Deserializer::from_slice(body);
+    match serde_path_to_error::deserialize(&mut de) {
+        Ok(payload) => Ok(payload),
+        Err(e) => {
+            let path = e.path().to_string();
+            let inner = e.into_inner();
+            error!("Failed to parse JSON payload at '{}': {}", path, inner);
+
+            if inner.is_syntax() || inner.is_eof() || inner.is_io() {
+                return Err((StatusCode::BAD_REQUEST, "Invalid JSON payload".to_string()));
+            }
+
+            // serde_json appends " at line X column Y", which is noise for a field-level error
+            let reason = inner.to_string();
+            let reason = reason.split(" at line ").next().unwrap_or(&reason).to_string();
+            let message = if path == "." {
+                format!("Invalid payload: {}", reason)
+            } else {
+                format!("Invalid payload field '{}': {}", path, reason)
+            };
+            Err((StatusCode::UNPROCESSABLE_ENTITY, message))
+        }
+    }
+}
+
+async fn handle_status(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    // last_error carries command output (hosts, registries, paths), so this is keyed like /logs
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    let (repo_path, labels, tags) = match state.config().get(&project) {
+        Some(entry) => (entry.repo_path().to_string(), entry.labels().clone(), entry.tags().to_vec()),
+        None => return json_error(StatusCode::NOT_FOUND, "Project not found in config"),
+    };
+    let commit = current_commit(&repo_path).await;
+
+    let runtime = state.runtime.lock().unwrap();
+    let slot = runtime.get(&project);
+    let body = serde_json::json!({
+        "project": project,
+        "labels": labels,
+        "tags": tags,
+        "commit": commit,
+        "running": slot.is_some_and(|s| s.in_flight > 0),
+        "pending": slot.is_some_and(|s| s.pending.is_some()),
+        "last_deploy": slot.and_then(|s| s.last_deploy.clone()),
+        "last_good_commit": state.history.last_good(&project),
+        "last_error": slot.and_then(|s| s.last_error.clone()),
+        "consecutive_failures": slot.map_or(0, |s| s.consecutive_failures),
+        "paused_until": slot.and_then(|s| s.paused_until).filter(|&until| until > unix_now()),
+    });
+    (StatusCode::OK, Json(body))
+}
+
+// The project's recent deploys, newest first
+async fn handle_history(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    if !state.config().contains_key(&project) {
+        return json_error(StatusCode::NOT_FOUND, "Project not found in config");
+    }
+    let body = serde_json::json!({ "project": project, "history": state.history.entries(&project) });
+    (StatusCode::OK, Json(body))
+}
+
+// Plain-text output of every command the project's latest deploy ran
+async fn handle_logs(State(state): State<Arc<AppState>>, Path(project): Path<String>, headers: HeaderMap) -> Response {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message).into_response();
+    }
+    if !state.config().contains_key(&project) {
+        return json_error(StatusCode::NOT_FOUND, "Project not found in config").into_response();
+    }
+    match state.runtime.lock().unwrap().get(&project).and_then(|slot| slot.last_log.clone()) {
+        Some(log) => ([(axum::http::header::CONTENT_TYPE, "text/plain; charset=utf-8")], log).into_response(),
+        None => json_error(StatusCode::NOT_FOUND, "No deploy logged for this project yet").into_response(),
+    }
+}
+
+// Clears the circuit breaker so a fixed project can deploy without waiting out the cooldown
+async fn handle_reset(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    if !state.config().contains_key(&project) {
+        return json_error(StatusCode::NOT_FOUND, "Project not found in config");
+    }
+
+    let mut runtime = state.runtime.lock().unwrap();
+    let slot = runtime.entry(project.clone()).or_default();
+    let cleared = slot.consecutive_failures;
+    slot.consecutive_failures = 0;
+    slot.paused_until = None;
+    info!("🔓 Circuit breaker for {} reset (was at {} failure(s))", project, cleared);
+
+    let body = serde_json::json!({
+        "project": project,
+        "consecutive_failures": 0,
+        "paused_until": null,
+        "running": slot.in_flight > 0,
+        "pending": slot.pending.is_some(),
+    });
+    (StatusCode::OK, Json(body))
+}
+
+// Switches an atomic_worktree project back `steps` (default 1) retained releases
+async fn handle_rollback(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DeployResponse>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return reply(code, message);
+    }
+    let steps = match params.get("steps").map(|s| s.parse::<u32>()) {
+        None => 1,
+        Some(Ok(steps)) if steps > 0 => steps,
+        _ => return reply(StatusCode::BAD_REQUEST, "steps must be a positive integer"),
+    };
+
+    let config = state.config();
+    let Some(entry) = config.get(&project) else {
+        return unknown_project(&state, &project);
+    };
+    if !entry.git_options().atomic_worktree {
+        return reply(StatusCode::BAD_REQUEST, "Rollback needs a project with atomic_worktree");
+    }
+
+    info!("📥 Rollback request for {}: {} release(s) back", project, steps);
+    let mut job = DeployJob::from_entry(&project, entry, "rollback");
+    job.git.rollback_steps = steps;
+    job.source = request_source(&headers, "api");
+    dispatch_deploy(&state, job).await
+}
+
+// Disaster recovery: rebuilds the commit of the project's last successful deploy in its history
+// instead of whatever its branch points at now
+async fn handle_redeploy_last_good(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DeployResponse>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return reply(code, message);
+    }
+    let config = state.config();
+    let Some(entry) = config.get(&project) else {
+        return unknown_project(&state, &project);
+    };
+    let Some(commit) = state.history.last_good(&project) else {
+        return reply(StatusCode::CONFLICT, "No successful deploy recorded for this project yet");
+    };
+
+    info!("📥 Redeploying {} at its last known-good commit {}", project, commit);
+    let mut job = DeployJob::from_entry(&project, entry, "repo");
+    job.git.commit = Some(commit);
+    job.source = request_source(&headers, "api");
+    dispatch_deploy(&state, job).await
+}
+
+// No-op unless API_KEY is set; then "Authorization: Bearer <key>" is required
+fn check_api_key(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let expected = match &state.api_key {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    match bearer_token(headers) {
+        Some(key) if constant_time_eq(key.as_bytes(), expected.as_bytes()) => Ok(()),
+        Some(_) => {
+            warn!("🚫 Rejected request with a wrong API key");
+            Err((StatusCode::UNAUTHORIZED, "Invalid API key"))
+        }
+        None => Err((StatusCode::UNAUTHORIZED, "Missing API key")),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// JSON webhooks are open unless signature sources or basic auth are configured; then any one
+// valid method passes: a signature, basic credentials, or the API_KEY bearer token
+fn check_webhook_auth(state: &AppState, headers: &HeaderMap, body: &[u8]) -> Result<(), (StatusCode, &'static str)> {
+    if state.signature_sources.is_empty() && state.basic_auth.is_none() {
+        return Ok(());
+    }
+
+    if state.basic_auth.as_ref().is_some_and(|auth| auth.matches(headers)) {
+        info!("✅ Authenticated via basic auth");
+        return Ok(());
+    }
+    if let (Some(expected), Some(key)) = (&state.api_key, bearer_token(headers)) {
+        if constant_time_eq(key.as_bytes(), expected.as_bytes()) {
+            info!("✅ Authenticated via API key");
+            return Ok(());
+        }
+    }
+    if state.signature_sources.is_empty() {
+        warn!("🚫 Webhook request without valid credentials");
+        return Err((StatusCode::UNAUTHORIZED, "Invalid credentials"));
+    }
+    check_sources(&state.signature_sources, headers, body)
+}
+
+// Configured deploy targets with their non-sensitive settings, sorted by name
+// ?tag=prod lists only the projects tagged prod
+async fn handle_projects(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+
+    let config = state.config();
+    let mut names: Vec<&String> = config
+        .keys()
+        .filter(|name| params.get("tag").is_none_or(|tag| config[*name].tags().contains(tag)))
+        .collect();
+    names.sort();
+    let projects: Vec<serde_json::Value> = names
+        .into_iter()
+        .map(|name| {
+            let entry = &config[name];
+            let git = entry.git_options();
+            let branches: Vec<&str> = if git.steps.is_empty() {
+                vec!["main"]
+            } else {
+                git.steps.iter().map(|s| s.branch.as_str()).collect()
+            };
+            serde_json::json!({
+                "name": name,
+                "path": entry.path(),
+                "repo_path": entry.repo_path(),
+                "compose_path": entry.compose_path(),
+                "branches": branches,
+                "compose_profiles": entry.compose_profiles(),
+                "labels": entry.labels(),
+                "tags": entry.tags(),
+            })
+        })
+        .collect();
+
+    (StatusCode::OK, Json(serde_json::json!({ "projects": projects })))
+}
+
+// What a deploy of this project would run with once config and the server's env defaults are
+// resolved (DeployJob::from_entry does that); payload values still win per request, which
+// `payload_overrides` and the credential `order` spell out. No secret is included, only where
+// one would come from.
+async fn handle_project_effective(
+    State(state): State<Arc<AppState>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    let config = state.config();
+    let Some(entry) = config.get(&project) else {
+        return json_error(StatusCode::NOT_FOUND, "Project not found in config");
+    };
+    (StatusCode::OK, Json(effective_project(&state, &project, entry)))
+}
+
+fn effective_project(state: &AppState, name: &str, entry: &ProjectEntry) -> serde_json::Value {
+    let job = DeployJob::from_entry(name, entry, "repo");
+    let (compose, git) = (&job.compose, &job.git);
+    let branches: Vec<serde_json::Value> = if git.steps.is_empty() {
+        vec![serde_json::json!({ "remote": "origin", "branch": "main" })]
+    } else {
+        git.steps
+            .iter()
+            .map(|step| serde_json::json!({ "remote": step.remote, "branch": step.branch, "strategy": format!("{:?}", step.strategy) }))
+            .collect()
+    };
+
+    // Env vars standing in for a setting this project leaves unset
+    let mut env_defaults = BTreeMap::new();
+    if entry.build_parallelism().is_none() && compose.build_parallelism.is_some() {
+        env_defaults.insert("build_parallelism", "BUILD_PARALLELISM");
+    }
+    if entry.nice().is_none() && compose.nice.is_some() {
+        env_defaults.insert("nice", "DEPLOY_NICE");
+    }
+    if entry.ionice_class().is_none() && compose.ionice_class.is_some() {
+        env_defaults.insert("ionice_class", "DEPLOY_IONICE_CLASS");
+    }
+    if compose.up_retries > 0 {
+        env_defaults.insert("up_retries", "COMPOSE_UP_RETRIES");
+    }
+    if env_flag("USE_SUDO") {
+        env_defaults.insert("use_sudo", "USE_SUDO");
+    }
+    let timeout = match entry.deploy_timeout() {
+        Some(timeout) => timeout,
+        None => {
+            if state.deploy_timeout.is_some() {
+                env_defaults.insert("timeout_secs", "DEPLOY_TIMEOUT_SECS");
+            }
+            state.deploy_timeout
+        }
+    };
+
+    // First that has a value wins; a credential_provider replaces the environment fallback
+    let credential_order = |configured: Option<&RegistryCredentials>, key: &str, env_var: &str| {
+        let mut order = vec!["payload".to_string()];
+        if let Some(creds) = configured {
+            order.push(format!("config {} (user {})", key, creds.user));
+        }
+        order.push(match job.credentials.as_ref().map(|source| &source.kind) {
+            Some(CredentialKind::Env { var }) => format!("credential_provider env {}", var),
+            Some(CredentialKind::File { path }) => format!("credential_provider file {}", path),
+            Some(CredentialKind::Command { command }) => {
+                format!("credential_provider command {}", command.first().map(String::as_str).unwrap_or(""))
+            }
+            None => format!(
+                "environment {} ({})",
+                env_var,
+                if env::var(env_var).is_ok_and(|v| !v.is_empty()) { "set" } else { "unset" }
+            ),
+        });
+        order
+    };
+
+    let payload_overrides: Vec<&str> = ["token", "user", "registry", "tag", "image_tag", "compose_profiles", "scale", "compose_override"]
+        .into_iter()
+        .chain(entry.allowed_options().iter().map(|option| option.as_str()))
+        .collect();
+
+    serde_json::json!({
+        "project": name,
+        "path": job.path,
+        "repo_path": job.repo_path,
+        "branches": branches,
+        "fallback_branch": git.fallback_branch,
+        "compose": {
+            "files": compose.files,
+            "project_directory": compose.project_directory,
+            "env_file": compose.env_file,
+            "profiles": compose.profiles,
+            "command": compose.command,
+            "remote_file": compose.remote_file.as_ref().map(|remote| remote.url.as_str()),
+            "use_sudo": compose.use_sudo,
+            "ssh_host": compose.ssh_host,
+            "build_parallelism": compose.build_parallelism,
+            "nice": compose.nice,
+            "ionice_class": compose.ionice_class,
+            "up_retries": compose.up_retries,
+            "stop_timeout": compose.stop_timeout,
+        },
+        "timeout_secs": timeout.map(|t| t.as_secs()),
+        "credentials": {
+            "git": credential_order(job.git_credentials.as_ref(), "git_credentials", GIT_TOKEN_VAR),
+            "docker": credential_order(job.docker_credentials.as_ref(), "docker_credentials", DOCKER_TOKEN_VAR),
+            "registries": job.registries.keys().collect::<Vec<_>>(),
+        },
+        "hooks": {
+            "post_up": compose.post_up,
+            "post_up_rollback": compose.post_up_rollback,
+            "restore_on_failure": compose.restore_on_failure,
+            "health_command": compose.health.as_ref().map(|health| &health.command),
+            "smoke_test_command": compose.smoke_test.as_ref().map(|smoke| &smoke.command),
+        },
+        "payload_overrides": payload_overrides,
+        "env_defaults": env_defaults,
+    })
+}
+
+// Host-wide refresh: POST /deploy/all?tag=prod&type=repo queues a deploy of every matching project
+// (every project without ?tag) and answers 202 with the batch's job IDs. Each deploy goes through
+// dispatch_deploy, so project locks and WORKER_COUNT still apply; paused projects and ones whose
+// allowed_types exclude the type are skipped and listed as such.
+// With ?wait=true it answers once every deploy has finished, with each one's result and an
+// aggregate status: 200 if all succeeded (or nothing ran), 207 if some failed and
+// BATCH_FAILED_STATUS (default 500) if all did. A deploy counts as failed when its own
+// response would have been a 4xx or 5xx; skipped projects don't count either way.
+async fn handle_deploy_all(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    static BATCHES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    let mode = params.get("type").map(String::as_str).unwrap_or("repo");
+    let wait = params.get("wait").is_some_and(|v| v == "true");
+    if !DEPLOY_TYPES.contains(&mode) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            &format!("Unknown type '{}'; expected one of: {}", mode, DEPLOY_TYPES.join(", ")),
+        );
+    }
+    if let Some(pause) = state.pause.lock().unwrap().clone() {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, &format!("paused: {}", pause.reason));
+    }
+
+    let config = state.config();
+    let mut names: Vec<&String> = config
+        .keys()
+        .filter(|name| params.get("tag").is_none_or(|tag| config[*name].tags().contains(tag)))
+        .collect();
+    names.sort();
+
+    let batch_id = format!("{}-{}", unix_now(), BATCHES.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+    let mut deploys = Vec::new();
+    let mut tasks = Vec::new();
+    let mut skipped = Vec::new();
+    for name in names {
+        let entry = &config[name];
+        if let Some(left) = paused_for(&state, name) {
+            skipped.push(serde_json::json!({ "project": name, "reason": format!("paused for {}s after repeated failures", left) }));
+            continue;
+        }
+        if let Err((_, message)) = check_type_allowed(name, entry, mode).and_then(|()| check_confirmed(name, entry, mode, None)) {
+            skipped.push(serde_json::json!({ "project": name, "reason": message }));
+            continue;
+        }
+        let mut job = DeployJob::from_entry(name, entry, mode);
+        job.source = request_source(&headers, "api");
+        if let Err(message) = validate_compose_options(&job.compose) {
+            skipped.push(serde_json::json!({ "project": name, "reason": message }));
+            continue;
+        }
+        let id = format!("{}/{}", batch_id, name);
+        deploys.push(serde_json::json!({ "project": name, "id": id }));
+        let state = state.clone();
+        tasks.push(tokio::spawn(async move {
+            let (code, Json(body)) = dispatch_deploy(&state, job).await;
+            if code.is_success() {
+                info!("🧺 Batch deploy {} finished: {}", id, body.message);
+            } else {
+                warn!("🧺 Batch deploy {} failed ({}): {}", id, code.as_u16(), body.message);
+            }
+            (code, body)
+        }));
+    }
+    info!("🧺 Batch {}: queued {} deploys, skipped {}", batch_id, deploys.len(), skipped.len());
+
+    if !wait {
+        return (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "batch_id": batch_id, "type": mode, "deploys": deploys, "skipped": skipped })),
+        );
+    }
+
+    // The deploys run concurrently; awaiting them in order only orders the results
+    let mut failed = 0;
+    for (deploy, task) in deploys.iter_mut().zip(tasks) {
+        let (code, body) = match task.await {
+            Ok(done) => done,
+            Err(e) => {
+                error!("Batch deploy {} panicked: {}", deploy["id"], e);
+                let (code, Json(body)) = reply(StatusCode::INTERNAL_SERVER_ERROR, "Deploy task failed");
+                (code, body)
+            }
+        };
+        if code.is_client_error() || code.is_server_error() {
+            failed += 1;
+        }
+        deploy["http_status"] = code.as_u16().into();
+        deploy["result"] = serde_json::to_value(&body).unwrap_or_default();
+    }
+    let status = match failed {
+        0 => StatusCode::OK,
+        n if n == deploys.len() => state.batch_failed_status,
+        _ => StatusCode::MULTI_STATUS,
+    };
+    let succeeded = deploys.len() - failed;
+    (
+        status,
+        Json(serde_json::json!({
+            "batch_id": batch_id,
+            "type": mode,
+            "succeeded": succeeded,
+            "failed": failed,
+            "deploys": deploys,
+            "skipped": skipped,
+        })),
+    )
+}
+
+// Liveness only: answers as long as the server is accepting requests
+async fn handle_health() -> &'static str {
+    "ok"
+}
+
+// JSON Schema of the /webhook payload, for checking payloads before sending them
+async fn handle_schema() -> Json<serde_json::Value> {
+    Json(crate::config::payload_schema())
+}
+
+// Static page; all data is fetched client-side from the JSON endpoints
+async fn handle_dashboard() -> axum::response::Html<&'static str> {
+    axum::response::Html(include_str!("dashboard.html"))
+}
+
+async fn handle_reload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+
+    match state.reload_config() {
+        Ok(count) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "success", "message": "Config reloaded", "projects": count })),
+        ),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PauseRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+// Rejects every deploy with 503 and the given reason until POST /resume; an empty body is allowed
+async fn handle_pause(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    let request: PauseRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        PauseRequest::default()
+    } else {
+        match parse_payload(&body) {
+            Ok(request) => request,
+            Err((code, message)) => return json_error(code, &message),
+        }
+    };
+    let reason = request
+        .reason
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .unwrap_or_else(|| "deploys paused by an operator".to_string());
+
+    let pause = Pause { reason, since: unix_now() };
+    warn!("⏸️ Deploys paused: {}", pause.reason);
+    let body = serde_json::json!({ "paused": true, "reason": pause.reason, "since": pause.since });
+    *state.pause.lock().unwrap() = Some(pause);
+    (StatusCode::OK, Json(body))
+}
+
+async fn handle_resume(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+    match state.pause.lock().unwrap().take() {
+        Some(pause) => info!("▶️ Deploys resumed (paused {}s for: {})", unix_now().saturating_sub(pause.since), pause.reason),
+        None => info!("Resume requested but deploys were not paused"),
+    }
+    (StatusCode::OK, Json(serde_json::json!({ "paused": false })))
+}
+
+async fn handle_metrics(State(state): State<Arc<AppState>>) -> impl axum::response::IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+async fn handle_stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err((code, message)) = check_api_key(&state, &headers) {
+        return json_error(code, message);
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "workers": state.workers.size(),
+            "busy": state.workers.busy(),
+            "queued": state.workers.queue_depths(),
+        })),
+    )
+}
+
+async fn handle_error(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<&'static str, (StatusCode, String)> {
+    check_content_type(&state, &headers)?;
+    let payload: ErrorPayload = parse_payload(&body)?;
+    Ok(process_build_error(&state, payload).await)
+}
+
+async fn process_build_error(state: &AppState, payload: ErrorPayload) -> &'static str {
+    info!("📥 Processing build error for project: {}", payload.project);
+
+    // 1. Lookup Project Path
+    let config = state.config();
+    let project_path = match config.get(&payload.project) {
+        Some(entry) => entry.repo_path(),
+        None => {
+            warn!("Project '{}' not found in config", payload.project);
+            return "Project not found";
+        }
+    };
+
+    // 2. Verify Local Remote matches Payload Repository
+    let remote_output = Command::new("git")
+        .arg("-C")
+        .arg(project_path)
+        .arg("remote")
+        .arg("get-url")
+        .arg("origin")
+        .output()
+        .await;
+
+    let remote_url = match remote_output {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error!("❌ git binary not found in PATH");
+            return "Git Binary Not Found";
+        }
+        _ => {
+            error!("Failed to get local git remote for {}", project_path);
+            return "Local verification failed";
+        }
+    };
+
+    if !remote_url.contains(&payload.repository) {
+        warn!(
+            "Repository mismatch: Local({}) vs Payload({})",
+            remote_url, payload.repository
+        );
+        return "Repository mismatch";
+    }
+
+    // 3. Inner Auth Check on GitHub
+    let auth_url = format!(
+        "https://{}@github.com/{}",
+        payload.token, payload.repository
+    );
+    let check_status = Command::new("git")
+        .arg("ls-remote")
+        .arg(&auth_url)
+        .status()
+        .await;
+
+    match check_status {
+        Ok(status) if status.success() => {
+            info!("✅ Auth Success. Logging error...");
+            error!(
+                "🚨 [BUILD ERROR][{}] Repo: {} -> {}",
+                payload.project, payload.repository, payload.message
+            );
+            "Error Logged Successfully"
+        }
+        _ => {
+            warn!("❌ GitHub Auth failed for {}", payload.repository);
+            "Authentication Failed"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deploy::{CommandEnv, CommandFuture};
+
+    // Every command succeeds with no output
+    struct OkRunner;
+
+    impl CommandRunner for OkRunner {
+        fn run<'a>(&'a self, _: &'a str, _: &'a [&'a str], _: Option<&'a str>, _: &'a CommandEnv) -> CommandFuture<'a> {
+            Box::pin(async {
+                Ok(std::process::Output {
+                    status: std::os::unix::process::ExitStatusExt::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
+    }
+
+    fn test_state(dir: &std::path::Path) -> Arc<AppState> {
+        let config = ConfigFile::from([("app".to_string(), ProjectEntry::Path(dir.display().to_string()))]);
+        let mut state = AppState::from_env(config);
+        state.runner = Arc::new(OkRunner);
+        state.lock_wait = Some(Duration::from_millis(50));
+        Arc::new(state)
+    }
+
+    fn restart_job(state: &AppState) -> DeployJob {
+        let mut job = DeployJob::from_entry("app", &state.config()["app"], "restart");
+        job.rollback_limit = 0;
+        job
+    }
+
+    #[tokio::test]
+    async fn a_lock_wait_timeout_is_not_replayed_to_a_retry() {
+        let dir = env::temp_dir().join(format!("graft-hook-busy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("compose.yaml"), "services: {}\n").unwrap();
+        let state = test_state(&dir);
+        let mut headers = HeaderMap::new();
+        headers.insert("idempotency-key", "ci-run-42".parse().unwrap());
+
+        // Another deploy of the project holds its only permit
+        let permits = Arc::new(Semaphore::new(1));
+        state.runtime.lock().unwrap().entry("app".to_string()).or_default().permits = Some(permits.clone());
+        let held = permits.clone().acquire_owned().await.unwrap();
+        let (code, Json(busy)) = dispatch_idempotent(&state, &headers, restart_job(&state), None).await;
+        assert_eq!((code, busy.code), (StatusCode::CONFLICT, Some("PROJECT_BUSY")));
+
+        drop(held);
+        let (code, Json(retry)) = dispatch_idempotent(&state, &headers, restart_job(&state), None).await;
+        assert_eq!(code, StatusCode::OK, "{:?}", retry);
+        assert_eq!(retry.status, "success");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+// Webhook authentication shared by every route and git host: signatures, tokens and basic auth
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{error, info};
+
+// HMAC signature verification helper
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn verify_signature(message: &[u8], signature: &str, secret: &str) -> bool {
+    let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+
+    mac.update(message);
+
+    // Extract hex signature from "sha256=..." format
+    let sig_hex = signature.strip_prefix("sha256=").unwrap_or(signature);
+
+    // Decode hex signature
+    let sig_bytes = match hex::decode(sig_hex) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+
+    mac.verify_slice(&sig_bytes).is_ok()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureAlgorithm {
+    // Hex HMAC-SHA256 of the raw body, optionally "sha256="-prefixed (GitHub, Gitea, Bitbucket)
+    HmacSha256,
+    // The header carries the shared secret itself (GitLab's X-Gitlab-Token)
+    Token,
+}
+
+// One webhook sender: which header it signs with, how, and with what secret
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignatureSource {
+    pub header: String,
+    pub algorithm: SignatureAlgorithm,
+    pub secret: String,
+}
+
+impl SignatureSource {
+    fn verify(&self, value: &str, body: &[u8]) -> bool {
+        match self.algorithm {
+            SignatureAlgorithm::HmacSha256 => verify_signature(body, value, &self.secret),
+            SignatureAlgorithm::Token => constant_time_eq(value.as_bytes(), self.secret.as_bytes()),
+        }
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Credentials accepted as `Authorization: Basic ...` for CI systems that can't sign requests
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub user: String,
+    pub password: String,
+}
+
+impl BasicAuth {
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let decoded = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            // Compare both halves so timing doesn't reveal which one was wrong
+            Some((user, password)) => {
+                let user_ok = constant_time_eq(user.as_bytes(), self.user.as_bytes());
+                let password_ok = constant_time_eq(password.as_bytes(), self.password.as_bytes());
+                user_ok & password_ok
+            }
+            None => false,
+        }
+    }
+}
+
+// Passes when no sources are configured, or when a source whose header is present verifies the body
+pub fn check_sources(
+    sources: &[SignatureSource],
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(), (StatusCode, &'static str)> {
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    let mut presented = false;
+    for source in sources {
+        let value = match headers.get(source.header.as_str()).and_then(|v| v.to_str().ok()) {
+            Some(v) => v,
+            None => continue,
+        };
+        presented = true;
+        if source.verify(value, body) {
+            info!("✅ Signature verified via {}", source.header);
+            return Ok(());
+        }
+    }
+
+    if presented {
+        error!("❌ Signature verification failed");
+        Err((StatusCode::UNAUTHORIZED, "Invalid signature"))
+    } else {
+        error!("Request carries none of the configured signature headers");
+        Err((StatusCode::UNAUTHORIZED, "Missing signature"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(body: &[u8], secret: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn sources() -> Vec<SignatureSource> {
+        vec![
+            SignatureSource {
+                header: "x-gitea-signature".to_string(),
+                algorithm: SignatureAlgorithm::HmacSha256,
+                secret: "gitea".to_string(),
+            },
+            SignatureSource {
+                header: "x-gitlab-token".to_string(),
+                algorithm: SignatureAlgorithm::Token,
+                secret: "gitlab".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn accepts_any_configured_source() {
+        let body = br#"{"project":"app"}"#;
+
+        let mut gitea = HeaderMap::new();
+        gitea.insert("x-gitea-signature", sign(body, "gitea").parse().unwrap());
+        assert!(check_sources(&sources(), &gitea, body).is_ok());
+
+        let mut gitlab = HeaderMap::new();
+        gitlab.insert("x-gitlab-token", "gitlab".parse().unwrap());
+        assert!(check_sources(&sources(), &gitlab, body).is_ok());
+    }
+
+    #[test]
+    fn basic_auth_checks_user_and_password() {
+        let auth = BasicAuth {
+            user: "ci".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let header = |creds: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, format!("Basic {}", STANDARD.encode(creds)).parse().unwrap());
+            headers
+        };
+
+        assert!(auth.matches(&header("ci:hunter2")));
+        assert!(!auth.matches(&header("ci:wrong")));
+        assert!(!auth.matches(&header("other:hunter2")));
+        assert!(!auth.matches(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_signatures() {
+        let body = br#"{"project":"app"}"#;
+
+        let mut wrong = HeaderMap::new();
+        wrong.insert("x-gitea-signature", sign(body, "other").parse().unwrap());
+        assert_eq!(check_sources(&sources(), &wrong, body), Err((StatusCode::UNAUTHORIZED, "Invalid signature")));
+
+        assert_eq!(
+            check_sources(&sources(), &HeaderMap::new(), body),
+            Err((StatusCode::UNAUTHORIZED, "Missing signature"))
+        );
+        assert!(check_sources(&[], &HeaderMap::new(), body).is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+// Fire-and-forget deploy metrics over UDP in the DogStatsD format, alongside the scraped /metrics
+use std::{
+    env,
+    net::{ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+pub struct Statsd {
+    socket: UdpSocket,
+}
+
+impl Statsd {
+    // Enabled by STATSD_ADDR ("host:port"); when unset nothing is bound or sent
+    pub fn from_env() -> Option<Self> {
+        let addr = env::var("STATSD_ADDR").ok().filter(|a| !a.trim().is_empty())?;
+        match Statsd::connect(addr.trim()) {
+            Ok(statsd) => {
+                info!("📈 Sending deploy metrics to StatsD at {}", addr.trim());
+                Some(statsd)
+            }
+            Err(e) => {
+                warn!("⚠️ Ignoring STATSD_ADDR={}: {}", addr, e);
+                None
+            }
+        }
+    }
+
+    fn connect(addr: &str) -> std::io::Result<Self> {
+        let target = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(target)?;
+        // A full socket buffer drops the packet rather than stalling the deploy
+        socket.set_nonblocking(true)?;
+        Ok(Statsd { socket })
+    }
+
+    // graft.deploys (counter) and graft.deploy.duration (timer, ms), tagged project/mode/result
+    pub fn deploy(&self, project: &str, mode: &str, success: bool, duration: Duration) {
+        for line in deploy_lines(project, mode, success, duration) {
+            if let Err(e) = self.socket.send(line.as_bytes()) {
+                debug!("StatsD send failed: {}", e);
+            }
+        }
+    }
+}
+
+fn deploy_lines(project: &str, mode: &str, success: bool, duration: Duration) -> [String; 2] {
+    let tags = format!(
+        "project:{},mode:{},result:{}",
+        tag_value(project),
+        tag_value(mode),
+        if success { "success" } else { "error" }
+    );
+    [
+        format!("graft.deploys:1|c|#{}", tags),
+        format!("graft.deploy.duration:{}|ms|#{}", duration.as_millis(), tags),
+    ]
+}
+
+// `,` `|` `#` and `:` would end the tag (or the whole metric) early
+fn tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', ':'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deploy_metrics_are_tagged_dogstatsd_lines() {
+        let [count, timer] = deploy_lines("web,api", "repo", false, Duration::from_millis(1500));
+        assert_eq!(count, "graft.deploys:1|c|#project:web_api,mode:repo,result:error");
+        assert_eq!(timer, "graft.deploy.duration:1500|ms|#project:web_api,mode:repo,result:error");
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let statsd = Statsd::connect(&receiver.local_addr().unwrap().to_string()).unwrap();
+        statsd.deploy("app", "image", true, Duration::from_millis(20));
+        let mut buf = [0; 256];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"graft.deploys:1|c|#project:app,mode:image,result:success");
+    }
+}
@@ -0,0 +1,199 @@
+// deploy_windows: the hours a project may deploy in, evaluated against the server clock shifted
+// by deploy_timezone. Only fixed offsets ("UTC", "+02:00", "-05:30") are understood, so a zone
+// with daylight saving needs its offset updated when the clocks change.
+use serde::Deserialize;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// {"days": ["mon", "fri"], "start": "09:00", "end": "17:00"}; no days means every day, and an
+// end before the start runs past midnight into the next day
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DeployWindow {
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeployWindows {
+    // Bit n set: open on DAYS[n]; start and end in minutes after local midnight
+    ranges: Vec<(u8, u32, u32)>,
+    offset_secs: i64,
+}
+
+fn parse_minutes(hhmm: &str) -> Option<u32> {
+    let (h, m) = hhmm.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    // 24:00 closes a window at midnight
+    (h < 24 && m < 60 || h == 24 && m == 0).then_some(h * 60 + m)
+}
+
+fn parse_offset(tz: &str) -> Option<i64> {
+    let tz = tz.trim();
+    let tz = tz.strip_prefix("UTC").unwrap_or(tz);
+    if tz.is_empty() || tz == "Z" {
+        return Some(0);
+    }
+    let (sign, rest) = match tz.as_bytes()[0] {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    let minutes = match rest.split_once(':') {
+        Some(_) => parse_minutes(rest)?,
+        None => rest.parse::<u32>().ok().filter(|h| *h < 24)? * 60,
+    };
+    Some(sign * i64::from(minutes) * 60)
+}
+
+impl DeployWindows {
+    pub fn parse(windows: &[DeployWindow], timezone: Option<&str>) -> Result<Self, String> {
+        let offset_secs = match timezone {
+            Some(tz) => parse_offset(tz).ok_or_else(|| format!("deploy_timezone '{}' is not UTC or a ±HH:MM offset", tz))?,
+            None => 0,
+        };
+        let mut ranges = Vec::new();
+        for window in windows {
+            let mut mask = 0u8;
+            for day in &window.days {
+                let i = DAYS
+                    .iter()
+                    .position(|d| day.trim().get(..3).is_some_and(|prefix| prefix.eq_ignore_ascii_case(d)))
+                    .ok_or_else(|| format!("unknown day '{}' in deploy_windows", day))?;
+                mask |= 1 << i;
+            }
+            if mask == 0 {
+                mask = 0x7f;
+            }
+            let start = parse_minutes(&window.start).ok_or_else(|| format!("bad start '{}' in deploy_windows", window.start))?;
+            let end = parse_minutes(&window.end).ok_or_else(|| format!("bad end '{}' in deploy_windows", window.end))?;
+            if start == end {
+                return Err(format!("deploy window {}-{} is empty", window.start, window.end));
+            }
+            ranges.push((mask, start, end));
+        }
+        Ok(DeployWindows { ranges, offset_secs })
+    }
+
+    // Local days since the epoch and minutes into the day; the epoch was a Thursday
+    fn local(&self, unix: u64) -> (i64, usize, u32) {
+        let local = unix as i64 + self.offset_secs;
+        let days = local.div_euclid(86400);
+        let weekday = (days + 3).rem_euclid(7) as usize;
+        (days, weekday, (local.rem_euclid(86400) / 60) as u32)
+    }
+
+    pub fn allows(&self, unix: u64) -> bool {
+        let (_, day, minute) = self.local(unix);
+        let yesterday = (day + 6) % 7;
+        self.ranges.iter().any(|&(mask, start, end)| {
+            let open = |d: usize| mask & (1 << d) != 0;
+            if start < end {
+                open(day) && (start..end).contains(&minute)
+            } else {
+                (open(day) && minute >= start) || (open(yesterday) && minute < end)
+            }
+        })
+    }
+
+    // When the next window opens, looking a week ahead; None when already open
+    pub fn next_open(&self, unix: u64) -> Option<u64> {
+        if self.allows(unix) {
+            return None;
+        }
+        let (today, weekday, _) = self.local(unix);
+        (0..=7i64)
+            .flat_map(|ahead| {
+                let day = (weekday + ahead as usize) % 7;
+                self.ranges
+                    .iter()
+                    .filter(move |(mask, _, _)| mask & (1 << day) != 0)
+                    .map(move |&(_, start, _)| (today + ahead) * 86400 + i64::from(start) * 60 - self.offset_secs)
+            })
+            .filter(|&at| at > unix as i64)
+            .min()
+            .map(|at| at as u64)
+    }
+
+    // e.g. "Mon 2026-10-19 09:00 +02:00", in the windows' own timezone
+    pub fn describe(&self, unix: u64) -> String {
+        let (days, weekday, minute) = self.local(unix);
+        let (y, m, d) = civil_from_days(days);
+        let offset = self.offset_secs.abs() / 60;
+        let sign = if self.offset_secs < 0 { '-' } else { '+' };
+        let day = DAYS[weekday];
+        format!(
+            "{}{} {:04}-{:02}-{:02} {:02}:{:02} {}{:02}:{:02}",
+            day[..1].to_ascii_uppercase(),
+            &day[1..],
+            y,
+            m,
+            d,
+            minute / 60,
+            minute % 60,
+            sign,
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+// Howard Hinnant's days-to-civil algorithm
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(json: serde_json::Value, tz: Option<&str>) -> DeployWindows {
+        DeployWindows::parse(&serde_json::from_value::<Vec<DeployWindow>>(json).unwrap(), tz).unwrap()
+    }
+
+    // Wednesday 2026-10-14 12:00 UTC
+    const WED_NOON: u64 = 1_791_979_200;
+
+    #[test]
+    fn business_hours_in_a_fixed_offset() {
+        let business = windows(serde_json::json!([{ "days": ["mon", "tue", "wed", "thu", "fri"], "start": "09:00", "end": "17:00" }]), Some("+02:00"));
+        assert!(business.allows(WED_NOON));
+        // 15:00 UTC is 17:00 local: closed, and the next window is Thursday morning
+        let closed = WED_NOON + 3 * 3600;
+        assert!(!business.allows(closed));
+        let next = business.next_open(closed).unwrap();
+        assert_eq!(business.describe(next), "Thu 2026-10-15 09:00 +02:00");
+        // Friday evening waits for Monday
+        let friday_evening = WED_NOON + 2 * 86400 + 8 * 3600;
+        assert_eq!(business.describe(business.next_open(friday_evening).unwrap()), "Mon 2026-10-19 09:00 +02:00");
+    }
+
+    #[test]
+    fn overnight_windows_belong_to_their_start_day() {
+        let nightly = windows(serde_json::json!([{ "days": ["sat"], "start": "22:00", "end": "06:00" }]), None);
+        let saturday_late = WED_NOON + 3 * 86400 + 11 * 3600;
+        assert!(nightly.allows(saturday_late));
+        assert!(nightly.allows(saturday_late + 6 * 3600));
+        assert!(!nightly.allows(saturday_late + 9 * 3600));
+        assert!(!nightly.allows(WED_NOON));
+    }
+
+    #[test]
+    fn malformed_windows_are_rejected() {
+        let parse = |json: serde_json::Value, tz| DeployWindows::parse(&serde_json::from_value::<Vec<DeployWindow>>(json).unwrap(), tz);
+        assert!(parse(serde_json::json!([{ "days": ["funday"], "start": "09:00", "end": "17:00" }]), None).is_err());
+        assert!(parse(serde_json::json!([{ "start": "9", "end": "17:00" }]), None).is_err());
+        assert!(parse(serde_json::json!([{ "start": "09:00", "end": "17:00" }]), Some("Europe/Berlin")).is_err());
+        assert!(parse(serde_json::json!([{ "start": "09:00", "end": "24:00" }]), Some("UTC-05:30")).is_ok());
+    }
+}